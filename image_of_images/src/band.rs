@@ -0,0 +1,395 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Seek, Write},
+    path::Path,
+};
+
+use image::RgbImage;
+use tiff::encoder::{colortype, TiffEncoder, TiffKind};
+
+use crate::{
+    progress::{Progress, Stage},
+    CancellationToken, FitMode, Grid, MosaicError,
+};
+
+/// Larger TIFF files need the 64 bit offsets of BigTIFF.
+const MAX_TIFF_BYTES: u64 = u32::MAX as u64 - (1 << 20);
+
+/// Formats which can be written band by band.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Png,
+    Tiff,
+}
+
+impl Format {
+    /// The format of `path` by its extension, `None` when it can't be written band by band.
+    pub(crate) fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+
+        match ext.as_str() {
+            "png" => Some(Format::Png),
+            "tif" | "tiff" => Some(Format::Tiff),
+            _ => None,
+        }
+    }
+}
+
+/// The grid of a result, and how it is fitted to the dimensions of the target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Frame {
+    pub(crate) grid: Grid,
+    pub(crate) fit_mode: FitMode,
+    pub(crate) target_width: u32,
+    pub(crate) target_height: u32,
+}
+
+impl Frame {
+    /// The frame with tiles scaled by `scale`.
+    pub(crate) fn scaled(self, scale: f32) -> Self {
+        let scaled = |v: u32| ((v as f32 * scale).round() as u32).max(1);
+
+        let grid = Grid {
+            tile_width: scaled(self.grid.tile_width),
+            tile_height: scaled(self.grid.tile_height),
+            ..self.grid
+        };
+
+        // rounding may make the scaled grid slightly larger than the scaled target, a grid which
+        // was larger to begin with is left to `Layout::new` to reject
+        let fits =
+            self.grid.width() <= self.target_width && self.grid.height() <= self.target_height;
+        let (target_width, target_height) = match self.fit_mode {
+            FitMode::Pad { .. } if fits => (
+                scaled(self.target_width).max(grid.width()),
+                scaled(self.target_height).max(grid.height()),
+            ),
+            _ => (scaled(self.target_width), scaled(self.target_height)),
+        };
+
+        Self {
+            grid,
+            target_width,
+            target_height,
+            ..self
+        }
+    }
+}
+
+/// The tiles of a row of the grid, left to right, at the size of the cells. Cells without a tile
+/// are left black.
+pub(crate) type RowTiles<'a> = dyn FnMut(u32) -> Result<Vec<Option<RgbImage>>, MosaicError> + 'a;
+
+/// Renders the tiles of `row_tiles` in memory. The tiles are requested one row of the grid at a
+/// time, so besides the result only a single row of tiles is kept.
+pub(crate) fn render(
+    frame: Frame,
+    row_tiles: &mut RowTiles,
+    progress: &Progress,
+    cancellation_token: &CancellationToken,
+) -> Result<RgbImage, MosaicError> {
+    let layout = Layout::new(frame)?;
+    let stage_progress = progress.stage(Stage::InsertingImages, layout.n_bands());
+
+    let mut bands = Bands::new(&layout, row_tiles);
+    let mut pixels = vec![0; layout.n_bytes() as usize];
+    let band_len = layout.row_len() * layout.band_height as usize;
+
+    for (y_start, band) in (0..)
+        .step_by(layout.band_height as usize)
+        .zip(pixels.chunks_mut(band_len))
+    {
+        cancellation_token.check()?;
+        bands.fill(y_start, band)?;
+        stage_progress.inc();
+    }
+
+    stage_progress.finish();
+
+    let result = RgbImage::from_raw(layout.width, layout.height, pixels)
+        .expect("pixels match the dimensions of the layout");
+
+    Ok(match frame.fit_mode {
+        FitMode::Stretch => image::imageops::resize(
+            &result,
+            frame.target_width,
+            frame.target_height,
+            image::imageops::FilterType::Triangle,
+        ),
+        FitMode::Crop | FitMode::Pad { .. } => result,
+    })
+}
+
+/// Renders the tiles of `row_tiles` to `path`. PNG and TIFF files are written one band of rows at
+/// a time, and the tiles are requested one row of the grid at a time, so only a single row of
+/// tiles is kept in memory. Other formats, and `Stretch` which resamples the result as a whole,
+/// are rendered in memory like [`render`] does, with a warning. A partially written file is
+/// removed.
+pub(crate) fn render_to_file(
+    path: &Path,
+    frame: Frame,
+    row_tiles: &mut RowTiles,
+    progress: &Progress,
+    cancellation_token: &CancellationToken,
+) -> Result<(), MosaicError> {
+    match Format::from_path(path) {
+        Some(format) if frame.fit_mode != FitMode::Stretch => write_banded(
+            path,
+            format,
+            &Layout::new(frame)?,
+            row_tiles,
+            progress,
+            cancellation_token,
+        ),
+        format => {
+            let reason = match format {
+                Some(_) => "Stretched results are resampled as a whole",
+                None => "Only PNG and TIFF files are written band by band",
+            };
+            progress.warn(format!("{reason}, {path:?} is rendered in memory"));
+            let result = render(frame, row_tiles, progress, cancellation_token)?;
+            result.save(path).map_err(|e| MosaicError::image(path, e))
+        }
+    }
+}
+
+fn write_banded(
+    path: &Path,
+    format: Format,
+    layout: &Layout,
+    row_tiles: &mut RowTiles,
+    progress: &Progress,
+    cancellation_token: &CancellationToken,
+) -> Result<(), MosaicError> {
+    let stage_progress = progress.stage(Stage::WritingImage, layout.n_bands());
+
+    let mut bands = Bands::new(layout, row_tiles);
+    let mut fill_band = |y_start: u32, band: &mut [u8]| {
+        cancellation_token.check()?;
+        bands.fill(y_start, band)?;
+        stage_progress.inc();
+        Ok(())
+    };
+
+    let result = File::create(path)
+        .map_err(|e| MosaicError::io(path, e))
+        .and_then(|file| {
+            let mut file = BufWriter::new(file);
+            let w = &mut file;
+            match format {
+                Format::Png => write_png(w, path, layout, &mut fill_band),
+                Format::Tiff if layout.n_bytes() > MAX_TIFF_BYTES => {
+                    write_tiff(TiffEncoder::new_big(w), path, layout, &mut fill_band)
+                }
+                Format::Tiff => write_tiff(TiffEncoder::new(w), path, layout, &mut fill_band),
+            }?;
+            file.flush().map_err(|e| MosaicError::io(path, e))
+        });
+
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+
+    result?;
+    stage_progress.finish();
+    Ok(())
+}
+
+/// Where the tiles end up in the result.
+struct Layout {
+    width: u32,
+    height: u32,
+    band_height: u32,
+    /// Position of the grid in the image, non zero when the grid is padded
+    left: u32,
+    top: u32,
+    background: [u8; 3],
+    grid: Grid,
+}
+
+impl Layout {
+    /// A stretched grid is laid out as is, it is resampled afterwards. Fails when a padded grid is
+    /// larger than the target, which only happens for plans which were edited.
+    fn new(frame: Frame) -> Result<Self, MosaicError> {
+        let grid = frame.grid;
+
+        let (width, height, background) = match frame.fit_mode {
+            FitMode::Pad { background } => (frame.target_width, frame.target_height, background),
+            FitMode::Crop | FitMode::Stretch => (grid.width(), grid.height(), [0; 3]),
+        };
+
+        if grid.width() > width || grid.height() > height {
+            return Err(MosaicError::GridLargerThanResult {
+                grid_width: grid.width(),
+                grid_height: grid.height(),
+                width,
+                height,
+            });
+        }
+
+        Ok(Self {
+            width,
+            height,
+            band_height: grid.tile_height.min(height),
+            left: (width - grid.width()) / 2,
+            top: (height - grid.height()) / 2,
+            background,
+            grid,
+        })
+    }
+
+    fn row_len(&self) -> usize {
+        self.width as usize * 3
+    }
+
+    fn n_bytes(&self) -> u64 {
+        self.row_len() as u64 * self.height as u64
+    }
+
+    fn n_bands(&self) -> usize {
+        (self.height as usize).div_ceil(self.band_height as usize)
+    }
+}
+
+/// Fills the rows of the result from top to bottom, with the tiles of a single row of the grid at
+/// a time.
+struct Bands<'a, 'b> {
+    layout: &'a Layout,
+    row_tiles: &'a mut RowTiles<'b>,
+    /// The tiles of the row of the grid which is being filled
+    row: Option<(u32, Vec<Option<RgbImage>>)>,
+}
+
+impl<'a, 'b> Bands<'a, 'b> {
+    fn new(layout: &'a Layout, row_tiles: &'a mut RowTiles<'b>) -> Self {
+        Self {
+            layout,
+            row_tiles,
+            row: None,
+        }
+    }
+
+    /// Fills `band` with the rows starting at `y_start`.
+    fn fill(&mut self, y_start: u32, band: &mut [u8]) -> Result<(), MosaicError> {
+        for (y, row) in (y_start..).zip(band.chunks_exact_mut(self.layout.row_len())) {
+            self.fill_row(y, row)?;
+        }
+        Ok(())
+    }
+
+    fn fill_row(&mut self, y: u32, row: &mut [u8]) -> Result<(), MosaicError> {
+        let layout = self.layout;
+        let grid = layout.grid;
+
+        if y < layout.top || y >= layout.top + grid.height() {
+            fill_background(row, layout.background);
+            return Ok(());
+        }
+
+        let grid_y = y - layout.top;
+        let tile_row = grid_y / grid.tile_height;
+        if !matches!(self.row, Some((r, _)) if r == tile_row) {
+            // the previous row is dropped before the next one is made
+            self.row = None;
+            let tiles = (self.row_tiles)(tile_row)?;
+            assert_eq!(tiles.len(), grid.n_horizontal as usize);
+            self.row = Some((tile_row, tiles));
+        }
+        let (_, tiles) = self.row.as_ref().expect("the row was just made");
+
+        let tile_y = (grid_y % grid.tile_height) as usize;
+        let tile_row_len = grid.tile_width as usize * 3;
+
+        let grid_start = layout.left as usize * 3;
+        let grid_end = grid_start + tiles.len() * tile_row_len;
+        fill_background(&mut row[..grid_start], layout.background);
+        fill_background(&mut row[grid_end..], layout.background);
+
+        for (dst, tile) in row[grid_start..grid_end]
+            .chunks_exact_mut(tile_row_len)
+            .zip(tiles)
+        {
+            match tile {
+                Some(tile) => {
+                    let src = &tile.as_raw()[tile_y * tile_row_len..(tile_y + 1) * tile_row_len];
+                    dst.copy_from_slice(src);
+                }
+                None => dst.fill(0),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn fill_background(pixels: &mut [u8], background: [u8; 3]) {
+    for px in pixels.chunks_exact_mut(3) {
+        px.copy_from_slice(&background);
+    }
+}
+
+type FillBand<'a> = dyn FnMut(u32, &mut [u8]) -> Result<(), MosaicError> + 'a;
+
+fn write_png(
+    file: impl Write,
+    path: &Path,
+    layout: &Layout,
+    fill_band: &mut FillBand,
+) -> Result<(), MosaicError> {
+    let png_error = |e| match e {
+        png::EncodingError::IoError(e) => MosaicError::io(path, e),
+        e => MosaicError::io(path, io::Error::other(e)),
+    };
+
+    let mut encoder = png::Encoder::new(file, layout.width, layout.height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    let mut stream = writer.stream_writer().map_err(png_error)?;
+
+    let mut band = vec![0; layout.row_len() * layout.band_height as usize];
+    for y_start in (0..layout.height).step_by(layout.band_height as usize) {
+        let n_rows = layout.band_height.min(layout.height - y_start) as usize;
+        let band = &mut band[..n_rows * layout.row_len()];
+
+        fill_band(y_start, band)?;
+        stream
+            .write_all(band)
+            .map_err(|e| MosaicError::io(path, e))?;
+    }
+
+    stream.finish().map_err(png_error)?;
+    writer.finish().map_err(png_error)
+}
+
+fn write_tiff<W: Write + Seek, K: TiffKind>(
+    encoder: tiff::TiffResult<TiffEncoder<W, K>>,
+    path: &Path,
+    layout: &Layout,
+    fill_band: &mut FillBand,
+) -> Result<(), MosaicError> {
+    let tiff_error = |e| match e {
+        tiff::TiffError::IoError(e) => MosaicError::io(path, e),
+        e => MosaicError::io(path, io::Error::other(e)),
+    };
+
+    let mut encoder = encoder.map_err(tiff_error)?;
+    let mut image = encoder
+        .new_image::<colortype::RGB8>(layout.width, layout.height)
+        .map_err(tiff_error)?;
+    image
+        .rows_per_strip(layout.band_height)
+        .map_err(tiff_error)?;
+
+    let mut band = vec![0; layout.row_len() * layout.band_height as usize];
+    for y_start in (0..layout.height).step_by(layout.band_height as usize) {
+        let n_rows = layout.band_height.min(layout.height - y_start) as usize;
+        let band = &mut band[..n_rows * layout.row_len()];
+
+        fill_band(y_start, band)?;
+        image.write_strip(band).map_err(tiff_error)?;
+    }
+
+    image.finish().map_err(tiff_error)
+}
@@ -0,0 +1,377 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use image::{imageops::FilterType, GenericImageView, ImageBuffer, Rgb};
+use rand::prelude::*;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{storage::TileStore, MosaicError};
+
+/// Mean colour of the four quadrants of an image, with channel values in `[0, 1]`.
+type Signature = [f32; 12];
+
+const MAX_ITERATIONS: usize = 20;
+
+/// Signature of a tile or a cell. Both are squashed to 2x2 pixels the same way, so that a cell
+/// which looks like a tile has the signature of the tile, whatever the aspect ratio of the cells.
+fn signature<I>(img: &I) -> Signature
+where
+    I: GenericImageView<Pixel = Rgb<u8>>,
+{
+    let small = image::imageops::resize(img, 2, 2, FilterType::Triangle);
+    let mut result = [0.0; 12];
+    for (r, &v) in result.iter_mut().zip(small.as_raw()) {
+        *r = v as f32 / 255.0;
+    }
+    result
+}
+
+/// Signature of a cell, which contains the pixels of a `width` x `height` cell as returned by
+/// [`cell_pixels`](crate::cell_pixels).
+fn cell_signature(cell: &[u8], width: u32, height: u32) -> Signature {
+    let cell = ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, cell)
+        .expect("cell data matches its dimensions");
+
+    signature(&cell)
+}
+
+fn distance(s1: &Signature, s2: &Signature) -> f32 {
+    s1.iter()
+        .zip(s2)
+        .map(|(v1, v2)| (v1 - v2) * (v1 - v2))
+        .sum()
+}
+
+fn nearest(centroids: &[Signature], s: &Signature) -> (usize, f32) {
+    centroids
+        .iter()
+        .map(|c| distance(c, s))
+        .enumerate()
+        .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap())
+        .expect("there is at least one cluster")
+}
+
+/// The tiles of a [`TileSet`](crate::TileSet) grouped into clusters of similar colour, computed
+/// with k-means on the mean colours of the quadrants of the tiles.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterModel {
+    /// Signature at the centre of every cluster
+    centroids: Vec<Signature>,
+    /// Cluster of every tile
+    assignments: Vec<usize>,
+}
+
+impl ClusterModel {
+    /// Clusters `tiles` into at most `num_clusters` clusters. Uses a fixed seed, so the same
+    /// tiles always give the same clusters.
+    pub(crate) fn new(tiles: &TileStore, num_clusters: usize) -> Self {
+        let signatures: Vec<Signature> = (0..tiles.len())
+            .map(|idx| signature(&tiles.get(idx)))
+            .collect();
+
+        let k = num_clusters.min(signatures.len()).max(1);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut centroids = init_centroids(&signatures, k, &mut rng);
+        let mut assignments = vec![usize::MAX; signatures.len()];
+
+        for _ in 0..MAX_ITERATIONS {
+            let nearest: Vec<(usize, f32)> = signatures
+                .par_iter()
+                .map(|s| nearest(&centroids, s))
+                .collect();
+
+            let new_assignments: Vec<usize> = nearest.iter().map(|&(c, _)| c).collect();
+            if new_assignments == assignments {
+                break;
+            }
+            assignments = new_assignments;
+
+            let mut sums = vec![([0.0; 12], 0usize); k];
+            for (s, &c) in signatures.iter().zip(&assignments) {
+                let (sum, n) = &mut sums[c];
+                for (sum, v) in sum.iter_mut().zip(s) {
+                    *sum += v;
+                }
+                *n += 1;
+            }
+
+            for (c, (sum, n)) in sums.into_iter().enumerate() {
+                if n > 0 {
+                    centroids[c] = sum.map(|v| v / n as f32);
+                } else {
+                    // an empty cluster takes over the tile which fits its own cluster worst
+                    let (worst, _) = nearest
+                        .iter()
+                        .enumerate()
+                        .max_by(|(_, (_, d1)), (_, (_, d2))| d1.partial_cmp(d2).unwrap())
+                        .unwrap();
+                    centroids[c] = signatures[worst];
+                }
+            }
+        }
+
+        Self {
+            centroids,
+            assignments,
+        }
+    }
+
+    pub fn num_clusters(&self) -> usize {
+        self.centroids.len()
+    }
+
+    /// Whether the model fits a tile set of `n_tiles` tiles.
+    fn is_valid_for(&self, n_tiles: usize) -> bool {
+        !self.centroids.is_empty()
+            && self.assignments.len() == n_tiles
+            && self.assignments.iter().all(|&c| c < self.centroids.len())
+    }
+}
+
+/// k-means++ initialisation, every next centroid is picked with a probability proportional to
+/// its squared distance to the centroids picked so far.
+fn init_centroids(signatures: &[Signature], k: usize, rng: &mut StdRng) -> Vec<Signature> {
+    let mut centroids = vec![*signatures.choose(rng).expect("there is at least one tile")];
+    let mut distances: Vec<f32> = signatures
+        .iter()
+        .map(|s| distance(s, &centroids[0]))
+        .collect();
+
+    while centroids.len() < k {
+        let total: f32 = distances.iter().sum();
+        let idx = if total > 0.0 {
+            let mut pick = rng.gen_range(0.0..total);
+            distances
+                .iter()
+                .position(|&d| {
+                    pick -= d;
+                    pick < 0.0
+                })
+                .unwrap_or(signatures.len() - 1)
+        } else {
+            // all remaining tiles coincide with a centroid
+            rng.gen_range(0..signatures.len())
+        };
+
+        centroids.push(signatures[idx]);
+        for (d, s) in distances.iter_mut().zip(signatures) {
+            *d = d.min(distance(s, &signatures[idx]));
+        }
+    }
+
+    centroids
+}
+
+/// The clusters of a [`TileSet`](crate::TileSet) together with the tiles they were computed for,
+/// so they can be stored and reused as long as the tiles do not change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TileIndex<Id> {
+    pub tiles: Vec<Id>,
+    pub clusters: ClusterModel,
+}
+
+impl<Id> TileIndex<Id> {
+    /// Whether the index was computed for exactly `tiles`.
+    pub(crate) fn matches(&self, tiles: &[Id]) -> bool
+    where
+        Id: PartialEq,
+    {
+        self.tiles == tiles && self.clusters.is_valid_for(tiles.len())
+    }
+}
+
+impl<Id: Serialize> TileIndex<Id> {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), MosaicError> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| MosaicError::io(path, e))?;
+        serde_json::to_writer(file, self).map_err(|source| MosaicError::Index {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
+
+impl<Id: DeserializeOwned> TileIndex<Id> {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MosaicError> {
+        let path = path.as_ref();
+        let file = BufReader::new(File::open(path).map_err(|e| MosaicError::io(path, e))?);
+        serde_json::from_reader(file).map_err(|source| MosaicError::Index {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
+
+/// Restricts the images scored for a cell to the members of the clusters nearest to the cell.
+pub(crate) struct ClusterSearch<'a> {
+    model: &'a ClusterModel,
+    /// `members[cluster]` are the images in `cluster`
+    members: Vec<Vec<usize>>,
+    clusters_searched: usize,
+    min_candidates: usize,
+}
+
+impl<'a> ClusterSearch<'a> {
+    /// Searches the images `img_idxs` of the tile set `model` was computed for. Image `i` of the
+    /// search refers to the tile `img_idxs[i]`. At least `clusters_searched` clusters are searched
+    /// for a cell, and more while they contain fewer than `min_candidates` images.
+    pub(crate) fn new(
+        model: &'a ClusterModel,
+        img_idxs: &[usize],
+        clusters_searched: usize,
+        min_candidates: usize,
+    ) -> Self {
+        let mut members = vec![Vec::new(); model.num_clusters()];
+        for (img_idx, &tile_idx) in img_idxs.iter().enumerate() {
+            members[model.assignments[tile_idx]].push(img_idx);
+        }
+
+        Self {
+            model,
+            members,
+            clusters_searched,
+            min_candidates,
+        }
+    }
+
+    /// Indices of the images in the clusters nearest to `cell`, see [`cell_signature`].
+    pub(crate) fn candidates(&self, cell: &[u8], width: u32, height: u32) -> Vec<usize> {
+        let signature = cell_signature(cell, width, height);
+
+        let mut clusters: Vec<(f32, usize)> = self
+            .model
+            .centroids
+            .iter()
+            .map(|c| distance(c, &signature))
+            .enumerate()
+            .map(|(idx, d)| (d, idx))
+            .collect();
+        clusters.sort_by(|c1, c2| c1.partial_cmp(c2).unwrap());
+
+        let mut candidates = Vec::new();
+        for (n_searched, (_, cluster)) in clusters.into_iter().enumerate() {
+            if n_searched >= self.clusters_searched && candidates.len() >= self.min_candidates {
+                break;
+            }
+            candidates.extend_from_slice(&self.members[cluster]);
+        }
+
+        candidates.sort_unstable();
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbImage};
+
+    use super::*;
+    use crate::{test_util::solid, MakeImgOfImsOpts, MosaicBuilder, TileSet};
+
+    /// Tiles of 32x8 pixels with colours which differ across the tiles and within them.
+    fn tiles(n: u32) -> Vec<RgbImage> {
+        (0..n)
+            .map(|i| {
+                RgbImage::from_fn(32, 8, |x, y| {
+                    Rgb([
+                        (i * 37 % 256) as u8,
+                        (x * 8 + i) as u8,
+                        (y * 30 + i * 11) as u8,
+                    ])
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cells_have_the_signature_of_the_same_tile() {
+        for tile in tiles(3) {
+            let tiles: TileStore = [tile.clone()].into_iter().collect();
+            assert_eq!(
+                cell_signature(tile.as_raw(), 32, 8),
+                signature(&tiles.get(0))
+            );
+        }
+    }
+
+    #[test]
+    fn clustering_is_deterministic() {
+        let tiles: TileStore = tiles(40).into_iter().collect();
+        let signatures: Vec<Signature> = (0..tiles.len())
+            .map(|idx| signature(&tiles.get(idx)))
+            .collect();
+
+        let centroids = init_centroids(&signatures, 5, &mut StdRng::seed_from_u64(0));
+        assert_eq!(centroids.len(), 5);
+        assert_eq!(
+            centroids,
+            init_centroids(&signatures, 5, &mut StdRng::seed_from_u64(0))
+        );
+
+        let model = ClusterModel::new(&tiles, 5);
+        assert_eq!(model.num_clusters(), 5);
+        assert!(model.is_valid_for(40));
+        assert_eq!(model, ClusterModel::new(&tiles, 5));
+    }
+
+    #[test]
+    fn tile_index_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+
+        let mut tile_set = TileSet::from_images(
+            tiles(20)
+                .into_iter()
+                .map(DynamicImage::ImageRgb8)
+                .enumerate(),
+            8,
+        );
+        tile_set.cluster(4);
+        let index = tile_set.index().unwrap();
+        index.save(&path).unwrap();
+
+        let loaded = TileIndex::<usize>::load(&path).unwrap();
+        assert_eq!(loaded, index);
+        assert!(loaded.matches(tile_set.ids()));
+        assert!(!loaded.matches(&tile_set.ids()[1..]));
+
+        let mut other = TileSet::from_images(
+            tiles(20)
+                .into_iter()
+                .map(DynamicImage::ImageRgb8)
+                .enumerate(),
+            8,
+        );
+        assert!(other.apply_index(loaded));
+        assert_eq!(other.clusters(), tile_set.clusters());
+    }
+
+    #[test]
+    fn searching_every_cluster_finds_the_best_tile() {
+        let mut tile_set = TileSet::from_images(
+            (0..30u8).map(|i| (i, solid(8, 8, [i * 8, 255 - i * 8, i * 3]))),
+            8,
+        );
+        tile_set.cluster(6);
+
+        let target_img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        }));
+        let opts = |clusters_searched| MakeImgOfImsOpts {
+            num_horizontal_imgs: 8,
+            num_vertical_imgs: 8,
+            clusters_searched,
+            ..Default::default()
+        };
+
+        let recall = MosaicBuilder::new(&tile_set, opts(6))
+            .search_recall(&target_img)
+            .unwrap();
+        assert_eq!(recall, 1.0);
+
+        let recall = MosaicBuilder::new(&tile_set, opts(1))
+            .search_recall(&target_img)
+            .unwrap();
+        assert!(recall < 1.0, "{recall}");
+    }
+}
@@ -0,0 +1,162 @@
+use std::{fmt, io, path::PathBuf};
+
+use image::{error::UnsupportedError, ImageError};
+
+use crate::cancel::Cancelled;
+
+/// A single invalid option.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptionProblem {
+    /// Name of the field in [`MakeImgOfImsOpts`](crate::MakeImgOfImsOpts), or `target_img` and
+    /// `input_dir` for problems with the inputs
+    pub option: &'static str,
+    pub message: String,
+    /// How the problem can be fixed
+    pub suggestion: String,
+}
+
+impl OptionProblem {
+    pub(crate) fn new(
+        option: &'static str,
+        message: impl Into<String>,
+        suggestion: impl Into<String>,
+    ) -> Self {
+        Self {
+            option,
+            message: message.into(),
+            suggestion: suggestion.into(),
+        }
+    }
+}
+
+impl fmt::Display for OptionProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}. {}", self.option, self.message, self.suggestion)
+    }
+}
+
+/// Everything that can go wrong while creating an image of images.
+#[derive(Debug, thiserror::Error)]
+pub enum MosaicError {
+    #[error("Invalid options:{}", join_problems(.problems))]
+    InvalidOptions { problems: Vec<OptionProblem> },
+    #[error("Failed reading target image {path:?}")]
+    UnreadableTarget {
+        path: PathBuf,
+        #[source]
+        source: ImageError,
+    },
+    #[error("No images found{}", .dir.as_ref().map(|d| format!(" in {d:?}")).unwrap_or_default())]
+    EmptyLibrary { dir: Option<PathBuf> },
+    #[error("Too few images, {available} images for {required} cells, try reducing the number of horizontal and/or vertical images")]
+    TooFewImages { available: usize, required: usize },
+    #[error("Unsupported image format of {path:?}")]
+    UnsupportedFormat {
+        path: PathBuf,
+        #[source]
+        source: UnsupportedError,
+    },
+    #[error("Failed processing image {path:?}")]
+    Image {
+        path: PathBuf,
+        #[source]
+        source: ImageError,
+    },
+    #[error("I/O error for {path:?}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Invalid plan {path:?}")]
+    Plan {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("Invalid tile index {path:?}")]
+    Index {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("Invalid job file {path:?}")]
+    Job {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("Invalid manifest {path:?}")]
+    Manifest {
+        path: PathBuf,
+        #[source]
+        source: csv::Error,
+    },
+    #[error("No job to resume in {dir:?}")]
+    NoJob { dir: PathBuf },
+    #[error("The {what} changed since the job in {dir:?} was started, start a new job instead")]
+    JobChanged { dir: PathBuf, what: &'static str },
+    #[error("Placement at ({row}, {col}) lies outside of the {n_horizontal}x{n_vertical} grid")]
+    PlacementOutsideGrid {
+        row: u32,
+        col: u32,
+        n_horizontal: u32,
+        n_vertical: u32,
+    },
+    #[error("The {grid_width}x{grid_height} pixels of the grid do not fit in the {width}x{height} pixels of the result")]
+    GridLargerThanResult {
+        grid_width: u32,
+        grid_height: u32,
+        width: u32,
+        height: u32,
+    },
+    #[error("Cancelled")]
+    Cancelled,
+}
+
+fn join_problems(problems: &[OptionProblem]) -> String {
+    problems.iter().map(|p| format!("\n  {p}")).collect()
+}
+
+impl MosaicError {
+    pub(crate) fn invalid_option(
+        option: &'static str,
+        message: impl Into<String>,
+        suggestion: impl Into<String>,
+    ) -> Self {
+        Self::InvalidOptions {
+            problems: vec![OptionProblem::new(option, message, suggestion)],
+        }
+    }
+
+    pub(crate) fn image(path: impl Into<PathBuf>, source: ImageError) -> Self {
+        let path = path.into();
+
+        match source {
+            ImageError::Unsupported(source) => Self::UnsupportedFormat { path, source },
+            ImageError::IoError(source) => Self::Io { path, source },
+            source => Self::Image { path, source },
+        }
+    }
+
+    pub(crate) fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Self::Io {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+impl From<OptionProblem> for MosaicError {
+    fn from(problem: OptionProblem) -> Self {
+        Self::InvalidOptions {
+            problems: vec![problem],
+        }
+    }
+}
+
+impl From<Cancelled> for MosaicError {
+    fn from(_: Cancelled) -> Self {
+        Self::Cancelled
+    }
+}
@@ -0,0 +1,658 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    cluster::ClusterModel,
+    storage::{read_u32, TileStore},
+    MakeImgOfImsOpts, MosaicError, TileSet,
+};
+
+const JOB_FILE: &str = "job.json";
+const TILES_FILE: &str = "tiles.json";
+const TILE_PIXELS_FILE: &str = "tiles.bin";
+const TARGET_DIR_PREFIX: &str = "target-";
+const ERRORS_FILE: &str = "errors.bin";
+const ASSIGNMENT_FILE: &str = "assignment.json";
+
+/// 64 bit FNV-1a, which unlike the hashers of std gives the same result in every run.
+pub(crate) struct Fingerprint(u64);
+
+impl Default for Fingerprint {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fingerprint {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub(crate) fn fingerprint(value: impl Hash) -> u64 {
+    let mut hasher = Fingerprint::default();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Fingerprint of the images at `paths` in the sources at `locations`. Uses the size and
+/// modification time of the files, so the images don't have to be read. Images in an archive are
+/// covered by the archive itself.
+pub(crate) fn inputs_fingerprint(locations: &[&Path], paths: &[PathBuf]) -> u64 {
+    let mut hasher = Fingerprint::default();
+
+    let archives: Vec<PathBuf> = locations
+        .iter()
+        .filter(|location| location.is_file())
+        .map(|location| location.to_path_buf())
+        .collect();
+    for path in archives.iter().chain(paths) {
+        path.hash(&mut hasher);
+        if let Ok(metadata) = fs::metadata(path) {
+            metadata.len().hash(&mut hasher);
+            metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .hash(&mut hasher);
+        }
+    }
+
+    hasher.finish()
+}
+
+/// Fingerprint of the options which affect which tiles are loaded and where they are placed.
+pub(crate) fn options_fingerprint(opts: &MakeImgOfImsOpts) -> u64 {
+    let mut pinned: Vec<_> = opts.pinned_tiles.iter().collect();
+    pinned.sort();
+
+    let grid = (
+        opts.target_width,
+        opts.target_height,
+        opts.fit_mode,
+        opts.num_horizontal_imgs,
+        opts.num_vertical_imgs,
+        opts.auto_grid,
+    );
+    let selection = (
+        opts.max_imgs,
+        pinned,
+        (&opts.include, &opts.exclude),
+        (opts.max_depth, opts.skip_hidden, opts.follow_symlinks),
+        opts.ignore_exif_orientation,
+    );
+    let search = (
+        opts.pyramid_levels,
+        opts.shortlist_size,
+        opts.match_tile_size,
+        opts.f32_scoring,
+        opts.num_clusters,
+        opts.clusters_searched,
+    );
+
+    fingerprint((grid, selection, search))
+}
+
+/// The fingerprints a job was started with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct JobInfo {
+    pub(crate) inputs: u64,
+    /// Also covers the size the tiles are loaded at, which follows from the grid
+    pub(crate) options: u64,
+}
+
+/// The tiles of a [`TileSet`] loaded from disk, besides their pixels.
+#[derive(Serialize, Deserialize)]
+struct SavedTiles {
+    paths: Vec<PathBuf>,
+    max_tile_size: u32,
+    clusters: Option<ClusterModel>,
+}
+
+/// Folder in which the progress of a job is stored: the loaded tiles, the errors of the cells
+/// scored so far and the tiles picked for every target. A job started with
+/// [`MakeImgOfImsOpts::resume`] continues from there.
+pub(crate) struct Job {
+    dir: PathBuf,
+    resume: bool,
+}
+
+impl Job {
+    pub(crate) fn from_opts(opts: &MakeImgOfImsOpts) -> Option<Self> {
+        opts.job_dir.as_ref().map(|dir| Self {
+            dir: dir.clone(),
+            resume: opts.resume,
+        })
+    }
+
+    /// Starts the job with the fingerprints `info`. A resumed job must have been started with
+    /// the same fingerprints, a new job discards the progress of an earlier job in the folder.
+    pub(crate) fn start(&self, info: JobInfo) -> Result<(), MosaicError> {
+        let path = self.dir.join(JOB_FILE);
+
+        if self.resume {
+            if !path.is_file() {
+                return Err(MosaicError::NoJob {
+                    dir: self.dir.clone(),
+                });
+            }
+
+            let started: JobInfo = read_json(&path)?;
+            let changed = if started.inputs != info.inputs {
+                Some("input images")
+            } else if started.options != info.options {
+                Some("options")
+            } else {
+                None
+            };
+
+            return match changed {
+                Some(what) => Err(MosaicError::JobChanged {
+                    dir: self.dir.clone(),
+                    what,
+                }),
+                None => Ok(()),
+            };
+        }
+
+        self.clear()?;
+        write_json(&path, &info)
+    }
+
+    /// Removes the files of an earlier job, leaving anything else in the folder alone.
+    fn clear(&self) -> Result<(), MosaicError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return fs::create_dir_all(&self.dir).map_err(|e| MosaicError::io(&self.dir, e))
+            }
+            Err(e) => return Err(MosaicError::io(&self.dir, e)),
+        };
+
+        for entry in entries {
+            let path = entry.map_err(|e| MosaicError::io(&self.dir, e))?.path();
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+
+            let result = if name.starts_with(TARGET_DIR_PREFIX) && path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else if [JOB_FILE, TILES_FILE, TILE_PIXELS_FILE].contains(&name) {
+                fs::remove_file(&path)
+            } else {
+                continue;
+            };
+            result.map_err(|e| MosaicError::io(&path, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// The tiles stored with [`Job::save_tiles`], when resuming a job which got that far.
+    pub(crate) fn load_tiles(&self) -> Result<Option<TileSet<PathBuf>>, MosaicError> {
+        let path = self.dir.join(TILES_FILE);
+        if !self.resume || !path.is_file() {
+            return Ok(None);
+        }
+
+        let saved: SavedTiles = read_json(&path)?;
+
+        let pixels_path = self.dir.join(TILE_PIXELS_FILE);
+        let imgs = File::open(&pixels_path)
+            .and_then(|file| TileStore::read_from(&mut BufReader::new(file)))
+            .map_err(|e| MosaicError::io(&pixels_path, e))?;
+
+        if imgs.len() != saved.paths.len() {
+            return Err(MosaicError::io(
+                pixels_path,
+                io::Error::new(io::ErrorKind::InvalidData, "number of tiles differs"),
+            ));
+        }
+
+        let mut tile_set = TileSet::from_scaled(saved.paths, imgs, saved.max_tile_size);
+        tile_set.paths = tile_set.ids.iter().cloned().map(Some).collect();
+        tile_set.clusters = saved.clusters;
+
+        Ok(Some(tile_set))
+    }
+
+    pub(crate) fn save_tiles(&self, tile_set: &TileSet<PathBuf>) -> Result<(), MosaicError> {
+        let pixels_path = self.dir.join(TILE_PIXELS_FILE);
+        write_atomically(&pixels_path, |w| tile_set.imgs.write_to(w))
+            .map_err(|e| MosaicError::io(&pixels_path, e))?;
+
+        // written last, as its presence marks the tiles as complete
+        write_json(
+            &self.dir.join(TILES_FILE),
+            &SavedTiles {
+                paths: tile_set.ids.clone(),
+                max_tile_size: tile_set.max_tile_size,
+                clusters: tile_set.clusters.clone(),
+            },
+        )
+    }
+
+    /// The progress of the target with the given fingerprint, which is empty unless resuming.
+    pub(crate) fn target(&self, fingerprint: u64) -> Result<TargetJob, MosaicError> {
+        let dir = self
+            .dir
+            .join(format!("{TARGET_DIR_PREFIX}{fingerprint:016x}"));
+
+        if !self.resume && dir.is_dir() {
+            fs::remove_dir_all(&dir).map_err(|e| MosaicError::io(&dir, e))?;
+        }
+        fs::create_dir_all(&dir).map_err(|e| MosaicError::io(&dir, e))?;
+
+        Ok(TargetJob { dir })
+    }
+}
+
+/// Errors of the cells of a target, by `(row, col)`, as `(img_idx, error)`.
+pub(crate) type CellErrors = HashMap<(u32, u32), Vec<(usize, f32)>>;
+
+/// Folder in which the progress of a single target of a [`Job`] is stored.
+pub(crate) struct TargetJob {
+    dir: PathBuf,
+}
+
+impl TargetJob {
+    /// The images picked for the cells of a `n_vertical` x `n_horizontal` grid out of `n_imgs`
+    /// images, if they were stored before.
+    pub(crate) fn load_assignment(
+        &self,
+        n_vertical: u32,
+        n_horizontal: u32,
+        n_imgs: usize,
+    ) -> Result<Option<Vec<Vec<usize>>>, MosaicError> {
+        let path = self.dir.join(ASSIGNMENT_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let assignment: Vec<Vec<usize>> = read_json(&path)?;
+        let fits = assignment.len() == n_vertical as usize
+            && assignment
+                .iter()
+                .all(|row| row.len() == n_horizontal as usize && row.iter().all(|&i| i < n_imgs));
+
+        if !fits {
+            log::warn!("Assignment {path:?} does not fit the grid, selecting images again");
+            return Ok(None);
+        }
+
+        Ok(Some(assignment))
+    }
+
+    /// Stores the picked images, after which the errors of the cells are no longer needed.
+    pub(crate) fn save_assignment(&self, assignment: &[Vec<usize>]) -> Result<(), MosaicError> {
+        write_json(&self.dir.join(ASSIGNMENT_FILE), &assignment)?;
+
+        let errors_path = self.dir.join(ERRORS_FILE);
+        match fs::remove_file(&errors_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(MosaicError::io(errors_path, e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The errors of the cells scored so far, and the log to which the errors of further cells
+    /// are appended. A record which was cut off by an interruption is dropped.
+    pub(crate) fn error_log(&self) -> Result<(CellErrors, ErrorLog), MosaicError> {
+        let path = self.dir.join(ERRORS_FILE);
+
+        let mut errors = HashMap::new();
+        let mut valid_len = 0;
+
+        if let Ok(file) = File::open(&path) {
+            let mut file = BufReader::new(file);
+            while let Ok(record_len) = read_cell_errors(&mut file, &mut errors) {
+                valid_len += record_len;
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|file| {
+                file.set_len(valid_len)?;
+                Ok(file)
+            })
+            .map_err(|e| MosaicError::io(&path, e))?;
+
+        Ok((
+            errors,
+            ErrorLog {
+                file: BufWriter::new(file),
+                path,
+            },
+        ))
+    }
+}
+
+/// Reads the errors of a single cell into `errors`, returns the length of the record.
+fn read_cell_errors(r: &mut impl Read, errors: &mut CellErrors) -> io::Result<u64> {
+    let row = read_u32(r)?;
+    let col = read_u32(r)?;
+    let n = read_u32(r)?;
+
+    let cell_errors = (0..n)
+        .map(|_| {
+            let img_idx = read_u32(r)? as usize;
+            let err = f32::from_bits(read_u32(r)?);
+            Ok((img_idx, err))
+        })
+        .collect::<io::Result<_>>()?;
+
+    errors.insert((row, col), cell_errors);
+    Ok(12 + n as u64 * 8)
+}
+
+/// Append only file with the errors of every scored cell, for the best tiles of the cell which
+/// [`calc_errors`](crate::calc_errors) keeps.
+pub(crate) struct ErrorLog {
+    file: BufWriter<File>,
+    path: PathBuf,
+}
+
+impl ErrorLog {
+    pub(crate) fn append(
+        &mut self,
+        row: u32,
+        col: u32,
+        errors: &[(usize, f32)],
+    ) -> Result<(), MosaicError> {
+        let mut write = || {
+            self.file.write_all(&row.to_le_bytes())?;
+            self.file.write_all(&col.to_le_bytes())?;
+            self.file.write_all(&(errors.len() as u32).to_le_bytes())?;
+            for &(img_idx, err) in errors {
+                self.file.write_all(&(img_idx as u32).to_le_bytes())?;
+                self.file.write_all(&err.to_bits().to_le_bytes())?;
+            }
+            Ok(())
+        };
+
+        write().map_err(|e| MosaicError::io(&self.path, e))
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<(), MosaicError> {
+        self.file
+            .flush()
+            .map_err(|e| MosaicError::io(&self.path, e))
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, MosaicError> {
+    let file = BufReader::new(File::open(path).map_err(|e| MosaicError::io(path, e))?);
+    serde_json::from_reader(file).map_err(|source| MosaicError::Job {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+fn write_json(path: &Path, value: &impl Serialize) -> Result<(), MosaicError> {
+    write_atomically(path, |w| {
+        serde_json::to_writer(w, value).map_err(io::Error::from)
+    })
+    .map_err(|e| MosaicError::io(path, e))
+}
+
+/// Writes to a temporary file which replaces `path` once it is complete, so an interrupted job
+/// never leaves a partially written file behind.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut tmp_name = path.file_name().expect("a file path").to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+    write(&mut file)?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{
+        test_util::{solid, write_solid_pngs},
+        CancellationToken, FitMode, MosaicBuilder, ProgressEvent, Stage,
+    };
+
+    fn job_opts(job_dir: &Path, resume: bool) -> MakeImgOfImsOpts {
+        MakeImgOfImsOpts {
+            job_dir: Some(job_dir.to_owned()),
+            resume,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn resume_with_other_tile_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let imgs_dir = dir.path().join("imgs");
+        let job_dir = dir.path().join("job");
+        write_solid_pngs(&imgs_dir, 3, 16);
+
+        TileSet::from_dir(&imgs_dir, 8, &job_opts(&job_dir, false)).unwrap();
+
+        let resumed = TileSet::from_dir(&imgs_dir, 8, &job_opts(&job_dir, true)).unwrap();
+        assert_eq!(resumed.len(), 3);
+
+        // a tile size which follows from other options, while the images are the same
+        let result = TileSet::from_dir(&imgs_dir, 12, &job_opts(&job_dir, true));
+        assert!(
+            matches!(
+                result,
+                Err(MosaicError::JobChanged {
+                    what: "options",
+                    ..
+                })
+            ),
+            "{result:?}"
+        );
+    }
+
+    /// Options of a job in `job_dir` for a 2x2 grid, which record the stages they start and
+    /// cancel the job when `cancel_at` starts.
+    fn staged_opts(
+        job_dir: &Path,
+        resume: bool,
+        cancel_at: Option<Stage>,
+    ) -> (MakeImgOfImsOpts, Arc<Mutex<Vec<Stage>>>) {
+        let stages = Arc::new(Mutex::new(Vec::new()));
+        let cancellation_token = CancellationToken::new();
+        let reporter = {
+            let stages = stages.clone();
+            let cancellation_token = cancellation_token.clone();
+            move |event| {
+                if let ProgressEvent::StageStarted { stage, .. } = event {
+                    stages.lock().unwrap().push(stage);
+                    if Some(stage) == cancel_at {
+                        cancellation_token.cancel();
+                    }
+                }
+            }
+        };
+
+        let opts = MakeImgOfImsOpts {
+            target_width: Some(40),
+            num_horizontal_imgs: 2,
+            num_vertical_imgs: 2,
+            progress_reporter: Some(Arc::new(reporter)),
+            cancellation_token,
+            ..job_opts(job_dir, resume)
+        };
+        (opts, stages)
+    }
+
+    /// The only target of the job in `job_dir`.
+    fn target_job(job_dir: &Path) -> TargetJob {
+        let dirs: Vec<PathBuf> = fs::read_dir(job_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_dir())
+            .collect();
+        assert_eq!(dirs.len(), 1, "{dirs:?}");
+        TargetJob {
+            dir: dirs[0].clone(),
+        }
+    }
+
+    #[test]
+    fn resume_after_every_stage() {
+        let dir = tempfile::tempdir().unwrap();
+        let imgs_dir = dir.path().join("imgs");
+        let job_dir = dir.path().join("job");
+        write_solid_pngs(&imgs_dir, 8, 8);
+        // the lightest tiles fit best
+        let target_img = solid(40, 40, [255; 3]);
+
+        let (opts, _) = staged_opts(&job_dir, false, None);
+        let tile_set = TileSet::from_dir(&imgs_dir, 8, &opts).unwrap();
+
+        // the tiles are read from the job
+        let (opts, stages) = staged_opts(&job_dir, true, None);
+        let resumed = TileSet::from_dir(&imgs_dir, 8, &opts).unwrap();
+        assert_eq!(resumed.ids(), tile_set.ids());
+        assert_eq!(resumed.imgs.get(7).as_raw(), tile_set.imgs.get(7).as_raw());
+        assert!(
+            !stages.lock().unwrap().contains(&Stage::LoadingImages),
+            "{stages:?}"
+        );
+
+        // stopped once all cells are scored, after which their errors are replaced such that
+        // the darkest tiles fit best
+        let (opts, _) = staged_opts(&job_dir, false, Some(Stage::SelectingImages));
+        let result = MosaicBuilder::new(&resumed, opts).build(&target_img);
+        assert!(matches!(result, Err(MosaicError::Cancelled)), "{result:?}");
+
+        let target = target_job(&job_dir);
+        let (errors, _) = target.error_log().unwrap();
+        assert_eq!(errors.len(), 4);
+        fs::remove_file(target.dir.join(ERRORS_FILE)).unwrap();
+        let (_, mut log) = target.error_log().unwrap();
+        for &(row, col) in errors.keys() {
+            log.append(row, col, &[(0, 0.1), (1, 0.2), (2, 0.3), (3, 0.4)])
+                .unwrap();
+        }
+        log.flush().unwrap();
+
+        let (opts, _) = staged_opts(&job_dir, true, Some(Stage::InsertingImages));
+        let result = MosaicBuilder::new(&resumed, opts).build(&target_img);
+        assert!(matches!(result, Err(MosaicError::Cancelled)), "{result:?}");
+
+        let assignment = target.load_assignment(2, 2, 8).unwrap().unwrap();
+        let picked: HashSet<usize> = assignment.iter().flatten().copied().collect();
+        assert_eq!(picked, HashSet::from([0, 1, 2, 3]));
+
+        // the assignment is used as it was stored
+        write_json(&target.dir.join(ASSIGNMENT_FILE), &[[5, 5], [5, 5]]).unwrap();
+
+        let (opts, _) = staged_opts(&job_dir, true, None);
+        let mosaic = MosaicBuilder::new(&resumed, opts)
+            .build(&target_img)
+            .unwrap();
+        let ids: Vec<&PathBuf> = mosaic.plan.placements.iter().map(|p| &p.id).collect();
+        assert_eq!(ids, [&resumed.ids()[5]; 4]);
+    }
+
+    #[test]
+    fn resume_with_other_inputs_or_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let imgs_dir = dir.path().join("imgs");
+        let job_dir = dir.path().join("job");
+        write_solid_pngs(&imgs_dir, 3, 16);
+
+        TileSet::from_dir(&imgs_dir, 8, &job_opts(&job_dir, false)).unwrap();
+
+        let other_opts = [
+            MakeImgOfImsOpts {
+                f32_scoring: true,
+                ..job_opts(&job_dir, true)
+            },
+            MakeImgOfImsOpts {
+                fit_mode: FitMode::Pad { background: [1; 3] },
+                ..job_opts(&job_dir, true)
+            },
+            MakeImgOfImsOpts {
+                exclude: vec!["*.jpg".to_owned()],
+                ..job_opts(&job_dir, true)
+            },
+        ];
+        for opts in other_opts {
+            let result = TileSet::from_dir(&imgs_dir, 8, &opts);
+            assert!(
+                matches!(
+                    result,
+                    Err(MosaicError::JobChanged {
+                        what: "options",
+                        ..
+                    })
+                ),
+                "{result:?}"
+            );
+        }
+
+        write_solid_pngs(&imgs_dir.join("more"), 1, 16);
+        let result = TileSet::from_dir(&imgs_dir, 8, &job_opts(&job_dir, true));
+        assert!(
+            matches!(
+                result,
+                Err(MosaicError::JobChanged {
+                    what: "input images",
+                    ..
+                })
+            ),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn files_are_replaced_through_a_temporary_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.json");
+        // a file which `with_extension` would have taken as the temporary file
+        fs::write(dir.path().join("tiles.tmp"), "other").unwrap();
+
+        write_json(&path, &[1, 2]).unwrap();
+
+        let names: HashSet<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(
+            names,
+            HashSet::from(["tiles.json".into(), "tiles.tmp".into()])
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("tiles.tmp")).unwrap(),
+            "other"
+        );
+        assert_eq!(read_json::<Vec<u32>>(&path).unwrap(), [1, 2]);
+    }
+}
@@ -0,0 +1,277 @@
+/// Sum of `(a[i] - b[i])^2` over two slices of the same length, using AVX2, SSE2 or NEON when
+/// available. The sum is exact, so every implementation gives the same result as the scalar one.
+pub(crate) fn sum_squared_diff(a: &[u8], b: &[u8]) -> u64 {
+    assert_eq!(a.len(), b.len());
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: the cpu supports avx2
+            return unsafe { x86::sum_squared_diff_avx2(a, b) };
+        }
+        if is_x86_feature_detected!("sse2") {
+            // SAFETY: the cpu supports sse2
+            return unsafe { x86::sum_squared_diff_sse2(a, b) };
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            // SAFETY: the cpu supports neon
+            return unsafe { aarch64::sum_squared_diff_neon(a, b) };
+        }
+    }
+
+    sum_squared_diff_scalar(a, b)
+}
+
+pub(crate) fn sum_squared_diff_scalar(a: &[u8], b: &[u8]) -> u64 {
+    a.iter()
+        .zip(b)
+        .map(|(&a, &b)| {
+            let d = a.abs_diff(b) as u64;
+            d * d
+        })
+        .sum()
+}
+
+/// Sum of the squared differences of `a` and `b` as `f32` channel values scaled to `[0, 1]`,
+/// summed over the channels of a pixel and then over the pixels, in order. This is how errors were
+/// summed before the exact kernel, and rounds exactly like it did.
+pub(crate) fn sum_squared_diff_f32(a: &[u8], b: &[u8]) -> f32 {
+    assert_eq!(a.len(), b.len());
+
+    a.chunks_exact(3)
+        .zip(b.chunks_exact(3))
+        .map(|(p1, p2)| {
+            p1.iter()
+                .zip(p2)
+                .map(|(&v1, &v2)| (v1 as f32 / 255.0 - v2 as f32 / 255.0).powi(2))
+                .sum::<f32>()
+        })
+        .sum()
+}
+
+/// Chunks after which the 32 bit lanes of the SIMD accumulators are added to the total, each
+/// chunk adds at most `4 * 255^2` to a lane.
+const CHUNKS_PER_FLUSH: usize = 4096;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    use super::{sum_squared_diff_scalar, CHUNKS_PER_FLUSH};
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn sum_squared_diff_avx2(a: &[u8], b: &[u8]) -> u64 {
+        const LANES: usize = 32;
+
+        let n_chunks = a.len() / LANES;
+        let zero = _mm256_setzero_si256();
+        let mut total = 0u64;
+
+        for block in (0..n_chunks).step_by(CHUNKS_PER_FLUSH) {
+            let mut acc = _mm256_setzero_si256();
+
+            for chunk in block..(block + CHUNKS_PER_FLUSH).min(n_chunks) {
+                let offset = chunk * LANES;
+                let va = _mm256_loadu_si256(a.as_ptr().add(offset) as *const __m256i);
+                let vb = _mm256_loadu_si256(b.as_ptr().add(offset) as *const __m256i);
+
+                let diff_lo = _mm256_sub_epi16(
+                    _mm256_unpacklo_epi8(va, zero),
+                    _mm256_unpacklo_epi8(vb, zero),
+                );
+                let diff_hi = _mm256_sub_epi16(
+                    _mm256_unpackhi_epi8(va, zero),
+                    _mm256_unpackhi_epi8(vb, zero),
+                );
+
+                acc = _mm256_add_epi32(acc, _mm256_madd_epi16(diff_lo, diff_lo));
+                acc = _mm256_add_epi32(acc, _mm256_madd_epi16(diff_hi, diff_hi));
+            }
+
+            let mut lanes = [0u32; 8];
+            _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, acc);
+            total += lanes.iter().map(|&l| l as u64).sum::<u64>();
+        }
+
+        let tail = n_chunks * LANES;
+        total + sum_squared_diff_scalar(&a[tail..], &b[tail..])
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn sum_squared_diff_sse2(a: &[u8], b: &[u8]) -> u64 {
+        const LANES: usize = 16;
+
+        let n_chunks = a.len() / LANES;
+        let zero = _mm_setzero_si128();
+        let mut total = 0u64;
+
+        for block in (0..n_chunks).step_by(CHUNKS_PER_FLUSH) {
+            let mut acc = _mm_setzero_si128();
+
+            for chunk in block..(block + CHUNKS_PER_FLUSH).min(n_chunks) {
+                let offset = chunk * LANES;
+                let va = _mm_loadu_si128(a.as_ptr().add(offset) as *const __m128i);
+                let vb = _mm_loadu_si128(b.as_ptr().add(offset) as *const __m128i);
+
+                let diff_lo =
+                    _mm_sub_epi16(_mm_unpacklo_epi8(va, zero), _mm_unpacklo_epi8(vb, zero));
+                let diff_hi =
+                    _mm_sub_epi16(_mm_unpackhi_epi8(va, zero), _mm_unpackhi_epi8(vb, zero));
+
+                acc = _mm_add_epi32(acc, _mm_madd_epi16(diff_lo, diff_lo));
+                acc = _mm_add_epi32(acc, _mm_madd_epi16(diff_hi, diff_hi));
+            }
+
+            let mut lanes = [0u32; 4];
+            _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, acc);
+            total += lanes.iter().map(|&l| l as u64).sum::<u64>();
+        }
+
+        let tail = n_chunks * LANES;
+        total + sum_squared_diff_scalar(&a[tail..], &b[tail..])
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64 {
+    use std::arch::aarch64::*;
+
+    use super::{sum_squared_diff_scalar, CHUNKS_PER_FLUSH};
+
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn sum_squared_diff_neon(a: &[u8], b: &[u8]) -> u64 {
+        const LANES: usize = 16;
+
+        let n_chunks = a.len() / LANES;
+        let mut total = 0u64;
+
+        for block in (0..n_chunks).step_by(CHUNKS_PER_FLUSH) {
+            let mut acc = vdupq_n_u32(0);
+
+            for chunk in block..(block + CHUNKS_PER_FLUSH).min(n_chunks) {
+                let offset = chunk * LANES;
+                let va = vld1q_u8(a.as_ptr().add(offset));
+                let vb = vld1q_u8(b.as_ptr().add(offset));
+
+                let diff = vabdq_u8(va, vb);
+                let sq_lo = vmull_u8(vget_low_u8(diff), vget_low_u8(diff));
+                let sq_hi = vmull_u8(vget_high_u8(diff), vget_high_u8(diff));
+
+                acc = vpadalq_u16(acc, sq_lo);
+                acc = vpadalq_u16(acc, sq_hi);
+            }
+
+            total += vaddlvq_u32(acc);
+        }
+
+        let tail = n_chunks * LANES;
+        total + sum_squared_diff_scalar(&a[tail..], &b[tail..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    /// Lengths with and without a tail, and long enough to flush the accumulators more than once.
+    fn lengths() -> Vec<usize> {
+        let long = CHUNKS_PER_FLUSH * 32;
+        let mut lengths = vec![0, 1, 15, 16, 31, 32, 33, 100, 1000];
+        lengths.extend([long - 1, long, long + 17, 2 * long + 45]);
+        lengths
+    }
+
+    fn random_bytes(rng: &mut StdRng, len: usize) -> Vec<u8> {
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    /// Compares `kernel` with the scalar implementation on random and worst case inputs.
+    fn check_matches_scalar(kernel: impl Fn(&[u8], &[u8]) -> u64) {
+        let mut rng = StdRng::seed_from_u64(40);
+
+        for len in lengths() {
+            let a = random_bytes(&mut rng, len);
+            let b = random_bytes(&mut rng, len);
+            assert_eq!(kernel(&a, &b), sum_squared_diff_scalar(&a, &b), "len {len}");
+
+            // the largest differences, which come closest to overflowing the lanes
+            let zeros = vec![0; len];
+            let full = vec![255; len];
+            assert_eq!(kernel(&zeros, &full), len as u64 * 255 * 255, "len {len}");
+            assert_eq!(kernel(&full, &zeros), len as u64 * 255 * 255, "len {len}");
+        }
+    }
+
+    #[test]
+    fn dispatch_matches_scalar() {
+        check_matches_scalar(sum_squared_diff);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn avx2_matches_scalar() {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: the cpu supports avx2
+            check_matches_scalar(|a, b| unsafe { x86::sum_squared_diff_avx2(a, b) });
+        }
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn sse2_matches_scalar() {
+        if is_x86_feature_detected!("sse2") {
+            // SAFETY: the cpu supports sse2
+            check_matches_scalar(|a, b| unsafe { x86::sum_squared_diff_sse2(a, b) });
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn neon_matches_scalar() {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            // SAFETY: the cpu supports neon
+            check_matches_scalar(|a, b| unsafe { aarch64::sum_squared_diff_neon(a, b) });
+        }
+    }
+
+    /// The error of a tile used to be summed in `f32`, over channel values scaled to `[0, 1]`.
+    /// The exact sum only differs from it by the rounding of that accumulation.
+    #[test]
+    fn matches_f32_error() {
+        let mut rng = StdRng::seed_from_u64(41);
+
+        for n_pixels in [1, 7, 64, 100 * 100] {
+            let a = random_bytes(&mut rng, n_pixels * 3);
+            let b = random_bytes(&mut rng, n_pixels * 3);
+
+            let f32_error = a
+                .chunks(3)
+                .zip(b.chunks(3))
+                .map(|(p1, p2)| {
+                    p1.iter()
+                        .zip(p2)
+                        .map(|(&v1, &v2)| (v1 as f32 / 255.0 - v2 as f32 / 255.0).powi(2))
+                        .sum::<f32>()
+                })
+                .sum::<f32>()
+                / n_pixels as f32;
+
+            let sum = sum_squared_diff(&a, &b);
+            let error = (sum as f64 / (255.0 * 255.0 * n_pixels as f64)) as f32;
+
+            assert!(
+                (error - f32_error).abs() <= 1e-4 * f32_error.max(1.0),
+                "{n_pixels} pixels: {error} != {f32_error}"
+            );
+        }
+    }
+}
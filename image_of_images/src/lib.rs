@@ -1,14 +1,12 @@
 use std::{
     collections::{HashMap, HashSet},
-    path::{Path, PathBuf},
+    io,
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
-use crossbeam::channel::SendError;
-use image::{
-    buffer::ConvertBuffer, io::Reader as ImageReader, DynamicImage, GenericImageView, ImageBuffer,
-    Rgb, Rgba,
-};
-use itertools::Itertools;
+use image::{DynamicImage, GenericImageView, ImageFormat, Rgb, RgbImage};
 use rand::prelude::*;
 use rayon::{
     iter::{
@@ -16,15 +14,87 @@ use rayon::{
     },
     slice::ParallelSliceMut,
 };
+use serde::{Deserialize, Serialize};
+
+mod band;
+mod cancel;
+mod cluster;
+mod error;
+mod job;
+mod kernel;
+mod load;
+mod manifest;
+mod orientation;
+mod plan;
+mod progress;
+mod pyramid;
+mod quota;
+mod search;
+mod source;
+mod storage;
+#[cfg(test)]
+mod test_util;
+mod validate;
+
+pub use cancel::CancellationToken;
+pub use cluster::{ClusterModel, TileIndex};
+pub use error::{MosaicError, OptionProblem};
+pub use manifest::ManifestSource;
+pub use plan::{
+    render_plan, render_plan_to_file, CropWindow, MosaicPlan, TilePlacement, Transform,
+};
+pub use progress::{
+    progress_channel, progress_event_channel, ProgressEvent, ProgressEventReceiver,
+    ProgressEventSender, ProgressReceiver, ProgressReporter, ProgressSender, Stage,
+    MIN_PROGRESS_INTERVAL,
+};
+pub use source::{
+    open_source, DirSource, ReadFile, SourceFile, TarSource, TileInput, TileMetadata, TileSource,
+    ZipSource,
+};
+pub use validate::FoundInputs;
+
+use cancel::Cancelled;
+use cluster::ClusterSearch;
+use job::{ErrorLog, Job, JobInfo, TargetJob};
+use progress::Progress;
+use pyramid::{Pyramid, Shortlist};
+use quota::Quotas;
+use search::CandidateSearch;
+use storage::{TileStore, TileView};
+
+type ResizedTiles = HashMap<(u32, u32), Arc<TileStore>>;
+/// Pyramids of the resized tiles, by the dimensions of the cells and the number of levels
+type Pyramids = HashMap<(u32, u32, usize), Arc<Pyramid>>;
+
+/// Number of leading bytes of a file which are enough to recognise every supported format.
+const FORMAT_SIGNATURE_LEN: u64 = 16;
+
+/// How `include` and `exclude` patterns are matched: `*` stays within a folder, and case doesn't
+/// matter, so `*.jpg` also matches camera exports like `IMG_1.JPG`.
+const PATTERN_MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
 
-type Image = ImageBuffer<Rgb<f32>, Vec<f32>>;
-pub type ProgressSender = crossbeam::channel::Sender<(usize, usize, &'static str)>;
-pub type ProgressReceiver = crossbeam::channel::Receiver<(usize, usize, &'static str)>;
-
-pub const IMAGE_EXTENSIONS: [&str; 3] = ["png", "jpg", "jpeg"];
+/// Extensions of the formats which can be read, e.g. to filter file dialogs. Images in an input
+/// folder are recognised by their content instead.
+pub fn image_extensions() -> Vec<&'static str> {
+    ImageFormat::all()
+        .filter(ImageFormat::reading_enabled)
+        .flat_map(|format| format.extensions_str().iter().copied())
+        .collect()
+}
 
-pub fn progress_channel() -> (ProgressSender, ProgressReceiver) {
-    crossbeam::channel::unbounded()
+/// Format of the image at `path`, recognised from `header`, the first bytes of the file. Formats
+/// without a signature, like TGA, are recognised from the extension regardless of its case. `None`
+/// when the file is not an image which can be read.
+fn image_format(path: &Path, header: &[u8]) -> Option<ImageFormat> {
+    image::guess_format(header)
+        .ok()
+        .or_else(|| ImageFormat::from_path(path).ok())
+        .filter(ImageFormat::reading_enabled)
 }
 
 pub fn find_free_filepath(dir: impl AsRef<Path>, base: &str, extension: &str) -> PathBuf {
@@ -40,13 +110,10 @@ pub fn find_free_filepath(dir: impl AsRef<Path>, base: &str, extension: &str) ->
     result
 }
 
-fn handle_progress_send_error(e: Result<(), SendError<(usize, usize, &'static str)>>) {
-    if let Err(e) = e {
-        log::warn!("Failed sending progress: {:?}", e);
-    }
-}
-
-fn resize_img(img: Image, width: u32, height: u32) -> Image {
+fn resize_img<I>(img: &I, width: u32, height: u32) -> RgbImage
+where
+    I: GenericImageView<Pixel = Rgb<u8>>,
+{
     let width = width as f32;
     let height = height as f32;
     let im_w = img.width() as f32;
@@ -58,7 +125,7 @@ fn resize_img(img: Image, width: u32, height: u32) -> Image {
     let scale = width_scale.max(height_scale);
 
     let mut img = image::imageops::resize(
-        &img,
+        img,
         (im_w * scale).ceil() as u32,
         (im_h * scale).ceil() as u32,
         image::imageops::FilterType::Triangle,
@@ -75,150 +142,414 @@ fn resize_img(img: Image, width: u32, height: u32) -> Image {
 
     let r = image::imageops::crop(&mut img, left, top, width, height).to_image();
     assert_eq!(r.dimensions(), (width, height));
-    r.convert()
+    r
 }
 
-fn load_imgs_from_dir(
-    dir: impl AsRef<Path>,
-    width: u32,
-    height: u32,
-    n_max_imgs: Option<usize>,
-    progress_sender: &Option<ProgressSender>,
-) -> anyhow::Result<Vec<Image>> {
-    let mut result = Vec::new();
-    let dir = dir.as_ref();
+/// `path` with symlinks and relative components resolved, such that different paths to the same
+/// file compare equal. Paths which can't be resolved, like those in archives, are kept as is.
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
 
-    let dir = dir
-        .to_str()
-        .ok_or(anyhow::anyhow!("Failed converting dir to str"))?
-        .to_string();
+/// Files found in a [`TileSource`].
+pub(crate) struct FoundImgs {
+    imgs: Vec<PathBuf>,
+    /// Metadata of the images which have any
+    metadata: HashMap<PathBuf, TileMetadata>,
+    /// Files which are not images that can be read
+    skipped: Vec<PathBuf>,
+}
 
-    let mut all_imgs = Vec::new();
+impl FoundImgs {
+    /// Reports the skipped files, the paths are only logged.
+    fn report_skipped(&self, progress: &Progress) {
+        if self.skipped.is_empty() {
+            return;
+        }
 
-    for ext in IMAGE_EXTENSIONS {
-        let glob_pattern = format!("{}/**/*.{}", &dir, ext);
-        all_imgs.extend(glob::glob(&glob_pattern)?)
-    }
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for path in &self.skipped {
+            log::info!("Skipped {path:?}, which is not a supported image");
 
-    if let Some(n) = n_max_imgs {
-        all_imgs.shuffle(&mut rand::thread_rng());
-        all_imgs = all_imgs.into_iter().take(n).collect();
+            let kind = match path.extension().and_then(|e| e.to_str()) {
+                Some(ext) => format!(".{}", ext.to_ascii_lowercase()),
+                None => "without extension".to_owned(),
+            };
+            *counts.entry(kind).or_default() += 1;
+        }
+
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|(k1, n1), (k2, n2)| n2.cmp(n1).then(k1.cmp(k2)));
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(kind, n)| format!("{n} {kind}"))
+            .collect();
+
+        progress.warn(format!(
+            "Skipped {} files which are not supported images ({})",
+            self.skipped.len(),
+            counts.join(", ")
+        ));
     }
+}
+
+/// Compiles a glob pattern of option `option`.
+fn glob_pattern(pattern: &str, option: &'static str) -> Result<glob::Pattern, OptionProblem> {
+    glob::Pattern::new(pattern).map_err(|e| {
+        OptionProblem::new(
+            option,
+            format!("Invalid pattern {pattern:?}: {e}"),
+            "Special characters like `[` can be matched with `[[]`",
+        )
+    })
+}
 
-    let n_imgs = all_imgs.len();
+/// Compiles the glob patterns of option `option`.
+fn glob_patterns(
+    patterns: &[String],
+    option: &'static str,
+) -> Result<Vec<glob::Pattern>, MosaicError> {
+    patterns
+        .iter()
+        .map(|p| Ok(glob_pattern(p, option)?))
+        .collect()
+}
 
-    for (i, entry) in all_imgs.into_iter().enumerate() {
-        if let Some(s) = progress_sender {
-            let e = s.send((i, n_imgs, "Loading images from disk"));
-            handle_progress_send_error(e);
+/// The problem with pinning an image to the cell at `(row, col)` of `grid`, if any.
+fn pinned_cell_problem(grid: Grid, row: u32, col: u32) -> Option<OptionProblem> {
+    (row >= grid.n_vertical || col >= grid.n_horizontal).then(|| {
+        OptionProblem::new(
+            "pinned_tiles",
+            format!(
+                "Pinned cell ({row}, {col}) lies outside of the {}x{} grid",
+                grid.n_horizontal, grid.n_vertical
+            ),
+            format!(
+                "Use a row below {} and a column below {}",
+                grid.n_vertical, grid.n_horizontal
+            ),
+        )
+    })
+}
+
+/// The images in `source` which are found with `include`, `exclude`, `max_depth` and
+/// `skip_hidden`, before `max_imgs` and `pinned_tiles` are applied. Images are recognised by their
+/// content, so their extension does not matter.
+fn find_all_imgs(
+    source: &dyn TileSource,
+    opts: &MakeImgOfImsOpts,
+) -> Result<FoundImgs, MosaicError> {
+    let include = glob_patterns(&opts.include, "include")?;
+    let exclude = glob_patterns(&opts.exclude, "exclude")?;
+
+    let dir = source.location();
+    let keep = |path: &Path| {
+        let relative = path.strip_prefix(dir).ok();
+        // the images of a manifest are matched relative to its folder, unless they are elsewhere
+        let matched_path = relative
+            .or_else(|| {
+                dir.parent()
+                    .and_then(|parent| path.strip_prefix(parent).ok())
+            })
+            .unwrap_or(path);
+        let matches = |p: &glob::Pattern| p.matches_path_with(matched_path, PATTERN_MATCH_OPTIONS);
+
+        // folders already apply `max_depth` and `skip_hidden` while searching, but archives
+        // don't, and the images listed in a manifest are used wherever they are
+        if let Some(relative) = relative {
+            let names: Vec<_> = relative
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(name) => Some(name),
+                    _ => None,
+                })
+                .collect();
+            if opts.skip_hidden && names.iter().any(|name| source::is_hidden(name)) {
+                return false;
+            }
+            if opts.max_depth.is_some_and(|d| names.len() > d + 1) {
+                return false;
+            }
         }
-        match entry {
-            Ok(path) => {
-                if let Some(img) = ImageReader::open(&path).ok().and_then(|r| r.decode().ok()) {
-                    let img = resize_img(img.into_rgb32f(), width, height);
-                    result.push(img);
-                } else {
-                    log::warn!("Failed loading image: {path:?}");
+
+        (include.is_empty() || include.iter().any(matches)) && !exclude.iter().any(matches)
+    };
+
+    let files = source.files(FORMAT_SIGNATURE_LEN, &keep)?;
+
+    let mut found = FoundImgs {
+        imgs: Vec::new(),
+        metadata: HashMap::new(),
+        skipped: Vec::new(),
+    };
+    for file in files {
+        match image_format(&file.path, &file.header) {
+            Some(_) => {
+                if !file.metadata.is_empty() {
+                    found.metadata.insert(file.path.clone(), file.metadata);
                 }
+                found.imgs.push(file.path);
             }
-            Err(_) => (),
+            None => found.skipped.push(file.path),
         }
     }
 
-    Ok(result)
+    Ok(found)
 }
 
-fn load_and_resize_target_img(
-    target_img_path: impl AsRef<Path>,
-    width: u32,
-) -> anyhow::Result<Image> {
-    let img = ImageReader::open(target_img_path)?.decode()?;
+/// At most `max_imgs` random images of `all_imgs`, together with the pinned images.
+fn pick_imgs(mut all_imgs: Vec<PathBuf>, opts: &MakeImgOfImsOpts) -> Vec<PathBuf> {
+    if let Some(n) = opts.max_imgs {
+        all_imgs.shuffle(&mut rand::thread_rng());
+        all_imgs = all_imgs.into_iter().take(n).collect();
+    }
 
-    let im_width = img.width() as f32;
-    let im_height = img.height() as f32;
-    let scale = width as f32 / im_width;
+    // pinned images are always used, regardless of exclusions or the maximum number of images
+    let mut picked: HashSet<PathBuf> = all_imgs.iter().map(|p| canonical(p)).collect();
+    for path in opts.pinned_tiles.values() {
+        if picked.insert(canonical(path)) {
+            all_imgs.push(path.clone());
+        }
+    }
 
-    let img = image::imageops::resize(
-        &img,
-        (im_width * scale) as u32,
-        (im_height * scale) as u32,
-        image::imageops::FilterType::Triangle,
-    );
+    all_imgs
+}
 
-    Ok(img.convert())
+fn target_dimensions(
+    im_width: u32,
+    im_height: u32,
+    width: Option<u32>,
+    height: Option<u32>,
+) -> (u32, u32) {
+    let scaled = |v: u32, scale: f32| ((v as f32 * scale).round() as u32).max(1);
+
+    match (width, height) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, scaled(im_height, w as f32 / im_width as f32)),
+        (None, Some(h)) => (scaled(im_width, h as f32 / im_height as f32), h),
+        (None, None) => (im_width, im_height),
+    }
 }
 
-fn squared_error(target_img: &Image, fill_img: &Image, x_start: u32, y_start: u32) -> f32 {
-    // let mut sum = 0.0;
+/// The part of `img` which is covered by `grid` once `img` is resized to `target_width` x
+/// `target_height`, resized to `match_grid` with 8 bits per channel. The target is never resized
+/// to its full size, as only the cells at the size they are scored at are needed.
+fn match_target(
+    img: &DynamicImage,
+    (target_width, target_height): (u32, u32),
+    grid: Grid,
+    match_grid: Grid,
+    fit_mode: FitMode,
+) -> RgbImage {
+    // the region of the resized target which the grid covers
+    let (left, top, width, height) = match fit_mode {
+        FitMode::Crop | FitMode::Pad { .. } => (
+            (target_width - grid.width()) / 2,
+            (target_height - grid.height()) / 2,
+            grid.width(),
+            grid.height(),
+        ),
+        FitMode::Stretch => (0, 0, target_width, target_height),
+    };
 
-    let (w, h) = fill_img.dimensions();
+    let (im_w, im_h) = img.dimensions();
+    let to_src = |v: u32, target_len: u32, len: u32| {
+        ((v as f32 * len as f32 / target_len as f32).round() as u32).min(len)
+    };
+
+    let x = to_src(left, target_width, im_w).min(im_w - 1);
+    let y = to_src(top, target_height, im_h).min(im_h - 1);
+    let width = to_src(width, target_width, im_w).clamp(1, im_w - x);
+    let height = to_src(height, target_height, im_h).clamp(1, im_h - y);
+
+    image::imageops::resize(
+        &img.crop_imm(x, y, width, height).to_rgb8(),
+        match_grid.width(),
+        match_grid.height(),
+        image::imageops::FilterType::Triangle,
+    )
+}
 
-    let patch = target_img.view(x_start, y_start, w, h);
+/// How the target is fitted to a grid whose size is not an exact divisor of the target size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum FitMode {
+    /// Crop the remaining margins off the target, the output is slightly smaller than the target
+    #[default]
+    Crop,
+    /// Stretch the target to fit the grid and scale the result back to the target dimensions
+    Stretch,
+    /// Keep the target dimensions and fill the remaining margins with a background colour
+    Pad { background: [u8; 3] },
+}
 
-    let sum = patch
-        .pixels()
-        .zip(fill_img.pixels())
-        .map(|(p1, &p2)| {
-            p1.2 .0
-                .into_iter()
-                .zip(p2.0.into_iter())
-                .map(|(v1, v2)| (v1 - v2).powi(2))
-                .sum::<f32>()
-        })
-        .sum::<f32>();
+/// Derives the grid from the shape of the tiles instead of a fixed number of horizontal and
+/// vertical images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AutoGrid {
+    /// Width to height ratio of the tiles, e.g. `(4, 3)`
+    pub tile_aspect_ratio: (u32, u32),
+    pub size: AutoGridSize,
+}
 
-    sum / (w as f32 * h as f32)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AutoGridSize {
+    /// Approximate total number of tiles
+    NumTiles(u32),
+    /// Width of a single tile in pixels
+    TileWidth(u32),
 }
 
-fn insert_sub_img(target_img: &mut Image, fill_img: &Image, x_start: u32, y_start: u32) {
-    let (w, h) = fill_img.dimensions();
+/// The grid of tiles which makes up the result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grid {
+    pub n_horizontal: u32,
+    pub n_vertical: u32,
+    pub tile_width: u32,
+    pub tile_height: u32,
+}
 
-    for i in 0..w {
-        for j in 0..h {
-            let x = x_start + i;
-            let y = y_start + j;
+impl Grid {
+    pub fn new(
+        target_width: u32,
+        target_height: u32,
+        n_horizontal: u32,
+        n_vertical: u32,
+        fit_mode: FitMode,
+    ) -> Self {
+        let (tile_width, tile_height) = match fit_mode {
+            FitMode::Crop | FitMode::Pad { .. } => {
+                (target_width / n_horizontal, target_height / n_vertical)
+            }
+            FitMode::Stretch => (
+                ((target_width as f32 / n_horizontal as f32).round() as u32).max(1),
+                ((target_height as f32 / n_vertical as f32).round() as u32).max(1),
+            ),
+        };
 
-            target_img.put_pixel(x, y, fill_img.get_pixel(i, j).clone())
+        Self {
+            n_horizontal,
+            n_vertical,
+            tile_width,
+            tile_height,
         }
     }
-}
 
-fn insert_sub_imgs(
-    target_img: &mut Image,
-    sub_imgs: &[Vec<&Image>],
-    progress_sender: &Option<ProgressSender>,
-) {
-    assert!(sub_imgs.len() > 0);
-    assert!(sub_imgs[0].len() > 0);
-
-    let mut x_from = 0;
-    let mut y_from = 0;
+    /// Picks the grid whose tiles have the requested aspect ratio and which best covers the target.
+    /// With [`AutoGridSize::NumTiles`], the tile widths around the one which would give exactly
+    /// that many tiles are tried as well, as rounding the tiles to whole pixels and fitting them to
+    /// the target changes the number of tiles. The grid with the number of tiles closest to the
+    /// requested one is used.
+    pub fn auto(
+        target_width: u32,
+        target_height: u32,
+        auto_grid: AutoGrid,
+        fit_mode: FitMode,
+    ) -> Self {
+        let (aspect_w, aspect_h) = auto_grid.tile_aspect_ratio;
+        let aspect = aspect_w.max(1) as f32 / aspect_h.max(1) as f32;
+
+        let n_tiles = |len: u32, tile: u32| match fit_mode {
+            FitMode::Crop | FitMode::Pad { .. } => (len / tile).max(1),
+            FitMode::Stretch => ((len as f32 / tile as f32).round() as u32).max(1),
+        };
+
+        let grid = |tile_width: u32| {
+            let tile_width = tile_width.clamp(1, target_width.max(1));
+            let tile_height =
+                ((tile_width as f32 / aspect).round() as u32).clamp(1, target_height.max(1));
+
+            Self {
+                n_horizontal: n_tiles(target_width, tile_width),
+                n_vertical: n_tiles(target_height, tile_height),
+                tile_width,
+                tile_height,
+            }
+        };
+
+        match auto_grid.size {
+            AutoGridSize::TileWidth(w) => grid(w),
+            AutoGridSize::NumTiles(n) => {
+                let ideal =
+                    (target_width as f32 * target_height as f32 * aspect / n.max(1) as f32).sqrt();
+                let min = ((ideal * 0.5).floor() as u32).max(1);
+                let max = ((ideal * 1.5).ceil() as u32).min(target_width).max(min);
+
+                (min..=max)
+                    .map(grid)
+                    .min_by(|g1, g2| {
+                        let off = |g: &Grid| g.n_cells().abs_diff(n as usize);
+                        let dist = |g: &Grid| (g.tile_width as f32 - ideal).abs();
+                        off(g1)
+                            .cmp(&off(g2))
+                            .then(dist(g1).partial_cmp(&dist(g2)).unwrap())
+                    })
+                    .expect("there is at least one tile width")
+            }
+        }
+    }
 
-    let (w, h) = sub_imgs[0][0].dimensions();
-    let total = sub_imgs.iter().map(Vec::len).sum::<usize>();
-    let mut cur = 0;
+    pub fn width(&self) -> u32 {
+        self.n_horizontal * self.tile_width
+    }
 
-    for sub_img_row in sub_imgs {
-        for &sub_img in sub_img_row {
-            if let Some(s) = progress_sender {
-                let e = s.send((cur, total, "Inserting images in target"));
-                handle_progress_send_error(e);
-                cur += 1;
-            }
-            assert!((w, h) == sub_img.dimensions());
+    pub fn height(&self) -> u32 {
+        self.n_vertical * self.tile_height
+    }
 
-            // dbg!(x_from);
-            // dbg!(y_from);
+    pub fn n_cells(&self) -> usize {
+        self.n_horizontal as usize * self.n_vertical as usize
+    }
 
-            insert_sub_img(target_img, sub_img, x_from, y_from);
+    /// The grid with its tiles scaled down such that their longest side is at most `max_size`.
+    fn downscaled(self, max_size: u32) -> Self {
+        let scale = (max_size as f32 / self.tile_width.max(self.tile_height) as f32).min(1.0);
+        let scaled = |v: u32| ((v as f32 * scale).round() as u32).max(1);
 
-            x_from += w;
+        Self {
+            tile_width: scaled(self.tile_width),
+            tile_height: scaled(self.tile_height),
+            ..self
         }
-        y_from += h;
-        x_from = 0;
     }
 }
 
+/// Pixels of the `width` x `height` cell at `(x_start, y_start)`, packed like the pixels of a
+/// tile.
+fn cell_pixels(
+    target_img: &RgbImage,
+    x_start: u32,
+    y_start: u32,
+    width: u32,
+    height: u32,
+) -> Vec<u8> {
+    let row_len = width as usize * 3;
+    let stride = target_img.width() as usize * 3;
+    let raw = target_img.as_raw();
+
+    let mut result = Vec::with_capacity(row_len * height as usize);
+    for y in y_start..y_start + height {
+        let start = y as usize * stride + x_start as usize * 3;
+        result.extend_from_slice(&raw[start..start + row_len]);
+    }
+
+    result
+}
+
+/// Mean over the pixels of the squared error summed over the channels, with channel values scaled
+/// to `[0, 1]`. `cell` contains the pixels of the target as returned by [`cell_pixels`]. See
+/// [`MakeImgOfImsOpts::f32_scoring`] for `f32_scoring`.
+fn squared_error(cell: &[u8], fill_img: &TileView, f32_scoring: bool) -> f32 {
+    let (w, h) = fill_img.dimensions();
+    let n_pixels = w as f32 * h as f32;
+
+    if f32_scoring {
+        return kernel::sum_squared_diff_f32(cell, fill_img.as_raw()) / n_pixels;
+    }
+
+    let sum = kernel::sum_squared_diff(cell, fill_img.as_raw());
+    (sum as f64 / (255.0 * 255.0 * n_pixels as f64)) as f32
+}
+
 fn empty_vec_2d<T>(rows: usize, cols: usize) -> Vec<Vec<Option<T>>> {
     (0..rows)
         .map(|_| (0..cols).map(|_| None).collect())
@@ -231,7 +562,7 @@ fn float_err_to_usize(f: f32, max_err: f32) -> usize {
     let f = f / max_err;
     let f = f * (usize::MAX as f32);
 
-    return f as usize;
+    f as usize
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -253,64 +584,130 @@ impl PartialOrd for ErrInfo {
     }
 }
 
+/// The `n` lowest of the errors of a cell, in the order of the images. Ties are broken by image,
+/// like the stable sort of the errors in [`select_imgs`] does.
+fn lowest_errors(mut errors: Vec<(usize, f32)>, n: usize) -> Vec<(usize, f32)> {
+    if errors.len() > n && n > 0 {
+        errors.select_nth_unstable_by(n - 1, |(i1, e1), (i2, e2)| {
+            e1.partial_cmp(e2).unwrap().then(i1.cmp(i2))
+        });
+        errors.truncate(n);
+        errors.sort_unstable_by_key(|&(idx, _)| idx);
+    }
+
+    errors
+}
+
+/// Scores the images for every cell which is not skipped. Only the best images of a cell are
+/// kept, as many as there are cells to fill: every picked image rules out one image for the other
+/// cells, so a cell always gets one of these unless quotas get in the way, in which case
+/// [`select_imgs`] fills it with the best remaining image. With a `job`, the errors of every cell
+/// are logged as soon as they are computed, and cells logged by an interrupted run are not scored
+/// again.
 fn calc_errors(
-    target_img: &Image,
-    imgs: &[&Image],
-    n_width: u32,
-    n_height: u32,
-    sub_img_width: u32,
-    sub_img_height: u32,
-    progress_sender: &Option<ProgressSender>,
-) -> Vec<ErrInfo> {
+    target_img: &RgbImage,
+    imgs: &[TileView],
+    search: Option<&CandidateSearch>,
+    grid: Grid,
+    skip_cells: &HashSet<(u32, u32)>,
+    job: Option<&TargetJob>,
+    opts: &MakeImgOfImsOpts,
+) -> Result<Vec<ErrInfo>, MosaicError> {
+    let progress = &opts.progress();
+    let cancellation_token = &opts.cancellation_token;
+
+    let (mut saved_errors, mut error_log): (_, Option<ErrorLog>) = match job {
+        Some(job) => {
+            let (errors, log) = job.error_log()?;
+            (errors, Some(log))
+        }
+        None => (HashMap::new(), None),
+    };
+
     let mut result = Vec::new();
 
-    let total = n_width * n_height;
+    let Grid {
+        n_horizontal: n_width,
+        n_vertical: n_height,
+        tile_width: sub_img_width,
+        tile_height: sub_img_height,
+    } = grid;
 
-    for i in (0..n_height) {
+    let n_free_cells = grid.n_cells() - skip_cells.len();
+    let stage_progress = progress.stage(Stage::CalculatingErrors, grid.n_cells());
+
+    for i in 0..n_height {
         for j in 0..n_width {
+            cancellation_token.check()?;
+
             let y_from = i * sub_img_height;
             let x_from = j * sub_img_width;
 
-            if let Some(s) = progress_sender {
-                let e = s.send((
-                    (i * n_width + j) as usize,
-                    total as usize,
-                    "calculating errors",
-                ));
-                handle_progress_send_error(e)
+            stage_progress.inc();
+
+            if skip_cells.contains(&(i, j)) {
+                continue;
             }
 
             // dbg!(n_height); dbg!(n_width); dbg!(x_from); dbg!(y_from); dbg!(sub_img_width); dbg!(sub_img_height);
 
-            let errors: Vec<_> = imgs
-                .par_iter()
-                // .iter()
-                .map(|im| squared_error(target_img, im, x_from, y_from))
-                .enumerate()
-                .map(|(idx, err)| (idx, i, j, err))
-                .collect();
+            let saved = saved_errors
+                .remove(&(i, j))
+                .filter(|errors| errors.iter().all(|&(idx, _)| idx < imgs.len()));
+
+            let errors: Vec<(usize, f32)> = match saved {
+                Some(errors) => errors,
+                None => {
+                    let cell =
+                        cell_pixels(target_img, x_from, y_from, sub_img_width, sub_img_height);
+
+                    let errors: Vec<_> = match search {
+                        Some(search) => search
+                            .candidates(&cell, sub_img_width, sub_img_height)
+                            .into_par_iter()
+                            .map(|idx| (idx, squared_error(&cell, &imgs[idx], opts.f32_scoring)))
+                            .collect(),
+                        None => imgs
+                            .par_iter()
+                            // .iter()
+                            .map(|im| squared_error(&cell, im, opts.f32_scoring))
+                            .enumerate()
+                            .collect(),
+                    };
+                    let errors = lowest_errors(errors, n_free_cells);
+
+                    if let Some(error_log) = &mut error_log {
+                        error_log.append(i, j, &errors)?;
+                    }
+                    errors
+                }
+            };
 
             let pos_err_min = errors
                 .iter()
-                .max_by(|&(_, _, _, err1), &(_, _, _, err2)| err1.partial_cmp(err2).unwrap())
-                .unwrap()
-                .3;
+                .map(|&(_, err)| err)
+                .max_by(|err1, err2| err1.partial_cmp(err2).unwrap())
+                .unwrap();
 
             result.extend(
                 errors
                     .into_iter()
-                    .map(|(img_idx, i_pos, j_pos, err)| (img_idx, i_pos, j_pos, pos_err_min, err)),
+                    .map(|(img_idx, err)| (img_idx, i, j, pos_err_min, err)),
             );
         }
+
+        if let Some(error_log) = &mut error_log {
+            error_log.flush()?;
+        }
     }
 
     let max_err = result
         .iter()
         .map(|r| r.4)
         .max_by(|v1, v2| v1.partial_cmp(v2).unwrap())
-        .unwrap();
+        .unwrap_or(0.0);
 
-    result
+    let result = result
         .into_iter()
         .map(|(img_idx, i_pos, j_pos, pos_err_min, err)| ErrInfo {
             img_idx,
@@ -319,58 +716,66 @@ fn calc_errors(
             pos_err_min: float_err_to_usize(pos_err_min, max_err),
             err: float_err_to_usize(err, max_err),
         })
-        .collect()
-}
-
+        .collect();
 
-fn fill_target_img(
-    mut target_img: Image,
-    imgs: &[&Image],
-    sub_img_width: u32,
-    sub_img_height: u32,
-    pop_used_img: bool,
-    progress_sender: &Option<ProgressSender>,
-) -> anyhow::Result<Image> {
-    let n_width = target_img.width() / sub_img_width;
-    let n_height = target_img.height() / sub_img_height;
+    stage_progress.finish();
+    Ok(result)
+}
 
-    if imgs.len() < (n_width * n_height) as usize {
-        return Err(anyhow::anyhow!("Too few images in directory, try reducing the number of horizontal and/or vertical images"));
+/// Picks an image of `imgs` for every cell of the grid, returned row by row. `pinned` contains
+/// `(row, col, img_idx)` for cells whose image is fixed, pinned images are expected at the end of
+/// `imgs` and are not considered for other cells. With a `search` only its candidates are scored
+/// for a cell, cells whose candidates all end up elsewhere are filled with the best remaining
+/// image. Images are only picked as far as `quotas` allows.
+#[allow(clippy::too_many_arguments)]
+fn select_imgs(
+    target_img: &RgbImage,
+    imgs: &[TileView],
+    search: Option<&CandidateSearch>,
+    pinned: &[(u32, u32, usize)],
+    mut quotas: Quotas,
+    grid: Grid,
+    job: Option<&TargetJob>,
+    opts: &MakeImgOfImsOpts,
+) -> Result<Vec<Vec<usize>>, MosaicError> {
+    let progress = &opts.progress();
+    let cancellation_token = &opts.cancellation_token;
+
+    if imgs.len() < grid.n_cells() {
+        return Err(MosaicError::TooFewImages {
+            available: imgs.len(),
+            required: grid.n_cells(),
+        });
     }
 
-    let pad_width = target_img.width() % sub_img_width;
-    let pad_height = target_img.height() % sub_img_height;
+    assert_eq!(target_img.dimensions(), (grid.width(), grid.height()));
 
-    let cropped_width = target_img.width() - pad_width;
-    let cropped_height = target_img.height() - pad_height;
+    let mut sub_imgs: Vec<Vec<_>> =
+        empty_vec_2d(grid.n_vertical as usize, grid.n_horizontal as usize);
 
-    let target_img = image::imageops::crop(
-        &mut target_img,
-        pad_width / 2,
-        pad_height / 2,
-        cropped_width,
-        cropped_height,
-    )
-    .to_image();
+    for &(row, col, img_idx) in pinned {
+        sub_imgs[row as usize][col as usize] = Some(img_idx);
+    }
+
+    let pinned_cells = pinned.iter().map(|&(row, col, _)| (row, col)).collect();
 
-    let mut result_img = target_img.clone();
-    let mut sub_imgs: Vec<Vec<_>> = empty_vec_2d(n_height as usize, n_width as usize);
+    let free_imgs = &imgs[..imgs.len() - pinned.len()];
 
     let mut errors = calc_errors(
-        &target_img,
-        imgs,
-        n_width,
-        n_height,
-        sub_img_width,
-        sub_img_height,
-        &progress_sender,
-    );
+        target_img,
+        free_imgs,
+        search,
+        grid,
+        &pinned_cells,
+        job,
+        opts,
+    )?;
 
     // reverse sort
     errors.par_sort_by(|e1, e2| e2.partial_cmp(e1).unwrap());
-    let n_images = (n_width * n_height) as usize;
-    let mut filled_imgs = 0;
-
+    let n_images = grid.n_cells();
+    let mut filled_imgs = pinned_cells.len();
+    let stage_progress = progress.stage(Stage::SelectingImages, n_images);
 
     let mut black_list = HashSet::new();
     while let Some(ErrInfo {
@@ -379,101 +784,2271 @@ fn fill_target_img(
         j_pos,
         ..
     }) = errors.pop()
-    
     {
+        cancellation_token.check()?;
+
         let i = i_pos as usize;
         let j = j_pos as usize;
 
-        if black_list.contains(&img_idx) || sub_imgs[i][j].is_some() {
+        if black_list.contains(&img_idx) || sub_imgs[i][j].is_some() || !quotas.allows(img_idx) {
             continue;
         }
 
-        sub_imgs[i][j] = Some(imgs[img_idx]);
+        sub_imgs[i][j] = Some(img_idx);
         black_list.insert(img_idx);
+        quotas.fill(img_idx);
         filled_imgs += 1;
 
-        if let Some(s) = progress_sender {
-            let r = s.send((filled_imgs, n_images, "Selecting images for result"));
-            handle_progress_send_error(r);
-        }
+        stage_progress.set(filled_imgs);
 
         if filled_imgs >= n_images {
-            break
+            break;
         }
+    }
 
+    for (i, row) in sub_imgs.iter_mut().enumerate() {
+        for (j, sub_img) in row.iter_mut().enumerate() {
+            if sub_img.is_some() {
+                continue;
+            }
+
+            cancellation_token.check()?;
+
+            let cell = cell_pixels(
+                target_img,
+                j as u32 * grid.tile_width,
+                i as u32 * grid.tile_height,
+                grid.tile_width,
+                grid.tile_height,
+            );
+
+            // there are at least as many free images as free cells, and enough of every input to
+            // meet its quota
+            let (img_idx, _) = free_imgs
+                .par_iter()
+                .enumerate()
+                .filter(|(idx, _)| !black_list.contains(idx))
+                .filter(|(idx, _)| quotas.allows(*idx))
+                .map(|(idx, im)| (idx, squared_error(&cell, im, opts.f32_scoring)))
+                .min_by(|(_, e1), (_, e2)| e1.partial_cmp(e2).unwrap())
+                .unwrap();
+
+            *sub_img = Some(img_idx);
+            black_list.insert(img_idx);
+            quotas.fill(img_idx);
+            filled_imgs += 1;
+
+            stage_progress.set(filled_imgs);
+        }
     }
 
+    stage_progress.finish();
 
-    let sub_imgs: Vec<Vec<_>> = sub_imgs.into_iter().map(|v| v.into_iter().map(Option::unwrap).collect() ).collect();
-    insert_sub_imgs(&mut result_img, &sub_imgs, progress_sender);
+    let sub_img_idxs = sub_imgs
+        .into_iter()
+        .map(|v| v.into_iter().map(Option::unwrap).collect())
+        .collect();
 
-    Ok(result_img)
+    Ok(sub_img_idxs)
 }
 
 #[derive(Debug, Clone)]
 pub struct MakeImgOfImsOpts {
-    pub target_width: u32,
+    pub target_width: Option<u32>,
+    pub target_height: Option<u32>,
+    pub fit_mode: FitMode,
     pub num_horizontal_imgs: u32,
     pub num_vertical_imgs: u32,
+    /// When set, overrides `num_horizontal_imgs` and `num_vertical_imgs`
+    pub auto_grid: Option<AutoGrid>,
     pub max_imgs: Option<usize>,
+    /// Has no effect, images are never used for more than one cell unless there are fewer images
+    /// than cells
+    #[deprecated(note = "images are never reused regardless of this option")]
     pub no_pop: bool,
+    /// Images which are forced into the cell at `(row, col)`, and not used elsewhere
+    pub pinned_tiles: HashMap<(u32, u32), PathBuf>,
+    /// Glob patterns of the images in the inputs which may be used, all images when empty.
+    /// Matched like `exclude`
+    pub include: Vec<String>,
+    /// Glob patterns of images in the input folder which should never be used, matched against
+    /// the path relative to the input folder, archive or the folder of a manifest. Case doesn't
+    /// matter, `*` doesn't match `/` while `**` matches any number of folders
+    pub exclude: Vec<String>,
+    /// Levels of subfolders of the input folders and archives which are searched for images, all
+    /// when not set. With 0 only the images directly in them are used
+    pub max_depth: Option<usize>,
+    /// Skip files and folders whose name starts with a dot
+    pub skip_hidden: bool,
+    /// Follow symlinks to files and folders in the input folders, otherwise they are skipped
+    pub follow_symlinks: bool,
+    /// Receives progress of the job, a [`ProgressSender`] can be used for a channel of
+    /// `(done, total, description)` tuples
+    pub progress_reporter: Option<Arc<dyn ProgressReporter>>,
+    /// Receives `(done, total, description)` tuples when no `progress_reporter` is set
+    #[deprecated(note = "use `progress_reporter: Some(Arc::new(progress_sender))` instead")]
     pub progress_sender: Option<ProgressSender>,
+    /// Checked while loading and matching images, a cancelled job returns
+    /// [`MosaicError::Cancelled`]
+    pub cancellation_token: CancellationToken,
+    /// Threads used for loading images, defaults to the number of cores
+    pub num_threads: Option<usize>,
+    /// Maximum number of loaded tiles waiting to be added to the [`TileSet`]
+    pub load_queue_size: usize,
+    /// Number of downscaled copies of the tiles which are compared to the cells with
+    /// `shortlist_size`. Their longest sides are 4 pixels, 8 pixels and so on, and they have the
+    /// aspect ratio of the cells
+    pub pyramid_levels: usize,
+    /// When set, cells are first compared to the downscaled tiles, from coarse to fine, and only
+    /// the best `shortlist_size` tiles are scored at full size. Faster, but the best tile may be
+    /// missed
+    pub shortlist_size: Option<usize>,
+    /// Cells are scored against the target and the tiles downscaled such that the longest side of
+    /// a cell is at most this many pixels, so matching takes memory for the downscaled target and
+    /// tiles only. The result is made from the tiles at full size
+    pub match_tile_size: u32,
+    /// Sum the error of a tile in `f32` pixel by pixel, as versions before the integer kernel did.
+    /// Slower, but gives the errors and so the images of those versions, whose rounding can order
+    /// nearly identical tiles differently than the exact sum does
+    pub f32_scoring: bool,
+    /// When set, tiles loaded with [`TileSet::from_dir`] are clustered by colour into this many
+    /// clusters, and only the tiles in the clusters nearest to a cell are scored
+    pub num_clusters: Option<usize>,
+    /// Number of clusters searched for a cell, more are searched when they contain fewer than
+    /// `shortlist_size` tiles
+    pub clusters_searched: usize,
+    /// File in which the clusters are stored, and from which they are reused as long as they
+    /// were computed for the same images and number of clusters
+    pub tile_index: Option<PathBuf>,
+    /// Folder in which the progress of the job is stored: the loaded tiles, the errors of the
+    /// cells scored so far and the tiles picked for every target. The errors take 8 bytes for
+    /// each of the best tiles of a cell, at most as many as there are cells, or about
+    /// `shortlist_size` tiles with a faster search. Without one, a grid of 200x200 cells may
+    /// take up to 12.8 GB
+    pub job_dir: Option<PathBuf>,
+    /// Continue the job in `job_dir` where it stopped instead of starting over. Fails when the
+    /// input images or options changed since the job was started
+    pub resume: bool,
+    /// Use the tiles as they are stored, instead of rotating them as their EXIF orientation asks
+    /// for
+    pub ignore_exif_orientation: bool,
 }
 
+#[allow(deprecated)]
 impl Default for MakeImgOfImsOpts {
     fn default() -> Self {
         Self {
-            target_width: 1000,
+            target_width: Some(1000),
+            target_height: None,
+            fit_mode: FitMode::default(),
             num_horizontal_imgs: 40,
             num_vertical_imgs: 40,
+            auto_grid: None,
             max_imgs: None,
             no_pop: false,
+            pinned_tiles: HashMap::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            max_depth: None,
+            skip_hidden: false,
+            follow_symlinks: true,
+            progress_reporter: None,
             progress_sender: None,
+            cancellation_token: CancellationToken::default(),
+            num_threads: None,
+            load_queue_size: 256,
+            pyramid_levels: pyramid::DEFAULT_LEVELS,
+            shortlist_size: None,
+            match_tile_size: 64,
+            f32_scoring: false,
+            num_clusters: None,
+            clusters_searched: 3,
+            tile_index: None,
+            job_dir: None,
+            resume: false,
+            ignore_exif_orientation: false,
         }
     }
 }
 
+/// A target fitted to a grid, and the tiles it is matched against.
+struct Prepared {
+    /// Dimensions of the resized target
+    target_width: u32,
+    target_height: u32,
+    grid: Grid,
+    /// `grid` with its cells at the size they are scored at
+    match_grid: Grid,
+    /// The part of the target covered by the grid, at the size of `match_grid`
+    target_img: RgbImage,
+    /// The tiles of the tile set, resized to the cells of `match_grid`
+    tiles: Arc<TileStore>,
+    /// The downscaled copies of `tiles`, when the options ask for a shortlist
+    pyramid: Option<Arc<Pyramid>>,
+}
+
+/// The tiles picked for a target, before they are rendered.
+struct Assignment<Id> {
+    plan: MosaicPlan<Id>,
+    /// Index in the tile set of the tile of every cell, row by row
+    tile_idxs: Vec<Vec<usize>>,
+}
+
+/// An image of images together with the plan it was rendered from.
+#[derive(Debug, Clone)]
+pub struct Mosaic<Id> {
+    pub image: DynamicImage,
+    pub plan: MosaicPlan<Id>,
+}
+
+/// A block of cells, `rows` and `cols` are clamped to the grid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CellRegion {
+    pub rows: Range<u32>,
+    pub cols: Range<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candidate<Id> {
+    pub id: Id,
+    /// Mean squared error between the tile and the cell of the target
+    pub error: f32,
+}
+
+/// The best fitting tiles for a single cell, ordered from best to worst.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CellCandidates<Id> {
+    pub row: u32,
+    pub col: u32,
+    pub candidates: Vec<Candidate<Id>>,
+}
+
+impl<Id> Mosaic<Id> {
+    /// Saves the image in the format of the extension of `output_file`, with 8 bits per channel
+    /// like [`MosaicBuilder::build_to_file`] does.
+    pub fn save(&self, output_file: impl AsRef<Path>) -> Result<(), MosaicError> {
+        let output_file = output_file.as_ref();
+        self.image
+            .to_rgb8()
+            .save(output_file)
+            .map_err(|e| MosaicError::image(output_file, e))
+    }
+}
+
+impl MakeImgOfImsOpts {
+    fn progress(&self) -> Progress {
+        #[allow(deprecated)]
+        let legacy = self.progress_sender.clone().map(|sender| {
+            let reporter: Arc<dyn ProgressReporter> = Arc::new(sender);
+            reporter
+        });
+
+        Progress::new(self.progress_reporter.clone().or(legacy))
+    }
+
+    /// The grid which will be used for `target_img` with these options.
+    pub fn grid_for_target(&self, target_img: &DynamicImage) -> Grid {
+        let (target_width, target_height) = target_dimensions(
+            target_img.width(),
+            target_img.height(),
+            self.target_width,
+            self.target_height,
+        );
+        self.grid(target_width, target_height)
+    }
+
+    fn grid(&self, target_width: u32, target_height: u32) -> Grid {
+        match self.auto_grid {
+            Some(auto_grid) => Grid::auto(target_width, target_height, auto_grid, self.fit_mode),
+            None => Grid::new(
+                target_width,
+                target_height,
+                self.num_horizontal_imgs,
+                self.num_vertical_imgs,
+                self.fit_mode,
+            ),
+        }
+    }
+}
+
+fn open_img(path: &Path, exif_orientation: bool) -> Result<DynamicImage, image::ImageError> {
+    let data = source::read_file(path)?;
+    decode_img(path, &data, exif_orientation)
+}
+
+fn decode_img(
+    path: &Path,
+    data: &[u8],
+    exif_orientation: bool,
+) -> Result<DynamicImage, image::ImageError> {
+    let img = load::img_reader(path, data)?.decode()?;
+
+    Ok(if exif_orientation {
+        orientation::apply_exif_orientation(img, data)
+    } else {
+        img
+    })
+}
+
+/// Loads the image at `path`, rotated as its EXIF orientation asks for when `exif_orientation` is
+/// set. `path` may also point into an archive, like the tiles of a [`TileSet`] loaded from one,
+/// which is searched on every call. Use [`render_plan_from_files`] to render a plan of such tiles.
+pub fn load_img(
+    path: impl AsRef<Path>,
+    exif_orientation: bool,
+) -> Result<DynamicImage, MosaicError> {
+    let path = path.as_ref();
+    open_img(path, exif_orientation).map_err(|e| MosaicError::image(path, e))
+}
+
+/// Like [`render_plan_to_file`], for a plan of tiles which are loaded like [`load_img`] does. The
+/// tiles are read in a single pass over every archive they are in, instead of searching the
+/// archive for every tile, and kept encoded until the rows they are used in are rendered. Reports
+/// progress and is cancelled as set in `opts`, tiles are rotated unless
+/// [`ignore_exif_orientation`](MakeImgOfImsOpts::ignore_exif_orientation) is set.
+pub fn render_plan_from_files(
+    plan: &MosaicPlan<PathBuf>,
+    scale: f32,
+    output_file: impl AsRef<Path>,
+    opts: &MakeImgOfImsOpts,
+) -> Result<(), MosaicError> {
+    let progress = opts.progress();
+    let paths: Vec<PathBuf> = plan
+        .placements
+        .iter()
+        .map(|placement| placement.id.clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    let stage_progress = progress.stage(Stage::LoadingImages, paths.len());
+    let read: Mutex<HashMap<usize, io::Result<Vec<u8>>>> = Mutex::new(HashMap::new());
+    source::read_files(&paths, &opts.cancellation_token, &|idx, data| {
+        read.lock().unwrap().insert(idx, data);
+        stage_progress.inc();
+    })?;
+    opts.cancellation_token.check()?;
+    stage_progress.finish();
+
+    let mut read = read.into_inner().unwrap();
+    let files = paths
+        .iter()
+        .enumerate()
+        .map(|(idx, path)| {
+            let data = read.remove(&idx).unwrap_or_else(|| {
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{path:?} not found"),
+                ))
+            });
+            data.map(|data| (path, data))
+                .map_err(|e| MosaicError::io(path, e))
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    let exif_orientation = !opts.ignore_exif_orientation;
+    plan::write_plan(
+        plan,
+        scale,
+        output_file.as_ref(),
+        &|path: &PathBuf| {
+            decode_img(path, &files[path], exif_orientation)
+                .map_err(|e| MosaicError::image(path, e))
+        },
+        &progress,
+        &opts.cancellation_token,
+    )
+}
+
+/// Like [`load_img`], but failures are reported as [`MosaicError::UnreadableTarget`].
+pub fn load_target_img(
+    path: impl AsRef<Path>,
+    exif_orientation: bool,
+) -> Result<DynamicImage, MosaicError> {
+    let path = path.as_ref();
+    open_img(path, exif_orientation).map_err(|source| match source {
+        image::ImageError::Unsupported(source) => MosaicError::UnsupportedFormat {
+            path: path.to_owned(),
+            source,
+        },
+        source => MosaicError::UnreadableTarget {
+            path: path.to_owned(),
+            source,
+        },
+    })
+}
+
+/// Scales `img` down such that its shortest side is `size`, images which are already smaller are
+/// kept as is.
+fn scale_to_cover(img: DynamicImage, size: u32) -> RgbImage {
+    let img = img.into_rgb8();
+    let (w, h) = img.dimensions();
+    let scale = size as f32 / w.min(h) as f32;
+
+    if scale >= 1.0 {
+        return img;
+    }
+
+    image::imageops::resize(
+        &img,
+        ((w as f32 * scale).ceil() as u32).max(size),
+        ((h as f32 * scale).ceil() as u32).max(size),
+        image::imageops::FilterType::Triangle,
+    )
+}
+
+/// A library of tiles which can be used for multiple images of images. Tiles are stored such
+/// that their shortest side is at most `max_tile_size`, and resized to the size cells of a grid
+/// are scored at on demand.
+#[derive(Debug)]
+pub struct TileSet<Id> {
+    ids: Vec<Id>,
+    /// Source path of every tile, if it was loaded from disk
+    paths: Vec<Option<PathBuf>>,
+    /// The inputs the tiles were loaded from
+    inputs: Vec<TileInput>,
+    /// Index in `inputs` of every tile, `None` for pinned tiles outside of the inputs
+    tile_inputs: Vec<Option<usize>>,
+    /// Metadata of every tile, e.g. from a [`ManifestSource`]
+    metadata: Vec<TileMetadata>,
+    imgs: TileStore,
+    clusters: Option<ClusterModel>,
+    max_tile_size: u32,
+    resized: Mutex<ResizedTiles>,
+    pyramids: Mutex<Pyramids>,
+}
+
+impl<Id> TileSet<Id> {
+    /// Creates a tile set from images which are already in memory.
+    pub fn from_images(
+        tiles: impl IntoIterator<Item = (Id, DynamicImage)>,
+        max_tile_size: u32,
+    ) -> Self {
+        let mut ids = Vec::new();
+        let imgs: TileStore = tiles
+            .into_iter()
+            .map(|(id, img)| {
+                ids.push(id);
+                scale_to_cover(img, max_tile_size)
+            })
+            .collect();
+
+        Self::from_scaled(ids, imgs, max_tile_size)
+    }
+
+    fn from_scaled(ids: Vec<Id>, imgs: TileStore, max_tile_size: u32) -> Self {
+        Self {
+            paths: vec![None; ids.len()],
+            inputs: Vec::new(),
+            tile_inputs: vec![None; ids.len()],
+            metadata: vec![TileMetadata::new(); ids.len()],
+            ids,
+            imgs,
+            clusters: None,
+            max_tile_size,
+            resized: Mutex::new(HashMap::new()),
+            pyramids: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn ids(&self) -> &[Id] {
+        &self.ids
+    }
+
+    /// Metadata of every tile, in the order of [`TileSet::ids`].
+    pub fn metadata(&self) -> &[TileMetadata] {
+        &self.metadata
+    }
+
+    pub fn max_tile_size(&self) -> u32 {
+        self.max_tile_size
+    }
+
+    /// Bytes used by the pixels of the tiles, including their downscaled copies and the tiles which
+    /// were resized to score the cells of grids.
+    pub fn memory_usage(&self) -> usize {
+        let resized = self.resized.lock().unwrap();
+        let pyramids = self.pyramids.lock().unwrap();
+        self.imgs.memory_usage()
+            + resized.values().map(|t| t.memory_usage()).sum::<usize>()
+            + pyramids.values().map(|p| p.memory_usage()).sum::<usize>()
+    }
+
+    pub fn clusters(&self) -> Option<&ClusterModel> {
+        self.clusters.as_ref()
+    }
+
+    /// Clusters the tiles by colour into at most `num_clusters` clusters, after which only the
+    /// tiles in the clusters nearest to a cell are scored.
+    pub fn cluster(&mut self, num_clusters: usize) {
+        self.clusters = Some(ClusterModel::new(&self.imgs, num_clusters));
+    }
+
+    /// The clusters of the tiles, which can be stored and applied to the same tiles later.
+    pub fn index(&self) -> Option<TileIndex<Id>>
+    where
+        Id: Clone,
+    {
+        Some(TileIndex {
+            tiles: self.ids.clone(),
+            clusters: self.clusters.clone()?,
+        })
+    }
+
+    /// Uses the clusters of `index`, if it was computed for exactly these tiles. Returns whether
+    /// the index was applied.
+    pub fn apply_index(&mut self, index: TileIndex<Id>) -> bool
+    where
+        Id: PartialEq,
+    {
+        if !index.matches(&self.ids) {
+            return false;
+        }
+
+        self.clusters = Some(index.clusters);
+        true
+    }
+
+    /// Resolves the pinned tiles of `opts` to `(row, col, tile_idx)`.
+    fn pinned_tiles(
+        &self,
+        grid: Grid,
+        opts: &MakeImgOfImsOpts,
+    ) -> Result<Vec<(u32, u32, usize)>, MosaicError> {
+        if opts.pinned_tiles.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // the first tile of every path, like a linear search would find
+        let mut tile_idxs = HashMap::new();
+        for (idx, path) in self.paths.iter().enumerate() {
+            if let Some(path) = path {
+                tile_idxs.entry(canonical(path)).or_insert(idx);
+            }
+        }
+
+        opts.pinned_tiles
+            .iter()
+            .map(|(&(row, col), path)| {
+                if let Some(problem) = pinned_cell_problem(grid, row, col) {
+                    return Err(problem.into());
+                }
+
+                let tile_idx = *tile_idxs.get(&canonical(path)).ok_or_else(|| {
+                    MosaicError::invalid_option(
+                        "pinned_tiles",
+                        format!("Pinned image {path:?} is not part of the loaded images"),
+                        "Check that the image can be loaded",
+                    )
+                })?;
+
+                Ok((row, col, tile_idx))
+            })
+            .collect()
+    }
+
+    fn resized(
+        &self,
+        width: u32,
+        height: u32,
+        progress: &Progress,
+        cancellation_token: &CancellationToken,
+    ) -> Result<Arc<TileStore>, Cancelled> {
+        let mut resized = self.resized.lock().unwrap();
+
+        if let Some(imgs) = resized.get(&(width, height)) {
+            return Ok(imgs.clone());
+        }
+
+        let stage_progress = progress.stage(Stage::ResizingImages, self.imgs.len());
+        let sources = &self.imgs;
+        let imgs = (0..sources.len())
+            .into_par_iter()
+            .map(|idx| {
+                cancellation_token.check()?;
+                let img = resize_img(&sources.get(idx), width, height);
+                stage_progress.inc();
+                Ok(img)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        stage_progress.finish();
+
+        let imgs = Arc::new(imgs.into_iter().collect::<TileStore>());
+        resized.insert((width, height), imgs.clone());
+
+        Ok(imgs)
+    }
+
+    /// The `n_levels` downscaled copies of `tiles`, which were [`resized`](TileSet::resized) to
+    /// cells of `width` x `height` pixels.
+    fn pyramid(&self, tiles: &TileStore, width: u32, height: u32, n_levels: usize) -> Arc<Pyramid> {
+        self.pyramids
+            .lock()
+            .unwrap()
+            .entry((width, height, n_levels))
+            .or_insert_with(|| Arc::new(Pyramid::from_tiles(tiles, n_levels)))
+            .clone()
+    }
+}
+
+impl TileSet<PathBuf> {
+    /// Loads the images in `input_dir`, which is a folder or an archive, see [`open_source`].
+    pub fn from_dir(
+        input_dir: impl AsRef<Path>,
+        max_tile_size: u32,
+        opts: &MakeImgOfImsOpts,
+    ) -> Result<Self, MosaicError> {
+        Self::from_inputs(&[TileInput::new(input_dir.as_ref())], max_tile_size, opts)
+    }
+
+    /// Loads the images in all `inputs`, whose quotas are respected when the tiles are matched.
+    /// An image which is in multiple inputs is loaded once, and belongs to the first of them.
+    pub fn from_inputs(
+        inputs: &[TileInput],
+        max_tile_size: u32,
+        opts: &MakeImgOfImsOpts,
+    ) -> Result<Self, MosaicError> {
+        let sources = inputs
+            .iter()
+            .map(|input| open_source(&input.path, opts))
+            .collect::<Result<Vec<_>, _>>()?;
+        let found = sources
+            .iter()
+            .map(|source| find_all_imgs(source.as_ref(), opts))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::from_found(
+            FoundInputs {
+                inputs: inputs.to_vec(),
+                sources,
+                found,
+            },
+            max_tile_size,
+            opts,
+        )
+    }
+
+    /// Like [`TileSet::from_inputs`], but loads the images which [`MakeImgOfImsOpts::validate`]
+    /// found, without searching the inputs again. `opts` should be the options they were found
+    /// with.
+    pub fn from_found(
+        found: FoundInputs,
+        max_tile_size: u32,
+        opts: &MakeImgOfImsOpts,
+    ) -> Result<Self, MosaicError> {
+        let FoundInputs {
+            inputs,
+            sources,
+            found,
+        } = found;
+        let sources: Vec<&dyn TileSource> = sources.iter().map(AsRef::as_ref).collect();
+
+        Self::from_sources(&sources, inputs, found, max_tile_size, opts)
+    }
+
+    /// Loads the images in `source`, see [`TileSet::from_inputs`].
+    pub fn from_source(
+        source: &dyn TileSource,
+        max_tile_size: u32,
+        opts: &MakeImgOfImsOpts,
+    ) -> Result<Self, MosaicError> {
+        let inputs = vec![TileInput::new(source.location())];
+        let found = find_all_imgs(source, opts)?;
+        Self::from_sources(&[source], inputs, vec![found], max_tile_size, opts)
+    }
+
+    /// Loads the images `found` in `sources`, of which `inputs` are the descriptions, respecting
+    /// `max_imgs`, `pinned_tiles`, `num_clusters` and `tile_index` of `opts`.
+    /// The images are found with the other options, see [`find_all_imgs`]. With a `job_dir`, the
+    /// loaded tiles are stored in it, and reused from there when resuming.
+    fn from_sources(
+        sources: &[&dyn TileSource],
+        inputs: Vec<TileInput>,
+        found: Vec<FoundImgs>,
+        max_tile_size: u32,
+        opts: &MakeImgOfImsOpts,
+    ) -> Result<Self, MosaicError> {
+        let mut all_paths = Vec::new();
+        // input and metadata of every image
+        let mut found_imgs = HashMap::new();
+
+        for (input, mut found) in found.into_iter().enumerate() {
+            found.report_skipped(&opts.progress());
+
+            for path in found.imgs {
+                if !found_imgs.contains_key(&path) {
+                    let metadata = found.metadata.remove(&path).unwrap_or_default();
+                    found_imgs.insert(path.clone(), (input, metadata));
+                    all_paths.push(path);
+                }
+            }
+        }
+
+        let job = Job::from_opts(opts);
+        if let Some(job) = &job {
+            let mut pinned: Vec<PathBuf> = opts.pinned_tiles.values().cloned().collect();
+            pinned.sort();
+
+            let locations: Vec<&Path> = sources.iter().map(|source| source.location()).collect();
+            let paths: Vec<PathBuf> = all_paths.iter().cloned().chain(pinned).collect();
+            job.start(JobInfo {
+                inputs: job::inputs_fingerprint(&locations, &paths),
+                options: job::fingerprint((job::options_fingerprint(opts), max_tile_size)),
+            })?;
+
+            if let Some(mut tile_set) = job.load_tiles()? {
+                tile_set.describe(inputs, &found_imgs);
+                return Ok(tile_set);
+            }
+        }
+
+        let img_paths = pick_imgs(all_paths, opts);
+
+        let (paths, imgs) = load::load_tiles(sources, img_paths, max_tile_size, opts)?;
+
+        let mut tile_set = Self::from_scaled(paths, imgs, max_tile_size);
+        tile_set.paths = tile_set.ids.iter().cloned().map(Some).collect();
+        tile_set.describe(inputs, &found_imgs);
+
+        if tile_set.is_empty() {
+            return Err(MosaicError::EmptyLibrary {
+                dir: match sources {
+                    [source] => Some(source.location().to_owned()),
+                    _ => None,
+                },
+            });
+        }
+
+        if let Some(num_clusters) = opts.num_clusters {
+            tile_set.load_or_cluster(num_clusters, opts)?;
+        }
+
+        if let Some(job) = &job {
+            job.save_tiles(&tile_set)?;
+        }
+
+        Ok(tile_set)
+    }
+
+    /// Sets the inputs of the tile set, and the input and metadata of every tile from
+    /// `found_imgs`, which has them by path.
+    fn describe(
+        &mut self,
+        inputs: Vec<TileInput>,
+        found_imgs: &HashMap<PathBuf, (usize, TileMetadata)>,
+    ) {
+        (self.tile_inputs, self.metadata) = self
+            .ids
+            .iter()
+            .map(|path| match found_imgs.get(path) {
+                Some((input, metadata)) => (Some(*input), metadata.clone()),
+                None => (None, TileMetadata::new()),
+            })
+            .unzip();
+        self.inputs = inputs;
+    }
+
+    /// Reuses the clusters in `opts.tile_index` when they fit, otherwise clusters the tiles and
+    /// stores the clusters there.
+    fn load_or_cluster(
+        &mut self,
+        num_clusters: usize,
+        opts: &MakeImgOfImsOpts,
+    ) -> Result<(), MosaicError> {
+        let path = match &opts.tile_index {
+            Some(path) => path,
+            None => {
+                self.cluster(num_clusters);
+                return Ok(());
+            }
+        };
+
+        if path.is_file() {
+            match TileIndex::load(path) {
+                Ok(index) if index.clusters.num_clusters() == num_clusters.min(self.len()) => {
+                    if self.apply_index(index) {
+                        return Ok(());
+                    }
+                    log::info!("Tile index {path:?} belongs to other images, clustering again");
+                }
+                Ok(_) => log::info!("Tile index {path:?} has a different number of clusters"),
+                Err(e) => {
+                    log::warn!("{e}");
+                    opts.progress()
+                        .warn(format!("Ignoring invalid tile index {path:?}"));
+                }
+            }
+        }
+
+        self.cluster(num_clusters);
+        self.index().expect("the tiles were clustered").save(path)
+    }
+}
+
+/// Creates images of images for possibly many targets from a single [`TileSet`].
+pub struct MosaicBuilder<'a, Id> {
+    tile_set: &'a TileSet<Id>,
+    opts: MakeImgOfImsOpts,
+}
+
+impl<'a, Id: Clone> MosaicBuilder<'a, Id> {
+    pub fn new(tile_set: &'a TileSet<Id>, opts: MakeImgOfImsOpts) -> Self {
+        Self { tile_set, opts }
+    }
+
+    pub fn opts(&self) -> &MakeImgOfImsOpts {
+        &self.opts
+    }
+
+    /// Search for the images worth scoring among the tiles `img_idxs`, which finds at least
+    /// `min_size` images per cell. `None` when all images should be scored, which is the case
+    /// unless the tile set is clustered or there is a `pyramid` for coarse-to-fine matching.
+    fn candidate_search<'b>(
+        &self,
+        pyramid: Option<&'b Pyramid>,
+        img_idxs: &[usize],
+        min_size: usize,
+    ) -> Option<CandidateSearch<'b>>
+    where
+        'a: 'b,
+    {
+        let opts = &self.opts;
+        let tile_set = self.tile_set;
+
+        let shortlist = pyramid.zip(opts.shortlist_size).map(|(pyramid, size)| {
+            Shortlist::new(pyramid, img_idxs, pyramid.n_levels(), size.max(min_size))
+        });
+
+        let clusters = tile_set.clusters.as_ref().map(|model| {
+            let min_candidates = opts.shortlist_size.unwrap_or(0).max(min_size);
+            ClusterSearch::new(model, img_idxs, opts.clusters_searched, min_candidates)
+        });
+
+        if shortlist.is_none() && clusters.is_none() {
+            return None;
+        }
+
+        Some(CandidateSearch {
+            n_imgs: img_idxs.len(),
+            clusters,
+            shortlist,
+        })
+    }
+
+    /// Checks the options against `target_img` and that there are tiles, then fits `target_img`
+    /// to the grid of the options, and resizes it and the tiles to the cells at the size they
+    /// are scored at.
+    fn prepare(&self, target_img: &DynamicImage) -> Result<Prepared, MosaicError> {
+        let opts = &self.opts;
+
+        let problems = opts.problems(target_img);
+        if !problems.is_empty() {
+            return Err(MosaicError::InvalidOptions { problems });
+        }
+
+        if self.tile_set.is_empty() {
+            return Err(MosaicError::EmptyLibrary { dir: None });
+        }
+
+        let (target_width, target_height) = target_dimensions(
+            target_img.width(),
+            target_img.height(),
+            opts.target_width,
+            opts.target_height,
+        );
+        let grid = opts.grid(target_width, target_height);
+        let match_grid = grid.downscaled(opts.match_tile_size);
+
+        let tiles = self.tile_set.resized(
+            match_grid.tile_width,
+            match_grid.tile_height,
+            &opts.progress(),
+            &opts.cancellation_token,
+        )?;
+        let pyramid = (opts.shortlist_size.is_some() && opts.pyramid_levels > 0).then(|| {
+            self.tile_set.pyramid(
+                &tiles,
+                match_grid.tile_width,
+                match_grid.tile_height,
+                opts.pyramid_levels,
+            )
+        });
+
+        Ok(Prepared {
+            target_width,
+            target_height,
+            grid,
+            match_grid,
+            target_img: match_target(
+                target_img,
+                (target_width, target_height),
+                grid,
+                match_grid,
+                opts.fit_mode,
+            ),
+            tiles,
+            pyramid,
+        })
+    }
+
+    /// Fraction of the cells of `target_img` whose best tile, found by scoring all tiles, is among
+    /// the candidates of the faster search used with these options, see
+    /// [`MakeImgOfImsOpts::num_clusters`] and [`MakeImgOfImsOpts::shortlist_size`]. This is 1
+    /// when all tiles are scored.
+    pub fn search_recall(&self, target_img: &DynamicImage) -> Result<f32, MosaicError> {
+        let Prepared {
+            match_grid: grid,
+            target_img,
+            tiles: imgs,
+            pyramid,
+            ..
+        } = self.prepare(target_img)?;
+
+        let tiles: Vec<TileView> = (0..imgs.len()).map(|idx| imgs.get(idx)).collect();
+        let all_idxs: Vec<usize> = (0..imgs.len()).collect();
+        let search = match self.candidate_search(pyramid.as_deref(), &all_idxs, 1) {
+            Some(search) => search,
+            None => return Ok(1.0),
+        };
+        let f32_scoring = self.opts.f32_scoring;
+
+        let mut n_found = 0;
+        for row in 0..grid.n_vertical {
+            for col in 0..grid.n_horizontal {
+                self.opts.cancellation_token.check()?;
+
+                let cell = cell_pixels(
+                    &target_img,
+                    col * grid.tile_width,
+                    row * grid.tile_height,
+                    grid.tile_width,
+                    grid.tile_height,
+                );
+
+                // `prepare` makes sure that there are tiles
+                let (best, _) = tiles
+                    .par_iter()
+                    .map(|im| squared_error(&cell, im, f32_scoring))
+                    .enumerate()
+                    .min_by(|(_, e1), (_, e2)| e1.partial_cmp(e2).unwrap())
+                    .expect("the tile set is not empty");
+
+                let candidates = search.candidates(&cell, grid.tile_width, grid.tile_height);
+                if candidates.contains(&best) {
+                    n_found += 1;
+                }
+            }
+        }
+
+        Ok(n_found as f32 / grid.n_cells() as f32)
+    }
+
+    /// The `k` tiles with the lowest error for every cell of `region`, or of the whole grid when
+    /// no region is given. Pinned tiles are not taken into account.
+    pub fn top_candidates(
+        &self,
+        target_img: &DynamicImage,
+        k: usize,
+        region: Option<CellRegion>,
+    ) -> Result<Vec<CellCandidates<Id>>, MosaicError> {
+        let opts = &self.opts;
+        let Prepared {
+            match_grid: grid,
+            target_img,
+            tiles: imgs,
+            pyramid,
+            ..
+        } = self.prepare(target_img)?;
+
+        let region = region.unwrap_or(CellRegion {
+            rows: 0..grid.n_vertical,
+            cols: 0..grid.n_horizontal,
+        });
+        let rows = region.rows.start..region.rows.end.min(grid.n_vertical);
+        let cols = region.cols.start..region.cols.end.min(grid.n_horizontal);
+
+        let tiles: Vec<TileView> = (0..imgs.len()).map(|idx| imgs.get(idx)).collect();
+        let all_idxs: Vec<usize> = (0..imgs.len()).collect();
+        let search = self.candidate_search(pyramid.as_deref(), &all_idxs, k);
+
+        rows.flat_map(|row| cols.clone().map(move |col| (row, col)))
+            .map(|(row, col)| {
+                opts.cancellation_token.check()?;
+
+                let cell = cell_pixels(
+                    &target_img,
+                    col * grid.tile_width,
+                    row * grid.tile_height,
+                    grid.tile_width,
+                    grid.tile_height,
+                );
+
+                let mut errors: Vec<(usize, f32)> = match &search {
+                    Some(search) => search
+                        .candidates(&cell, grid.tile_width, grid.tile_height)
+                        .into_par_iter()
+                        .map(|idx| (idx, squared_error(&cell, &tiles[idx], opts.f32_scoring)))
+                        .collect(),
+                    None => tiles
+                        .par_iter()
+                        .map(|im| squared_error(&cell, im, opts.f32_scoring))
+                        .enumerate()
+                        .collect(),
+                };
+
+                errors.sort_by(|(_, e1), (_, e2)| e1.partial_cmp(e2).unwrap());
+
+                Ok(CellCandidates {
+                    row,
+                    col,
+                    candidates: errors
+                        .into_iter()
+                        .take(k)
+                        .map(|(idx, error)| Candidate {
+                            id: self.tile_set.ids[idx].clone(),
+                            error,
+                        })
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn build(&self, target_img: &DynamicImage) -> Result<Mosaic<Id>, MosaicError> {
+        let opts = &self.opts;
+        let Assignment { plan, tile_idxs } = self.assign(target_img)?;
+
+        let result = band::render(
+            plan.frame(),
+            &mut self.row_tiles(plan.grid, &tile_idxs),
+            &opts.progress(),
+            &opts.cancellation_token,
+        )?;
+
+        Ok(Mosaic {
+            image: DynamicImage::ImageRgb8(result),
+            plan,
+        })
+    }
+
+    /// Like [`MosaicBuilder::build`], but writes the image of images to `output_file` instead of
+    /// returning it. PNG and TIFF files are written one band of rows at a time, and only the
+    /// tiles of a single row of the grid are resized to the cells at a time, such that results
+    /// far larger than the available memory can be made. Other formats, and results with
+    /// [`FitMode::Stretch`] which are resampled as a whole, are created in memory, which is
+    /// reported as a warning. The image has 8 bits per channel, like [`Mosaic::save`] writes.
+    pub fn build_to_file(
+        &self,
+        target_img: &DynamicImage,
+        output_file: impl AsRef<Path>,
+    ) -> Result<MosaicPlan<Id>, MosaicError> {
+        let opts = &self.opts;
+        let Assignment { plan, tile_idxs } = self.assign(target_img)?;
+
+        band::render_to_file(
+            output_file.as_ref(),
+            plan.frame(),
+            &mut self.row_tiles(plan.grid, &tile_idxs),
+            &opts.progress(),
+            &opts.cancellation_token,
+        )?;
+
+        Ok(plan)
+    }
+
+    /// The tiles `tile_idxs` of a row of `grid`, resized to its cells.
+    fn row_tiles<'b>(
+        &'b self,
+        grid: Grid,
+        tile_idxs: &'b [Vec<usize>],
+    ) -> impl FnMut(u32) -> Result<Vec<Option<RgbImage>>, MosaicError> + 'b {
+        let imgs = &self.tile_set.imgs;
+
+        move |row| {
+            Ok(tile_idxs[row as usize]
+                .par_iter()
+                .map(|&idx| {
+                    Some(resize_img(
+                        &imgs.get(idx),
+                        grid.tile_width,
+                        grid.tile_height,
+                    ))
+                })
+                .collect())
+        }
+    }
+
+    fn assign(&self, target_img: &DynamicImage) -> Result<Assignment<Id>, MosaicError> {
+        let opts = &self.opts;
+        let Prepared {
+            target_width,
+            target_height,
+            grid,
+            match_grid,
+            target_img,
+            tiles: imgs,
+            pyramid,
+        } = self.prepare(target_img)?;
+
+        let pinned = self.tile_set.pinned_tiles(grid, opts)?;
+        let pinned_tile_idxs: HashSet<usize> = pinned.iter().map(|&(_, _, idx)| idx).collect();
+        let n_free_cells = grid.n_cells() - pinned.len();
+
+        let mut img_idxs: Vec<usize> = (0..imgs.len())
+            .filter(|idx| !pinned_tile_idxs.contains(idx))
+            .collect();
+
+        while !img_idxs.is_empty() && img_idxs.len() < n_free_cells {
+            img_idxs.extend_from_slice(&img_idxs.clone());
+        }
+
+        // pinned images go at the end, as expected by `select_imgs`
+        let n_pool = img_idxs.len();
+        img_idxs.extend(pinned.iter().map(|&(_, _, idx)| idx));
+        let pinned: Vec<_> = pinned
+            .iter()
+            .enumerate()
+            .map(|(k, &(row, col, _))| (row, col, n_pool + k))
+            .collect();
+
+        let img_refs: Vec<TileView> = img_idxs.iter().map(|&idx| imgs.get(idx)).collect();
+        let search = self.candidate_search(pyramid.as_deref(), &img_idxs[..n_pool], 1);
+
+        let img_inputs: Vec<Option<usize>> = img_idxs[..n_pool]
+            .iter()
+            .map(|&idx| self.tile_set.tile_inputs[idx])
+            .collect();
+        let needed_cells = quota::needed_cells(
+            &self.tile_set.inputs,
+            &img_inputs,
+            n_free_cells,
+            &opts.progress(),
+        );
+
+        let target_job = match Job::from_opts(opts) {
+            Some(job) => Some(job.target(job::fingerprint((
+                &self.tile_set.imgs,
+                (&self.tile_set.tile_inputs, &needed_cells),
+                job::options_fingerprint(opts),
+                target_img.as_raw(),
+            )))?),
+            None => None,
+        };
+
+        let saved = match &target_job {
+            Some(job) => job.load_assignment(grid.n_vertical, grid.n_horizontal, img_refs.len())?,
+            None => None,
+        };
+
+        let sub_img_idxs = match saved {
+            Some(sub_img_idxs) => sub_img_idxs,
+            None => {
+                let sub_img_idxs = select_imgs(
+                    &target_img,
+                    &img_refs,
+                    search.as_ref(),
+                    &pinned,
+                    Quotas::new(img_inputs, needed_cells, n_free_cells),
+                    match_grid,
+                    target_job.as_ref(),
+                    opts,
+                )?;
+
+                if let Some(job) = &target_job {
+                    job.save_assignment(&sub_img_idxs)?;
+                }
+                sub_img_idxs
+            }
+        };
+
+        let placements = sub_img_idxs
+            .iter()
+            .enumerate()
+            .flat_map(|(row, cols)| {
+                cols.iter()
+                    .enumerate()
+                    .map(move |(col, &idx)| (row as u32, col as u32, idx))
+            })
+            .map(|(row, col, idx)| {
+                let tile_idx = img_idxs[idx];
+                let (src_width, src_height) = self.tile_set.imgs.dimensions(tile_idx);
+
+                TilePlacement {
+                    row,
+                    col,
+                    id: self.tile_set.ids[tile_idx].clone(),
+                    metadata: self.tile_set.metadata[tile_idx].clone(),
+                    transform: Transform::Identity,
+                    crop: CropWindow::centered(
+                        src_width,
+                        src_height,
+                        grid.tile_width,
+                        grid.tile_height,
+                    ),
+                    error: squared_error(
+                        &cell_pixels(
+                            &target_img,
+                            col * match_grid.tile_width,
+                            row * match_grid.tile_height,
+                            match_grid.tile_width,
+                            match_grid.tile_height,
+                        ),
+                        &img_refs[idx],
+                        opts.f32_scoring,
+                    ),
+                }
+            })
+            .collect();
+
+        let tile_idxs = sub_img_idxs
+            .into_iter()
+            .map(|row| row.into_iter().map(|idx| img_idxs[idx]).collect())
+            .collect();
+
+        Ok(Assignment {
+            plan: MosaicPlan {
+                grid,
+                fit_mode: opts.fit_mode,
+                target_width,
+                target_height,
+                placements,
+            },
+            tile_idxs,
+        })
+    }
+}
+
+/// Creates an image of images from images which are already in memory, tiles are identified by
+/// the caller defined `Id`.
+pub fn make_mosaic<Id: Clone>(
+    target_img: &DynamicImage,
+    tiles: impl IntoIterator<Item = (Id, DynamicImage)>,
+    opts: &MakeImgOfImsOpts,
+) -> Result<Mosaic<Id>, MosaicError> {
+    let grid = opts.grid_for_target(target_img);
+    let tile_set = TileSet::from_images(tiles, grid.tile_width.max(grid.tile_height));
+
+    MosaicBuilder::new(&tile_set, opts.clone()).build(target_img)
+}
+
+/// Creates an image of images of `target_im_path` from the images in `inputs`, and writes it to
+/// `output_file`.
 pub fn make_img_of_images(
     target_im_path: impl AsRef<Path>,
-    input_dir: impl AsRef<Path>,
+    inputs: &[TileInput],
     output_file: impl AsRef<Path>,
     opts: MakeImgOfImsOpts,
-) -> anyhow::Result<()> {
-    let output_file = output_file.as_ref();
-    let target_img = load_and_resize_target_img(target_im_path, opts.target_width)?;
-
-    // let tgt_img_conf: ImageBuffer<Rgba<u16>, Vec<u16>> = target_img.convert();
-    // tgt_img_conf.save(opt.output_dir.join("preprocessed_target_image.png"))?;
-
-    let img_width = target_img.width() / opts.num_horizontal_imgs;
-    let img_height = target_img.height() / opts.num_vertical_imgs;
-
-    let imgs = load_imgs_from_dir(
-        input_dir,
-        img_width,
-        img_height,
-        opts.max_imgs,
-        &opts.progress_sender,
-    )?;
+) -> Result<Grid, MosaicError> {
+    let target_img = load_target_img(target_im_path, !opts.ignore_exif_orientation)?;
+    let found = opts.validate(std::slice::from_ref(&target_img), inputs)?;
+    let grid = opts.grid_for_target(&target_img);
+
+    let tile_set = TileSet::from_found(found, grid.tile_width.max(grid.tile_height), &opts)?;
+
+    let plan = MosaicBuilder::new(&tile_set, opts).build_to_file(&target_img, output_file)?;
+
+    Ok(plan.grid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{grey, solid, write_solid_png_zip, write_solid_pngs};
 
-    let mut img_refs: Vec<&Image> = imgs.iter().collect();
-    
-    while img_refs.len() < img_width as usize * img_height as usize {
-        img_refs.extend_from_slice(&img_refs.clone());
+    fn tile_set(n_tiles: u8) -> TileSet<u8> {
+        TileSet::from_images((0..n_tiles).map(|i| (i, solid(8, 8, [i * 10; 3]))), 8)
     }
 
+    fn grid_opts(num_horizontal_imgs: u32, num_vertical_imgs: u32) -> MakeImgOfImsOpts {
+        MakeImgOfImsOpts {
+            target_width: Some(40),
+            num_horizontal_imgs,
+            num_vertical_imgs,
+            ..Default::default()
+        }
+    }
 
-    let result = fill_target_img(
-        target_img,
-        &mut img_refs,
-        img_width,
-        img_height,
-        !opts.no_pop,
-        &opts.progress_sender,
-    )?;
+    /// A target of `colours` in a 2x2 grid of `size` x `size` cells.
+    fn quadrants(size: u32, colours: [[u8; 3]; 4]) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(size * 2, size * 2, |x, y| {
+            Rgb(colours[(y / size * 2 + x / size) as usize])
+        }))
+    }
+
+    #[test]
+    fn make_mosaic_places_best_tiles() {
+        let colours = [[250, 0, 0], [0, 250, 0], [0, 0, 250], [250, 250, 0]];
+        let tiles = ["red", "green", "blue", "yellow"]
+            .into_iter()
+            .zip(colours)
+            .map(|(name, colour)| (name, solid(16, 16, colour)))
+            .rev();
+        let opts = MakeImgOfImsOpts {
+            target_width: Some(40),
+            ..grid_opts(2, 2)
+        };
+
+        let mosaic = make_mosaic(&quadrants(20, colours), tiles, &opts).unwrap();
+
+        let placements: Vec<_> = mosaic
+            .plan
+            .placements
+            .iter()
+            .map(|p| (p.row, p.col, p.id))
+            .collect();
+        assert_eq!(
+            placements,
+            vec![
+                (0, 0, "red"),
+                (0, 1, "green"),
+                (1, 0, "blue"),
+                (1, 1, "yellow")
+            ]
+        );
+        assert!(mosaic.plan.placements.iter().all(|p| p.error == 0.0));
+        assert_eq!(mosaic.image.to_rgb8(), quadrants(20, colours).to_rgb8());
+    }
 
-    let result: ImageBuffer<Rgba<u16>, Vec<u16>> = result.convert();
-    result.save(output_file)?;
+    #[test]
+    fn tile_set_is_reused_across_builders() {
+        let colours = [[250, 0, 0], [0, 250, 0], [0, 0, 250], [250, 250, 0]];
+        let tile_set = TileSet::from_images(
+            colours
+                .into_iter()
+                .enumerate()
+                .map(|(i, c)| (i, solid(32, 32, c))),
+            32,
+        );
+        let opts = |target_width| MakeImgOfImsOpts {
+            target_width: Some(target_width),
+            ..grid_opts(2, 2)
+        };
+
+        let small = MosaicBuilder::new(&tile_set, opts(40))
+            .build(&quadrants(20, colours))
+            .unwrap();
+        let memory_usage = tile_set.memory_usage();
+
+        // the tiles are resized to the cells of every grid once
+        let builder = MosaicBuilder::new(&tile_set, opts(80));
+        let large = builder.build(&quadrants(40, colours)).unwrap();
+        assert!(tile_set.memory_usage() > memory_usage);
+        let memory_usage = tile_set.memory_usage();
+        let again = builder.build(&quadrants(40, colours)).unwrap();
+        assert_eq!(tile_set.memory_usage(), memory_usage);
+
+        assert_eq!(small.image.dimensions(), (40, 40));
+        assert_eq!(large.image.dimensions(), (80, 80));
+        assert_eq!(again.plan, large.plan);
+        for mosaic in [small, large] {
+            let ids: Vec<usize> = mosaic.plan.placements.iter().map(|p| p.id).collect();
+            assert_eq!(ids, vec![0, 1, 2, 3]);
+        }
+    }
+
+    /// Builds a 2x2 mosaic of a white target from 8 greys in `dir`, of which `0.png`, the
+    /// darkest, is pinned to `cell` through a path with a `..` component.
+    fn build_with_pinned_tile(
+        dir: &Path,
+        cell: (u32, u32),
+        exclude: &[&str],
+    ) -> Result<(Mosaic<PathBuf>, PathBuf), MosaicError> {
+        let imgs_dir = dir.join("imgs");
+        let paths = write_solid_pngs(&imgs_dir, 8, 8);
+        let pinned = imgs_dir.join("..").join("imgs").join("0.png");
+
+        let opts = MakeImgOfImsOpts {
+            pinned_tiles: HashMap::from([(cell, pinned)]),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+            ..grid_opts(2, 2)
+        };
+        let tile_set = TileSet::from_dir(&imgs_dir, 8, &opts)?;
+        let mosaic = MosaicBuilder::new(&tile_set, opts).build(&solid(40, 40, [255; 3]))?;
+
+        Ok((mosaic, canonical(&paths[0])))
+    }
+
+    /// Checks that the pinned image is at `(1, 1)` only, and the brightest images elsewhere.
+    fn assert_pinned_at_bottom_right(mosaic: &Mosaic<PathBuf>, pinned: &Path) {
+        let placements: Vec<_> = mosaic
+            .plan
+            .placements
+            .iter()
+            .map(|p| (p.row, p.col, canonical(&p.id) == pinned))
+            .collect();
+        assert_eq!(
+            placements,
+            vec![(0, 0, false), (0, 1, false), (1, 0, false), (1, 1, true)]
+        );
+
+        let mut names: Vec<_> = mosaic.plan.placements[..3]
+            .iter()
+            .map(|p| p.id.file_name().unwrap().to_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["5.png", "6.png", "7.png"]);
+    }
 
-    Ok(())
+    #[test]
+    fn pinned_tile_is_only_used_in_its_cell() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mosaic, pinned) = build_with_pinned_tile(dir.path(), (1, 1), &[]).unwrap();
+        assert_pinned_at_bottom_right(&mosaic, &pinned);
+    }
+
+    #[test]
+    fn pinned_tile_is_used_when_excluded() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mosaic, pinned) = build_with_pinned_tile(dir.path(), (1, 1), &["0.png"]).unwrap();
+        assert_pinned_at_bottom_right(&mosaic, &pinned);
+    }
+
+    #[test]
+    fn pinned_tile_outside_grid_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = build_with_pinned_tile(dir.path(), (2, 0), &[]).map(|_| ());
+        match result {
+            Err(MosaicError::InvalidOptions { problems }) => {
+                assert_eq!(problems.len(), 1);
+                assert_eq!(problems[0].option, "pinned_tiles");
+            }
+            r => panic!("{r:?}"),
+        }
+    }
+
+    #[test]
+    fn top_candidates_are_ordered_by_error() {
+        let tile_set = tile_set(8);
+        let target_img = solid(40, 40, [33; 3]);
+        // pinned tiles don't matter, even when the pinned image is not part of the tile set
+        let dir = tempfile::tempdir().unwrap();
+        let pinned = write_solid_pngs(dir.path(), 1, 8).remove(0);
+        let opts = MakeImgOfImsOpts {
+            pinned_tiles: HashMap::from([((0, 1), pinned)]),
+            ..grid_opts(2, 2)
+        };
+        let builder = MosaicBuilder::new(&tile_set, opts);
+
+        let region = CellRegion {
+            rows: 0..1,
+            cols: 1..5,
+        };
+        let cells = builder
+            .top_candidates(&target_img, 3, Some(region))
+            .unwrap();
+        assert_eq!(cells.len(), 1);
+        assert_eq!((cells[0].row, cells[0].col), (0, 1));
+        let ids: Vec<u8> = cells[0].candidates.iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![3, 4, 2]);
+        let errors: Vec<f32> = cells[0].candidates.iter().map(|c| c.error).collect();
+        assert!(errors.windows(2).all(|e| e[0] < e[1]), "{errors:?}");
+
+        let cells = builder.top_candidates(&target_img, 20, None).unwrap();
+        let positions: Vec<_> = cells.iter().map(|c| (c.row, c.col)).collect();
+        assert_eq!(positions, vec![(0, 0), (0, 1), (1, 0), (1, 1)]);
+        for cell in cells {
+            let ids: Vec<u8> = cell.candidates.iter().map(|c| c.id).collect();
+            assert_eq!(ids[..3], [3, 4, 2]);
+            assert_eq!(ids.len(), 8);
+        }
+    }
+
+    /// Options whose cancellation token is cancelled as soon as `stage` starts.
+    /// A reporter which collects the warnings.
+    fn warnings_reporter() -> (Arc<dyn ProgressReporter>, Arc<Mutex<Vec<String>>>) {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let reporter = {
+            let warnings = warnings.clone();
+            move |event| {
+                if let ProgressEvent::Warning(message) = event {
+                    warnings.lock().unwrap().push(message);
+                }
+            }
+        };
+        (Arc::new(reporter), warnings)
+    }
+
+    #[test]
+    fn images_are_recognised_by_content() {
+        let dir = tempfile::tempdir().unwrap();
+        solid(8, 8, [10; 3])
+            .save_with_format(dir.path().join("photo.JPG"), ImageFormat::Jpeg)
+            .unwrap();
+        solid(8, 8, [20; 3])
+            .save_with_format(dir.path().join("export.dat"), ImageFormat::Png)
+            .unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not an image").unwrap();
+        std::fs::write(dir.path().join("broken.png"), "not an image either").unwrap();
+        std::fs::write(dir.path().join("README"), "").unwrap();
+
+        let (reporter, warnings) = warnings_reporter();
+        let opts = MakeImgOfImsOpts {
+            progress_reporter: Some(reporter),
+            ..Default::default()
+        };
+        let tile_set = TileSet::from_dir(dir.path(), 8, &opts).unwrap();
+
+        let names: Vec<_> = tile_set
+            .ids()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["export.dat", "photo.JPG"]);
+        // a file without a known signature is taken by its extension, and then fails to load
+        let broken = dir.path().join("broken.png");
+        assert_eq!(
+            *warnings.lock().unwrap(),
+            [
+                "Skipped 2 files which are not supported images (1 .txt, 1 without extension)"
+                    .to_owned(),
+                format!("Failed loading image: {broken:?}"),
+            ]
+        );
+    }
+
+    /// Names of the images found in `input`, relative to it.
+    fn found_names(input: &Path, opts: MakeImgOfImsOpts) -> Vec<String> {
+        let source = open_source(input, &opts).unwrap();
+        let found = find_all_imgs(source.as_ref(), &opts).unwrap();
+        let mut names: Vec<String> = found
+            .imgs
+            .iter()
+            .map(|path| {
+                path.strip_prefix(input)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn discovery_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let imgs = dir.path().join("imgs");
+        let outside = dir.path().join("outside");
+        for name in [
+            "a.png",
+            "B.PNG",
+            ".hidden.png",
+            ".trash/c.png",
+            "thumbs/d.png",
+            "sub/deep/e.png",
+        ] {
+            let path = imgs.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            solid(8, 8, [0; 3])
+                .save_with_format(path, ImageFormat::Png)
+                .unwrap();
+        }
+        write_solid_pngs(&outside, 1, 8);
+        std::os::unix::fs::symlink(&outside, imgs.join("link")).unwrap();
+
+        let all = [
+            ".hidden.png",
+            ".trash/c.png",
+            "B.PNG",
+            "a.png",
+            "link/0.png",
+            "sub/deep/e.png",
+            "thumbs/d.png",
+        ];
+        assert_eq!(found_names(&imgs, MakeImgOfImsOpts::default()), all);
+
+        let without = |left_out: &[&str]| -> Vec<&str> {
+            all.into_iter().filter(|n| !left_out.contains(n)).collect()
+        };
+        let cases = [
+            (
+                MakeImgOfImsOpts {
+                    include: vec!["*.png".to_owned()],
+                    ..Default::default()
+                },
+                vec![".hidden.png", "B.PNG", "a.png"],
+            ),
+            (
+                MakeImgOfImsOpts {
+                    include: vec!["**/*.png".to_owned()],
+                    ..Default::default()
+                },
+                all.to_vec(),
+            ),
+            (
+                MakeImgOfImsOpts {
+                    exclude: vec!["thumbs/**".to_owned(), ".TRASH/*".to_owned()],
+                    ..Default::default()
+                },
+                without(&["thumbs/d.png", ".trash/c.png"]),
+            ),
+            (
+                MakeImgOfImsOpts {
+                    max_depth: Some(0),
+                    ..Default::default()
+                },
+                vec![".hidden.png", "B.PNG", "a.png"],
+            ),
+            (
+                MakeImgOfImsOpts {
+                    max_depth: Some(1),
+                    ..Default::default()
+                },
+                without(&["sub/deep/e.png"]),
+            ),
+            (
+                MakeImgOfImsOpts {
+                    skip_hidden: true,
+                    ..Default::default()
+                },
+                without(&[".hidden.png", ".trash/c.png"]),
+            ),
+            (
+                MakeImgOfImsOpts {
+                    follow_symlinks: false,
+                    ..Default::default()
+                },
+                without(&["link/0.png"]),
+            ),
+        ];
+        for (opts, expected) in cases {
+            let description = format!(
+                "{:?}",
+                (
+                    &opts.include,
+                    &opts.exclude,
+                    opts.max_depth,
+                    opts.skip_hidden,
+                    opts.follow_symlinks
+                )
+            );
+            assert_eq!(found_names(&imgs, opts), expected, "{description}");
+        }
+    }
+
+    #[test]
+    fn patterns_match_paths_in_archives_and_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("tiles.zip");
+        write_solid_png_zip(&archive, 3, 8);
+        let opts = || MakeImgOfImsOpts {
+            include: vec!["1.PNG".to_owned(), "sub/*".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(found_names(&archive, opts()), ["1.png"]);
+
+        write_solid_pngs(&dir.path().join("sub"), 2, 8);
+        let manifest = dir.path().join("tiles.txt");
+        std::fs::write(&manifest, "sub/0.png\nsub/1.png\n").unwrap();
+        let source = open_source(&manifest, &opts()).unwrap();
+        let found = find_all_imgs(source.as_ref(), &opts()).unwrap();
+        assert_eq!(found.imgs.len(), 2);
+    }
+
+    fn cancelled_at(stage: Stage) -> MakeImgOfImsOpts {
+        let cancellation_token = CancellationToken::new();
+        let reporter = {
+            let cancellation_token = cancellation_token.clone();
+            move |event| {
+                if matches!(event, ProgressEvent::StageStarted { stage: s, .. } if s == stage) {
+                    cancellation_token.cancel();
+                }
+            }
+        };
+
+        MakeImgOfImsOpts {
+            progress_reporter: Some(Arc::new(reporter)),
+            cancellation_token,
+            ..grid_opts(2, 2)
+        }
+    }
+
+    #[test]
+    fn cancelled_while_loading() {
+        let dir = tempfile::tempdir().unwrap();
+        write_solid_pngs(dir.path(), 8, 8);
+
+        let result = TileSet::from_dir(dir.path(), 8, &cancelled_at(Stage::LoadingImages));
+        assert!(matches!(result, Err(MosaicError::Cancelled)));
+    }
+
+    #[test]
+    fn cancelled_jobs_leave_no_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let tile_set = tile_set(8);
+        let target_img = solid(40, 40, [128; 3]);
+
+        // PNG and TIFF files are created before the first band is written, JPEG files afterwards
+        for (stage, name) in [
+            (Stage::CalculatingErrors, "out.png"),
+            (Stage::WritingImage, "out.png"),
+            (Stage::WritingImage, "out.tiff"),
+            (Stage::InsertingImages, "out.jpg"),
+        ] {
+            let path = dir.path().join(name);
+            let result = MosaicBuilder::new(&tile_set, cancelled_at(stage))
+                .build_to_file(&target_img, &path);
+
+            assert!(
+                matches!(result, Err(MosaicError::Cancelled)),
+                "{stage:?} {name}: {result:?}"
+            );
+            assert!(!path.exists(), "{stage:?} {name}");
+        }
+    }
+
+    #[test]
+    fn top_candidates_rejects_invalid_grids() {
+        let tile_set = tile_set(4);
+        let target_img = solid(40, 40, [128; 3]);
+
+        for opts in [grid_opts(0, 4), grid_opts(5000, 4)] {
+            let result = MosaicBuilder::new(&tile_set, opts).top_candidates(&target_img, 2, None);
+            assert!(
+                matches!(result, Err(MosaicError::InvalidOptions { .. })),
+                "{result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn search_recall_of_empty_tile_set() {
+        let tile_set = tile_set(0);
+        let target_img = solid(40, 40, [128; 3]);
+        let opts = MakeImgOfImsOpts {
+            shortlist_size: Some(2),
+            ..grid_opts(4, 4)
+        };
+
+        let result = MosaicBuilder::new(&tile_set, opts).search_recall(&target_img);
+        assert!(
+            matches!(result, Err(MosaicError::EmptyLibrary { dir: None })),
+            "{result:?}"
+        );
+    }
+
+    /// `n` tiles of 32x8 pixels with the same grey middle, which differ only in their outer
+    /// quarters, and a target made of them in reverse order.
+    fn edge_tiles(n: usize) -> (TileSet<usize>, DynamicImage) {
+        let tiles: Vec<RgbImage> = (0..n)
+            .map(|i| {
+                RgbImage::from_fn(32, 8, |x, _| match x {
+                    0..=7 => Rgb(grey(i, n)),
+                    24.. => Rgb(grey(n - 1 - i, n)),
+                    _ => Rgb([128; 3]),
+                })
+            })
+            .collect();
+
+        let mut target_img = RgbImage::new(32 * n as u32, 8);
+        for (i, tile) in tiles.iter().rev().enumerate() {
+            image::imageops::replace(&mut target_img, tile, 32 * i as i64, 0);
+        }
+
+        let tile_set = TileSet::from_images(
+            tiles.into_iter().map(DynamicImage::ImageRgb8).enumerate(),
+            8,
+        );
+        (tile_set, DynamicImage::ImageRgb8(target_img))
+    }
+
+    #[test]
+    fn shortlist_keeps_the_best_tile_of_wide_cells() {
+        let (tile_set, target_img) = edge_tiles(8);
+
+        let opts = MakeImgOfImsOpts {
+            num_horizontal_imgs: 8,
+            num_vertical_imgs: 1,
+            pyramid_levels: 3,
+            shortlist_size: Some(1),
+            ..Default::default()
+        };
+        let builder = MosaicBuilder::new(&tile_set, opts);
+
+        assert_eq!(builder.search_recall(&target_img).unwrap(), 1.0);
+        let candidates = builder.top_candidates(&target_img, 1, None).unwrap();
+        let ids: Vec<usize> = candidates.iter().map(|c| c.candidates[0].id).collect();
+        assert_eq!(ids, [7, 6, 5, 4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn pyramid_levels_are_checked() {
+        let (tile_set, target_img) = edge_tiles(8);
+        let opts = |pyramid_levels| MakeImgOfImsOpts {
+            num_horizontal_imgs: 8,
+            num_vertical_imgs: 1,
+            pyramid_levels,
+            shortlist_size: Some(1),
+            ..Default::default()
+        };
+
+        // without levels all tiles are scored
+        let builder = MosaicBuilder::new(&tile_set, opts(0));
+        assert_eq!(builder.search_recall(&target_img).unwrap(), 1.0);
+        assert!(builder.prepare(&target_img).unwrap().pyramid.is_none());
+
+        let builder = MosaicBuilder::new(&tile_set, opts(6));
+        let pyramid = builder.prepare(&target_img).unwrap().pyramid.unwrap();
+        assert_eq!(pyramid.n_levels(), 6);
+
+        let result = MosaicBuilder::new(&tile_set, opts(7)).search_recall(&target_img);
+        match result {
+            Err(MosaicError::InvalidOptions { problems }) => {
+                assert_eq!(problems.len(), 1);
+                assert_eq!(problems[0].option, "pyramid_levels");
+            }
+            r => panic!("{r:?}"),
+        }
+    }
+
+    const FIT_MODES: [FitMode; 3] = [
+        FitMode::Crop,
+        FitMode::Stretch,
+        FitMode::Pad { background: [0; 3] },
+    ];
+
+    fn auto_grid(tile_aspect_ratio: (u32, u32), size: AutoGridSize) -> AutoGrid {
+        AutoGrid {
+            tile_aspect_ratio,
+            size,
+        }
+    }
+
+    #[test]
+    fn auto_grid_num_tiles_exact() {
+        for fit_mode in FIT_MODES {
+            let grid = Grid::auto(
+                400,
+                300,
+                auto_grid((1, 1), AutoGridSize::NumTiles(12)),
+                fit_mode,
+            );
+
+            assert_eq!(
+                grid,
+                Grid {
+                    n_horizontal: 4,
+                    n_vertical: 3,
+                    tile_width: 100,
+                    tile_height: 100,
+                },
+                "{fit_mode:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn auto_grid_num_tiles_closest_to_request() {
+        let size = AutoGridSize::NumTiles(50);
+
+        for fit_mode in FIT_MODES {
+            let grid = Grid::auto(103, 77, auto_grid((4, 3), size), fit_mode);
+
+            // rounding the tile size of exactly 50 tiles to 15x11 pixels would give 42 tiles
+            assert!(grid.n_cells().abs_diff(50) <= 1, "{fit_mode:?}: {grid:?}");
+            let aspect = grid.tile_width as f32 / grid.tile_height as f32;
+            assert!((aspect - 4.0 / 3.0).abs() < 0.1, "{fit_mode:?}: {grid:?}");
+        }
+    }
+
+    #[test]
+    fn auto_grid_tile_width() {
+        let size = AutoGridSize::TileWidth(15);
+
+        for (fit_mode, (n_horizontal, n_vertical)) in
+            FIT_MODES.into_iter().zip([(6, 7), (7, 7), (6, 7)])
+        {
+            let grid = Grid::auto(103, 77, auto_grid((4, 3), size), fit_mode);
+
+            assert_eq!(
+                grid,
+                Grid {
+                    n_horizontal,
+                    n_vertical,
+                    tile_width: 15,
+                    tile_height: 11,
+                },
+                "{fit_mode:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn lowest_errors_keeps_best_in_image_order() {
+        let errors = vec![(0, 0.5), (1, 0.1), (2, 0.3), (3, 0.1), (4, 0.3), (5, 0.9)];
+
+        assert_eq!(
+            lowest_errors(errors.clone(), 3),
+            vec![(1, 0.1), (2, 0.3), (3, 0.1)]
+        );
+        assert_eq!(lowest_errors(errors.clone(), 6), errors);
+        assert_eq!(lowest_errors(errors.clone(), 10), errors);
+    }
+
+    #[test]
+    fn build_to_file_in_any_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let tile_set = tile_set(16);
+        let target_img = solid(40, 40, [128; 3]);
+        let builder = MosaicBuilder::new(&tile_set, grid_opts(4, 4));
+
+        for name in ["out.png", "out.tiff", "out.jpg", "out.bmp"] {
+            let path = dir.path().join(name);
+            builder.build_to_file(&target_img, &path).unwrap();
+            assert_eq!(image::open(&path).unwrap().dimensions(), (40, 40), "{name}");
+        }
+    }
+
+    #[test]
+    fn build_to_file_and_save_write_the_same_bit_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let tile_set = tile_set(16);
+        let target_img = solid(40, 40, [128; 3]);
+        let builder = MosaicBuilder::new(&tile_set, grid_opts(4, 4));
+
+        for name in ["png", "tiff"] {
+            let built = dir.path().join(format!("built.{name}"));
+            let saved = dir.path().join(format!("saved.{name}"));
+            builder.build_to_file(&target_img, &built).unwrap();
+            builder.build(&target_img).unwrap().save(&saved).unwrap();
+
+            let built = image::open(built).unwrap();
+            let saved = image::open(saved).unwrap();
+            assert_eq!(built.color(), image::ColorType::Rgb8, "{name}");
+            assert_eq!(saved.color(), image::ColorType::Rgb8, "{name}");
+            assert_eq!(built, saved, "{name}");
+        }
+    }
+
+    #[test]
+    fn rendering_in_memory_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let tile_set = tile_set(16);
+        let target_img = solid(40, 40, [128; 3]);
+
+        let (reporter, warnings) = warnings_reporter();
+        let opts = |fit_mode| MakeImgOfImsOpts {
+            fit_mode,
+            progress_reporter: Some(reporter.clone()),
+            ..grid_opts(4, 4)
+        };
+
+        for (name, fit_mode, warned) in [
+            ("out.png", FitMode::Crop, false),
+            ("out.jpg", FitMode::Crop, true),
+            ("out.png", FitMode::Stretch, true),
+        ] {
+            let path = dir.path().join(name);
+            MosaicBuilder::new(&tile_set, opts(fit_mode))
+                .build_to_file(&target_img, &path)
+                .unwrap();
+
+            let warnings = std::mem::take(&mut *warnings.lock().unwrap());
+            let in_memory = warnings
+                .iter()
+                .any(|w| w.ends_with("is rendered in memory"));
+            assert_eq!(in_memory, warned, "{name} {fit_mode:?}: {warnings:?}");
+        }
+    }
+
+    #[test]
+    fn matches_downscaled_cells_and_renders_full_size_tiles() {
+        let colours = [[250, 0, 0], [0, 250, 0], [0, 0, 250], [250, 250, 0]];
+        let tile_set = TileSet::from_images(
+            colours
+                .iter()
+                .enumerate()
+                .map(|(i, &c)| (i, solid(64, 64, c))),
+            64,
+        );
+
+        let mut target_img = RgbImage::new(256, 256);
+        for (x, y, px) in target_img.enumerate_pixels_mut() {
+            *px = Rgb(colours[(y / 128 * 2 + x / 128) as usize]);
+        }
+
+        let opts = MakeImgOfImsOpts {
+            target_width: Some(256),
+            num_horizontal_imgs: 2,
+            num_vertical_imgs: 2,
+            match_tile_size: 8,
+            ..Default::default()
+        };
+        let mosaic = MosaicBuilder::new(&tile_set, opts)
+            .build(&DynamicImage::ImageRgb8(target_img))
+            .unwrap();
+
+        let ids: Vec<usize> = mosaic.plan.placements.iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![0, 1, 2, 3]);
+
+        let image = mosaic.image.to_rgb8();
+        assert_eq!(image.dimensions(), (256, 256));
+        for (i, colour) in colours.iter().enumerate() {
+            let (x, y) = (i as u32 % 2 * 128 + 64, i as u32 / 2 * 128 + 64);
+            assert_eq!(image.get_pixel(x, y).0, *colour);
+        }
+    }
+
+    /// Builds a mosaic of 7x5 tiles of a 103x77 target, which the grid can't cover exactly.
+    fn build_with_fit_mode(fit_mode: FitMode) -> Mosaic<u8> {
+        let opts = MakeImgOfImsOpts {
+            target_width: Some(103),
+            target_height: Some(77),
+            fit_mode,
+            ..grid_opts(7, 5)
+        };
+
+        MosaicBuilder::new(&tile_set(4), opts)
+            .build(&solid(206, 154, [128; 3]))
+            .unwrap()
+    }
+
+    #[test]
+    fn crop_cuts_off_margins() {
+        let mosaic = build_with_fit_mode(FitMode::Crop);
+
+        assert_eq!(mosaic.image.dimensions(), (98, 75));
+        assert_eq!(
+            (mosaic.plan.grid.tile_width, mosaic.plan.grid.tile_height),
+            (14, 15)
+        );
+    }
+
+    #[test]
+    fn stretch_keeps_target_dimensions() {
+        let mosaic = build_with_fit_mode(FitMode::Stretch);
+
+        assert_eq!(mosaic.image.dimensions(), (103, 77));
+        assert_eq!(
+            (mosaic.plan.grid.tile_width, mosaic.plan.grid.tile_height),
+            (15, 15)
+        );
+    }
+
+    #[test]
+    fn pad_keeps_target_dimensions() {
+        let mosaic = build_with_fit_mode(FitMode::Pad {
+            background: [1, 2, 3],
+        });
+        let image = mosaic.image.to_rgb8();
+
+        assert_eq!(image.dimensions(), (103, 77));
+        assert_eq!(
+            (mosaic.plan.grid.tile_width, mosaic.plan.grid.tile_height),
+            (14, 15)
+        );
+        // the 98x75 grid is centred, leaving margins of 2 and 1 pixels on the left and top
+        assert_eq!(image.get_pixel(1, 40).0, [1, 2, 3]);
+        assert_eq!(image.get_pixel(50, 0).0, [1, 2, 3]);
+        assert_ne!(image.get_pixel(2, 1).0, [1, 2, 3]);
+    }
+
+    #[test]
+    fn banded_file_matches_built_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let tile_set = tile_set(1);
+        let target_img = solid(45, 43, [128; 3]);
+        let opts = MakeImgOfImsOpts {
+            target_width: Some(45),
+            fit_mode: FitMode::Pad {
+                background: [1, 2, 3],
+            },
+            ..grid_opts(4, 4)
+        };
+        let builder = MosaicBuilder::new(&tile_set, opts);
+
+        let path = dir.path().join("out.png");
+        builder.build_to_file(&target_img, &path).unwrap();
+        let built = builder.build(&target_img).unwrap();
+
+        assert_eq!(image::open(&path).unwrap().to_rgb8(), built.image.to_rgb8());
+        assert_eq!(built.image.to_rgb8().get_pixel(0, 0).0, [1, 2, 3]);
+    }
+
+    #[test]
+    fn render_plan_from_files_reads_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = write_solid_png_zip(&dir.path().join("tiles.zip"), 3, 8);
+
+        let placements = [(0, 0, 0), (0, 1, 1), (1, 0, 2), (1, 1, 0)]
+            .into_iter()
+            .map(|(row, col, i)| TilePlacement {
+                row,
+                col,
+                id: paths[i].clone(),
+                metadata: TileMetadata::new(),
+                transform: Transform::Identity,
+                crop: CropWindow::centered(8, 8, 4, 4),
+                error: 0.0,
+            })
+            .collect();
+        let plan = MosaicPlan {
+            grid: Grid {
+                n_horizontal: 2,
+                n_vertical: 2,
+                tile_width: 4,
+                tile_height: 4,
+            },
+            fit_mode: FitMode::Crop,
+            target_width: 8,
+            target_height: 8,
+            placements,
+        };
+
+        let path = dir.path().join("out.png");
+        render_plan_from_files(&plan, 1.0, &path, &MakeImgOfImsOpts::default()).unwrap();
+
+        let expected = render_plan(&plan, 1.0, |path| load_img(path, true)).unwrap();
+        assert_eq!(image::open(&path).unwrap().to_rgb8(), expected.to_rgb8());
+        assert_eq!(expected.to_rgb8().get_pixel(4, 4).0, grey(0, 3));
+        assert_eq!(expected.to_rgb8().get_pixel(0, 4).0, grey(2, 3));
+    }
+
+    type ImageF32 = image::ImageBuffer<Rgb<f32>, Vec<f32>>;
+
+    /// How tiles were scored before the integer kernel, summing in `f32` over channel values
+    /// scaled to `[0, 1]`.
+    fn squared_error_f32(
+        target_img: &ImageF32,
+        fill_img: &TileView,
+        x_start: u32,
+        y_start: u32,
+    ) -> f32 {
+        let (w, h) = fill_img.dimensions();
+
+        let patch = target_img.view(x_start, y_start, w, h);
+
+        let sum = patch
+            .pixels()
+            .zip(fill_img.pixels())
+            .map(|(p1, &p2)| {
+                p1.2 .0
+                    .into_iter()
+                    .zip(p2.0)
+                    .map(|(v1, v2)| (v1 - v2 as f32 / 255.0).powi(2))
+                    .sum::<f32>()
+            })
+            .sum::<f32>();
+
+        sum / (w as f32 * h as f32)
+    }
+
+    #[test]
+    fn kernel_picks_same_tiles_as_f32_scorer() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(40);
+        let mut random_img = |width, height| {
+            RgbImage::from_fn(width, height, |_, _| Rgb([rng.gen(), rng.gen(), rng.gen()]))
+        };
+
+        let (tile_width, tile_height) = (8, 6);
+        let tiles: TileStore = (0..300)
+            .map(|_| random_img(tile_width, tile_height))
+            .collect();
+        let target_img = random_img(tile_width * 10, tile_height * 10);
+        let target_f32 = DynamicImage::ImageRgb8(target_img.clone()).into_rgb32f();
+
+        // the best few tiles, as the error log keeps them
+        let ranking = |errors: Vec<f32>| {
+            let mut ranked: Vec<(usize, f32)> = errors.into_iter().enumerate().collect();
+            ranked.sort_by(|(_, e1), (_, e2)| e1.partial_cmp(e2).unwrap());
+            ranked
+                .into_iter()
+                .take(5)
+                .map(|(idx, _)| idx)
+                .collect::<Vec<_>>()
+        };
+
+        for y in (0..target_img.height()).step_by(tile_height as usize) {
+            for x in (0..target_img.width()).step_by(tile_width as usize) {
+                let cell = cell_pixels(&target_img, x, y, tile_width, tile_height);
+
+                let errors = (0..tiles.len())
+                    .map(|idx| squared_error(&cell, &tiles.get(idx), false))
+                    .collect();
+                let f32_errors = (0..tiles.len())
+                    .map(|idx| squared_error_f32(&target_f32, &tiles.get(idx), x, y))
+                    .collect();
+
+                assert_eq!(ranking(errors), ranking(f32_errors), "cell at ({x}, {y})");
+            }
+        }
+    }
+
+    /// A random 64x64 cell, and copies of a random tile with the red channel of a random pixel one
+    /// level up or down. The exact errors of many of them differ by less than the `f32` sum can
+    /// resolve.
+    fn near_identical_tiles() -> (RgbImage, TileStore) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(40);
+        let cell = RgbImage::from_fn(64, 64, |_, _| Rgb(rng.gen()));
+        let tile = RgbImage::from_fn(64, 64, |_, _| Rgb(rng.gen()));
+
+        let tiles = (0..40)
+            .map(|_| {
+                let mut tile = tile.clone();
+                let px = tile.get_pixel_mut(rng.gen_range(0..64), rng.gen_range(0..64));
+                px[0] = if px[0] == 0 || (px[0] < 255 && rng.gen()) {
+                    px[0] + 1
+                } else {
+                    px[0] - 1
+                };
+                tile
+            })
+            .collect();
+
+        (cell, tiles)
+    }
+
+    #[test]
+    fn f32_scoring_matches_f32_scorer_on_near_ties() {
+        let (cell, tiles) = near_identical_tiles();
+        let target_f32 = DynamicImage::ImageRgb8(cell.clone()).into_rgb32f();
+
+        let errors: Vec<f32> = (0..tiles.len())
+            .map(|idx| squared_error(cell.as_raw(), &tiles.get(idx), true))
+            .collect();
+        let f32_errors: Vec<f32> = (0..tiles.len())
+            .map(|idx| squared_error_f32(&target_f32, &tiles.get(idx), 0, 0))
+            .collect();
+
+        let bits = |errors: &[f32]| errors.iter().map(|e| e.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&errors), bits(&f32_errors));
+    }
+
+    /// Pairs of tiles `(i, j)` where `i` has the lower exact error, but the higher `errors`.
+    fn inversions(sums: &[u64], errors: &[f32]) -> Vec<(usize, usize)> {
+        (0..sums.len())
+            .flat_map(|i| (0..sums.len()).map(move |j| (i, j)))
+            .filter(|&(i, j)| sums[i] < sums[j] && errors[i] > errors[j])
+            .collect()
+    }
+
+    #[test]
+    fn exact_scoring_never_inverts_near_ties() {
+        let (cell, tiles) = near_identical_tiles();
+        let target_f32 = DynamicImage::ImageRgb8(cell.clone()).into_rgb32f();
+
+        let sums: Vec<u64> = (0..tiles.len())
+            .map(|idx| kernel::sum_squared_diff_scalar(cell.as_raw(), tiles.get(idx).as_raw()))
+            .collect();
+        let errors: Vec<f32> = (0..tiles.len())
+            .map(|idx| squared_error(cell.as_raw(), &tiles.get(idx), false))
+            .collect();
+        let f32_errors: Vec<f32> = (0..tiles.len())
+            .map(|idx| squared_error_f32(&target_f32, &tiles.get(idx), 0, 0))
+            .collect();
+
+        // the closest tiles may round to the same error, but never to a lower one
+        assert_eq!(inversions(&sums, &errors), vec![]);
+        // unlike the `f32` sum
+        assert_ne!(inversions(&sums, &f32_errors), vec![]);
+    }
 }
@@ -0,0 +1,365 @@
+use std::{
+    io::{self, Cursor},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use crossbeam::channel::Sender;
+use image::{io::Reader as ImageReader, DynamicImage, GrayImage, ImageFormat, RgbImage};
+
+use crate::{
+    orientation::apply_exif_orientation, progress::Stage, scale_to_cover, source,
+    storage::TileStore, MakeImgOfImsOpts, MosaicError, TileSource,
+};
+
+/// A reader for the image file `data` at `path`, whose format is recognised from its content, or
+/// from the extension of `path` for formats without a signature.
+pub(crate) fn img_reader<'a>(
+    path: &Path,
+    data: &'a [u8],
+) -> io::Result<ImageReader<Cursor<&'a [u8]>>> {
+    let mut reader = ImageReader::new(Cursor::new(data));
+    if let Ok(format) = ImageFormat::from_path(path) {
+        reader.set_format(format);
+    }
+
+    reader.with_guessed_format()
+}
+
+/// Decodes a JPEG at a reduced scale of 1/2, 1/4 or 1/8 in the DCT domain, such that its
+/// shortest side is still at least `min_size`. Returns `None` when the image can't be reduced or
+/// has a pixel format which isn't supported here, so it can be decoded normally.
+fn decode_jpeg_scaled(data: &[u8], min_size: u32) -> Option<DynamicImage> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    decoder.read_info().ok()?;
+    let info = decoder.info()?;
+
+    if !matches!(
+        info.pixel_format,
+        jpeg_decoder::PixelFormat::RGB24 | jpeg_decoder::PixelFormat::L8
+    ) {
+        return None;
+    }
+
+    let min_size = min_size.min(u16::MAX as u32) as u16;
+    // only the shortest side may end up at the requested size
+    let (width, height) = if info.width <= info.height {
+        decoder.scale(min_size, u16::MAX).ok()?
+    } else {
+        decoder.scale(u16::MAX, min_size).ok()?
+    };
+
+    if (width, height) == (info.width, info.height) {
+        return None;
+    }
+
+    let (width, height) = (width as u32, height as u32);
+    let pixels = decoder.decode().ok()?;
+
+    if info.pixel_format == jpeg_decoder::PixelFormat::L8 {
+        GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)
+    } else {
+        RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+    }
+}
+
+/// Decodes the image file `data` at `path`. JPEGs are decoded at the smallest scale which still
+/// covers `min_size`.
+fn decode_img(path: &Path, data: &[u8], min_size: u32) -> Option<DynamicImage> {
+    let reader = img_reader(path, data).ok()?;
+
+    if reader.format() == Some(ImageFormat::Jpeg) {
+        if let Some(img) = decode_jpeg_scaled(data, min_size) {
+            return Some(img);
+        }
+    }
+
+    reader.decode().ok()
+}
+
+/// Like [`decode_img`], rotated as the EXIF orientation asks for unless
+/// `opts.ignore_exif_orientation` is set.
+fn load_tile(
+    path: &Path,
+    data: &[u8],
+    min_size: u32,
+    opts: &MakeImgOfImsOpts,
+) -> Option<DynamicImage> {
+    let img = decode_img(path, data, min_size)?;
+
+    Some(if opts.ignore_exif_orientation {
+        img
+    } else {
+        apply_exif_orientation(img, data)
+    })
+}
+
+/// Reads the images at `paths` from `sources`, and decodes and scales them on `opts.num_threads`
+/// threads, skipping the ones which fail to load. Scaled tiles are streamed into the store through
+/// a queue of at most `opts.load_queue_size` tiles, so only the tiles which are being worked on
+/// are kept at full size. The tiles are stored in the order of `paths`, together with their paths.
+pub(crate) fn load_tiles(
+    sources: &[&dyn TileSource],
+    paths: Vec<PathBuf>,
+    max_tile_size: u32,
+    opts: &MakeImgOfImsOpts,
+) -> Result<(Vec<PathBuf>, TileStore), MosaicError> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.num_threads.unwrap_or(0))
+        .build()
+        .map_err(|e| {
+            MosaicError::invalid_option(
+                "num_threads",
+                format!("Failed starting threads: {e}"),
+                "Use fewer threads",
+            )
+        })?;
+
+    let progress = opts.progress();
+    let stage_progress = progress.stage(Stage::LoadingImages, paths.len());
+    let cancellation_token = &opts.cancellation_token;
+
+    let mut store = TileStore::default();
+    let mut path_idxs = Vec::with_capacity(paths.len());
+
+    let read_result = stream(
+        opts.load_queue_size,
+        |sender| {
+            pool.install(|| {
+                source::read_from_sources(sources, &paths, cancellation_token, &|idx, data| {
+                    if cancellation_token.is_cancelled() {
+                        return;
+                    }
+
+                    let path = &paths[idx];
+                    let img = data
+                        .ok()
+                        .and_then(|data| load_tile(path, &data, max_tile_size, opts));
+
+                    match img {
+                        Some(img) => {
+                            let tile = scale_to_cover(img, max_tile_size);
+                            // only fails when the receiver is gone, which never happens first
+                            let _ = sender.send((idx, tile));
+                        }
+                        None => {
+                            log::warn!("Failed loading image: {path:?}");
+                            progress.warn(format!("Failed loading image: {path:?}"));
+                        }
+                    }
+
+                    stage_progress.inc();
+                })
+            })
+        },
+        |(idx, tile)| {
+            store.push(&tile);
+            path_idxs.push(idx);
+        },
+    );
+
+    read_result?;
+    cancellation_token.check()?;
+    stage_progress.finish();
+
+    // tiles arrive in the order in which they finish loading
+    let mut order: Vec<usize> = (0..path_idxs.len()).collect();
+    order.sort_unstable_by_key(|&k| path_idxs[k]);
+    store.reorder(&order);
+    store.shrink_to_fit();
+
+    let paths = order.iter().map(|&k| paths[path_idxs[k]].clone()).collect();
+
+    Ok((paths, store))
+}
+
+/// Runs `produce` on a thread of its own, and passes the items it sends to `consume` on the
+/// current thread as they arrive. At most `queue_size` items wait to be consumed, producers block
+/// until there is room.
+fn stream<T: Send, R: Send>(
+    queue_size: usize,
+    produce: impl FnOnce(Sender<T>) -> R + Send,
+    mut consume: impl FnMut(T),
+) -> R {
+    let (sender, receiver) = crossbeam::channel::bounded(queue_size.max(1));
+
+    thread::scope(|s| {
+        // the receiver stops once `produce` is done and has dropped the sender
+        let producer = s.spawn(|| produce(sender));
+        for item in receiver {
+            consume(item);
+        }
+        producer.join().expect("loading images does not panic")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use rayon::prelude::*;
+
+    use image::{GenericImageView, ImageBuffer, Luma, Rgb};
+
+    use super::*;
+    use crate::{source::DirSource, test_util::write_solid_pngs};
+
+    fn encode_jpeg(img: DynamicImage) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        img.write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        jpeg
+    }
+
+    /// A baseline JPEG of `width` x `height` CMYK pixels which are all the same, which the image
+    /// crate can't encode. Every block only has a zero DC difference, which is a single bit with
+    /// the one code Huffman tables used here, as is the end of block.
+    fn cmyk_jpeg(width: u16, height: u16) -> Vec<u8> {
+        fn segment(jpeg: &mut Vec<u8>, marker: u8, data: &[u8]) {
+            jpeg.extend([0xff, marker]);
+            jpeg.extend((data.len() as u16 + 2).to_be_bytes());
+            jpeg.extend(data);
+        }
+
+        let mut jpeg = vec![0xff, 0xd8];
+        segment(&mut jpeg, 0xdb, &[[0].as_slice(), &[1; 64]].concat());
+
+        let [w1, w2] = width.to_be_bytes();
+        let [h1, h2] = height.to_be_bytes();
+        let mut frame = vec![8, h1, h2, w1, w2, 4];
+        for id in 1..=4 {
+            frame.extend([id, 0x11, 0]);
+        }
+        segment(&mut jpeg, 0xc0, &frame);
+
+        for class in [0x00, 0x10] {
+            let mut table = vec![class, 1];
+            table.extend([0; 15]);
+            table.push(0);
+            segment(&mut jpeg, 0xc4, &table);
+        }
+
+        let mut scan = vec![4];
+        for id in 1..=4 {
+            scan.extend([id, 0x00]);
+        }
+        scan.extend([0, 63, 0]);
+        segment(&mut jpeg, 0xda, &scan);
+
+        // 4 blocks of 2 bits per 8x8 pixels
+        let n_mcus = (width as usize).div_ceil(8) * (height as usize).div_ceil(8);
+        jpeg.extend(vec![0; n_mcus]);
+        jpeg.extend([0xff, 0xd9]);
+        jpeg
+    }
+
+    #[test]
+    fn large_jpegs_are_decoded_at_reduced_scale() {
+        let img = ImageBuffer::from_fn(256, 128, |x, y| Rgb([x as u8, y as u8, 128]));
+        let jpeg = encode_jpeg(DynamicImage::ImageRgb8(img));
+        let path = Path::new("tile.jpg");
+
+        // 1/8 would make the shortest side 16
+        let img = decode_img(path, &jpeg, 32).unwrap();
+        assert_eq!(img.dimensions(), (64, 32));
+        assert!(matches!(img, DynamicImage::ImageRgb8(_)));
+
+        let img = decode_img(path, &jpeg, 17).unwrap();
+        assert_eq!(img.dimensions(), (64, 32));
+
+        // 1/2 would be too small
+        assert!(decode_jpeg_scaled(&jpeg, 100).is_none());
+        let img = decode_img(path, &jpeg, 100).unwrap();
+        assert_eq!(img.dimensions(), (256, 128));
+    }
+
+    #[test]
+    fn grayscale_jpegs_stay_grayscale() {
+        let img = ImageBuffer::from_fn(128, 256, |x, y| Luma([(x + y) as u8]));
+        let jpeg = encode_jpeg(DynamicImage::ImageLuma8(img));
+
+        let img = decode_img(Path::new("tile.jpg"), &jpeg, 16).unwrap();
+        assert_eq!(img.dimensions(), (16, 32));
+        assert!(matches!(img, DynamicImage::ImageLuma8(_)));
+    }
+
+    #[test]
+    fn cmyk_jpegs_use_full_decoder() {
+        let jpeg = cmyk_jpeg(64, 48);
+
+        assert!(decode_jpeg_scaled(&jpeg, 8).is_none());
+        let img = decode_img(Path::new("tile.jpg"), &jpeg, 8).unwrap();
+        assert_eq!(img.dimensions(), (64, 48));
+    }
+
+    #[test]
+    fn stream_respects_queue_size() {
+        let sent = AtomicUsize::new(0);
+        let consumed = AtomicUsize::new(0);
+        let max_waiting = AtomicUsize::new(0);
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let mut items = Vec::new();
+        stream(
+            3,
+            |sender| {
+                pool.install(|| {
+                    (0..100).into_par_iter().for_each(|i| {
+                        sender.send(i).unwrap();
+                        let sent = sent.fetch_add(1, Ordering::SeqCst) + 1;
+                        let waiting = sent.saturating_sub(consumed.load(Ordering::SeqCst));
+                        max_waiting.fetch_max(waiting, Ordering::SeqCst);
+                    })
+                })
+            },
+            |i| {
+                thread::sleep(Duration::from_micros(200));
+                items.push(i);
+                consumed.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+
+        items.sort_unstable();
+        assert_eq!(items, (0..100).collect::<Vec<_>>());
+        // the queue, and the item which is being consumed
+        let max_waiting = max_waiting.into_inner();
+        assert!(max_waiting <= 3 + 1, "{max_waiting} items waiting");
+    }
+
+    #[test]
+    fn load_order_does_not_depend_on_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = write_solid_pngs(dir.path(), 16, 8);
+        paths.reverse();
+        let source = DirSource::new(dir.path());
+
+        let loaded: Vec<_> = [1, 2, 8]
+            .into_iter()
+            .map(|num_threads| {
+                let opts = MakeImgOfImsOpts {
+                    num_threads: Some(num_threads),
+                    load_queue_size: 2,
+                    ..Default::default()
+                };
+                let (loaded_paths, store) =
+                    load_tiles(&[&source], paths.clone(), 8, &opts).unwrap();
+                let tiles: Vec<Vec<u8>> = (0..store.len())
+                    .map(|idx| store.get(idx).as_raw().to_vec())
+                    .collect();
+                (loaded_paths, tiles)
+            })
+            .collect();
+
+        assert_eq!(loaded[0].0, paths);
+        for (i, (loaded_paths, tiles)) in loaded.iter().enumerate() {
+            assert_eq!(loaded_paths, &loaded[0].0, "{i}");
+            assert_eq!(tiles, &loaded[0].1, "{i}");
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    source::{read_from_disk, read_header},
+    CancellationToken, MosaicError, OptionProblem, ReadFile, SourceFile, TileMetadata, TileSource,
+};
+
+/// Name of the CSV column with the paths, otherwise the first column is used.
+const PATH_COLUMN: &str = "path";
+
+/// Extensions by which manifests are recognised, see [`open_source`](crate::open_source).
+const EXTENSIONS: [&str; 3] = ["csv", "txt", "lst"];
+
+/// A file which lists the images to use. Either a CSV file, recognised by its `csv` extension,
+/// with a header row, the paths in the `path` column or else the first column, and metadata of
+/// the images like tags or captions in the other columns. Or a text file with a path on every
+/// line, in which empty lines and lines starting with `#` are ignored. Relative paths are
+/// relative to the folder of the manifest. The metadata ends up in the plan and does not affect
+/// matching, so a `weight` column has no special meaning; use the
+/// [`quota`](crate::TileInput::quota) of the manifest to weigh it against other inputs.
+#[derive(Debug, Clone)]
+pub struct ManifestSource {
+    path: PathBuf,
+    /// The listed images, without duplicates
+    entries: Vec<(PathBuf, TileMetadata)>,
+}
+
+impl ManifestSource {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, MosaicError> {
+        let path = path.into();
+        let content = fs::read_to_string(&path).map_err(|e| MosaicError::io(&path, e))?;
+
+        let is_csv = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+        let entries = if is_csv {
+            parse_csv(&content).map_err(|source| MosaicError::Manifest {
+                path: path.clone(),
+                source,
+            })?
+        } else {
+            parse_list(&content)
+        };
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut seen = HashSet::new();
+        let entries = entries
+            .into_iter()
+            .map(|(entry, metadata)| (dir.join(entry), metadata))
+            .filter(|(entry, _)| seen.insert(entry.clone()))
+            .collect();
+
+        Ok(Self { path, entries })
+    }
+
+    /// Whether `path` has the extension of a manifest, regardless of its case.
+    pub(crate) fn has_extension(path: &Path) -> bool {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+    }
+
+    /// Whether at least one of the listed images exists.
+    pub(crate) fn lists_existing_file(&self) -> bool {
+        self.entries.iter().any(|(path, _)| path.is_file())
+    }
+}
+
+fn parse_list(content: &str) -> Vec<(String, TileMetadata)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| (line.to_owned(), TileMetadata::new()))
+        .collect()
+}
+
+fn parse_csv(content: &str) -> Result<Vec<(String, TileMetadata)>, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(content.as_bytes());
+
+    let headers = reader.headers()?.clone();
+    let path_column = headers
+        .iter()
+        .position(|h| h.eq_ignore_ascii_case(PATH_COLUMN))
+        .unwrap_or(0);
+
+    let mut entries = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+
+        let path = match record.get(path_column) {
+            Some(path) if !path.is_empty() => path.to_owned(),
+            _ => continue,
+        };
+
+        let metadata = headers
+            .iter()
+            .zip(record.iter())
+            .enumerate()
+            .filter(|&(column, (_, value))| column != path_column && !value.is_empty())
+            .map(|(_, (name, value))| (name.to_owned(), value.to_owned()))
+            .collect();
+
+        entries.push((path, metadata));
+    }
+
+    Ok(entries)
+}
+
+impl TileSource for ManifestSource {
+    fn location(&self) -> &Path {
+        &self.path
+    }
+
+    /// Fails when listed images are missing, as the manifest asks for exactly these images.
+    fn files(
+        &self,
+        header_len: u64,
+        keep: &(dyn Fn(&Path) -> bool + Sync),
+    ) -> Result<Vec<SourceFile>, MosaicError> {
+        let files: Vec<Result<SourceFile, PathBuf>> = self
+            .entries
+            .par_iter()
+            .filter(|(path, _)| keep(path))
+            .map(|(path, metadata)| {
+                let header = File::open(path).and_then(|file| read_header(file, header_len));
+                match header {
+                    Ok(header) => Ok(SourceFile {
+                        path: path.clone(),
+                        header,
+                        metadata: metadata.clone(),
+                    }),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Err(path.clone()),
+                    // unreadable files get an empty header, and are skipped as they are no images
+                    Err(_) => Ok(SourceFile {
+                        path: path.clone(),
+                        header: Vec::new(),
+                        metadata: metadata.clone(),
+                    }),
+                }
+            })
+            .collect();
+
+        let missing: Vec<OptionProblem> = files
+            .iter()
+            .filter_map(|file| file.as_ref().err())
+            .map(|path| {
+                OptionProblem::new(
+                    "input_dir",
+                    format!("{path:?} listed in {:?} does not exist", self.path),
+                    "Remove it from the list, or restore the image",
+                )
+            })
+            .collect();
+
+        if !missing.is_empty() {
+            return Err(MosaicError::InvalidOptions { problems: missing });
+        }
+
+        Ok(files.into_iter().filter_map(Result::ok).collect())
+    }
+
+    fn read(
+        &self,
+        paths: &[PathBuf],
+        cancellation_token: &CancellationToken,
+        read_file: &ReadFile,
+    ) -> Result<(), MosaicError> {
+        read_from_disk(paths, cancellation_token, read_file);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(pairs: &[(&str, &str)]) -> TileMetadata {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_csv_finds_path_column() {
+        let entries = parse_csv("caption,Path,tag\nhello,a.png,x\n,b.png,\n").unwrap();
+
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    "a.png".to_owned(),
+                    metadata(&[("caption", "hello"), ("tag", "x")])
+                ),
+                ("b.png".to_owned(), TileMetadata::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_csv_uses_first_column_without_path_column() {
+        let entries = parse_csv("file,caption\na.png,hello\n").unwrap();
+
+        assert_eq!(
+            entries,
+            vec![("a.png".to_owned(), metadata(&[("caption", "hello")]))]
+        );
+    }
+
+    #[test]
+    fn parse_csv_quoted_commas() {
+        let entries = parse_csv("path,caption\n\"a, b.png\",\"x, y\"\n").unwrap();
+
+        assert_eq!(
+            entries,
+            vec![("a, b.png".to_owned(), metadata(&[("caption", "x, y")]))]
+        );
+    }
+
+    #[test]
+    fn parse_csv_skips_empty_paths() {
+        let entries = parse_csv("path,caption\n,orphan\n  ,blank\na.png\n").unwrap();
+
+        assert_eq!(entries, vec![("a.png".to_owned(), TileMetadata::new())]);
+    }
+
+    #[test]
+    fn parse_list_skips_comments_and_empty_lines() {
+        let entries = parse_list("# tiles\na.png\n\n   \n  b.png  \n#c.png\nd#e.png\n");
+        let paths: Vec<&str> = entries.iter().map(|(path, _)| path.as_str()).collect();
+
+        assert_eq!(paths, vec!["a.png", "b.png", "d#e.png"]);
+    }
+
+    #[test]
+    fn open_resolves_relative_paths_and_removes_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("tiles.txt");
+        let absolute = dir.path().join("abs.png");
+        fs::write(
+            &manifest,
+            format!("a.png\nsub/b.png\na.png\n{}\n", absolute.display()),
+        )
+        .unwrap();
+
+        let source = ManifestSource::open(&manifest).unwrap();
+        let paths: Vec<&Path> = source.entries.iter().map(|(p, _)| p.as_path()).collect();
+
+        assert_eq!(
+            paths,
+            vec![
+                dir.path().join("a.png").as_path(),
+                dir.path().join("sub/b.png").as_path(),
+                absolute.as_path(),
+            ]
+        );
+    }
+
+    #[test]
+    fn open_keeps_metadata_of_first_duplicate() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("tiles.CSV");
+        fs::write(&manifest, "path,caption\na.png,first\na.png,second\n").unwrap();
+
+        let source = ManifestSource::open(&manifest).unwrap();
+
+        assert_eq!(
+            source.entries,
+            vec![(dir.path().join("a.png"), metadata(&[("caption", "first")]))]
+        );
+    }
+
+    #[test]
+    fn missing_listed_file_is_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("tiles.txt");
+        fs::write(dir.path().join("a.png"), b"not checked here").unwrap();
+        fs::write(&manifest, "a.png\nmissing.png\n").unwrap();
+
+        let source = ManifestSource::open(&manifest).unwrap();
+        let result = source.files(16, &|_| true);
+
+        match result {
+            Err(MosaicError::InvalidOptions { problems }) => {
+                assert_eq!(problems.len(), 1);
+                assert!(problems[0].message.contains("missing.png"), "{problems:?}");
+            }
+            result => panic!("{result:?}"),
+        }
+    }
+}
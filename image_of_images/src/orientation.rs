@@ -0,0 +1,128 @@
+use std::io::Cursor;
+
+use image::DynamicImage;
+
+/// The EXIF orientation of the image file `data`, from 1 to 8. `None` when the file has no
+/// orientation tag or its format can't hold EXIF data.
+fn exif_orientation(data: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()?;
+
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+/// Rotates and flips `img`, which was decoded from the file `data`, such that it appears the way
+/// its EXIF orientation tag asks for. Cameras store portrait photos sideways with such a tag.
+pub(crate) fn apply_exif_orientation(img: DynamicImage, data: &[u8]) -> DynamicImage {
+    match exif_orientation(data) {
+        Some(2) => img.fliph(),
+        Some(3) => img.rotate180(),
+        Some(4) => img.flipv(),
+        Some(5) => img.rotate90().fliph(),
+        Some(6) => img.rotate90(),
+        Some(7) => img.rotate270().fliph(),
+        Some(8) => img.rotate270(),
+        _ => img,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{codecs::jpeg::JpegEncoder, GenericImageView, Rgb, RgbImage};
+
+    use super::*;
+    use crate::{load_img, MakeImgOfImsOpts, TileSet};
+
+    /// A 32x16 JPEG which is black but for its top left 8x8 pixels, with an EXIF orientation tag
+    /// of `orientation`.
+    fn oriented_jpeg(orientation: u16) -> Vec<u8> {
+        let img = RgbImage::from_fn(32, 16, |x, y| {
+            if x < 8 && y < 8 {
+                Rgb([255; 3])
+            } else {
+                Rgb([0; 3])
+            }
+        });
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 95)
+            .encode_image(&img)
+            .unwrap();
+
+        // a little endian TIFF header with a single IFD entry: the orientation as a SHORT
+        let mut exif = b"Exif\0\0II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0".to_vec();
+        exif.extend_from_slice(&orientation.to_le_bytes());
+        exif.extend_from_slice(&[0; 6]);
+
+        let mut app1 = vec![0xff, 0xe1];
+        app1.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        app1.extend_from_slice(&exif);
+
+        // right after the start of image marker
+        jpeg.splice(2..2, app1);
+        jpeg
+    }
+
+    /// Which corners of `img` are bright, as `(top, left)`.
+    fn bright_corners(img: &impl GenericImageView<Pixel = Rgb<u8>>) -> Vec<(bool, bool)> {
+        let (width, height) = img.dimensions();
+        [(true, true), (true, false), (false, true), (false, false)]
+            .into_iter()
+            .filter(|&(top, left)| {
+                let x = if left { 2 } else { width - 3 };
+                let y = if top { 2 } else { height - 3 };
+                img.get_pixel(x, y).0[0] > 128
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_orientation_puts_the_corner_in_place() {
+        // the displayed position of the stored top left corner, and whether width and height
+        // are swapped
+        let expected = [
+            (1, (true, true), false),
+            (2, (true, false), false),
+            (3, (false, false), false),
+            (4, (false, true), false),
+            (5, (true, true), true),
+            (6, (true, false), true),
+            (7, (false, false), true),
+            (8, (false, true), true),
+        ];
+
+        for (orientation, corner, swapped) in expected {
+            let data = oriented_jpeg(orientation);
+            assert_eq!(exif_orientation(&data), Some(orientation as u32));
+
+            let img = image::load_from_memory(&data).unwrap();
+            let img = apply_exif_orientation(img, &data).to_rgb8();
+
+            let dimensions = if swapped { (16, 32) } else { (32, 16) };
+            assert_eq!(img.dimensions(), dimensions, "{orientation}");
+            assert_eq!(bright_corners(&img), [corner], "{orientation}");
+        }
+    }
+
+    #[test]
+    fn orientation_can_be_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("portrait.jpg");
+        std::fs::write(&path, oriented_jpeg(6)).unwrap();
+
+        assert_eq!(load_img(&path, true).unwrap().dimensions(), (16, 32));
+        assert_eq!(load_img(&path, false).unwrap().dimensions(), (32, 16));
+
+        for (ignore_exif_orientation, corner) in [(false, (true, false)), (true, (true, true))] {
+            let opts = MakeImgOfImsOpts {
+                ignore_exif_orientation,
+                ..Default::default()
+            };
+            let tile_set = TileSet::from_dir(dir.path(), 16, &opts).unwrap();
+            let tile = tile_set.imgs.get(0);
+            assert_eq!(bright_corners(&tile), [corner], "{ignore_exif_orientation}");
+        }
+    }
+}
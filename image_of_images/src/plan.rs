@@ -0,0 +1,443 @@
+use std::{collections::HashMap, fs::File, hash::Hash, io::BufReader, path::Path};
+
+use image::{DynamicImage, GenericImageView, RgbImage};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    band::{self, Frame},
+    progress::Progress,
+    CancellationToken, FitMode, Grid, MosaicError, TileMetadata,
+};
+
+/// Orientation change applied to the source image of a tile before it is cropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Transform {
+    #[default]
+    Identity,
+    FlipHorizontal,
+    FlipVertical,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Transform {
+    fn apply(self, img: DynamicImage) -> DynamicImage {
+        match self {
+            Transform::Identity => img,
+            Transform::FlipHorizontal => img.fliph(),
+            Transform::FlipVertical => img.flipv(),
+            Transform::Rotate90 => img.rotate90(),
+            Transform::Rotate180 => img.rotate180(),
+            Transform::Rotate270 => img.rotate270(),
+        }
+    }
+}
+
+/// Part of the (transformed) source image which is used for a tile, as fractions of the source
+/// dimensions so it does not depend on the resolution the source is loaded at.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CropWindow {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl CropWindow {
+    /// The centered window with the aspect ratio of a `tile_width` x `tile_height` tile, which is
+    /// what tiles are cropped to when matching.
+    pub fn centered(src_width: u32, src_height: u32, tile_width: u32, tile_height: u32) -> Self {
+        let src_aspect = src_width as f32 / src_height as f32;
+        let tile_aspect = tile_width as f32 / tile_height as f32;
+
+        let (width, height) = if src_aspect > tile_aspect {
+            (tile_aspect / src_aspect, 1.0)
+        } else {
+            (1.0, src_aspect / tile_aspect)
+        };
+
+        Self {
+            x: (1.0 - width) / 2.0,
+            y: (1.0 - height) / 2.0,
+            width,
+            height,
+        }
+    }
+
+    fn crop(&self, img: &DynamicImage) -> DynamicImage {
+        let (w, h) = img.dimensions();
+        let to_px = |f: f32, len: u32| ((f.clamp(0.0, 1.0) * len as f32).round() as u32).min(len);
+
+        let x = to_px(self.x, w).min(w - 1);
+        let y = to_px(self.y, h).min(h - 1);
+        let width = to_px(self.width, w).clamp(1, w - x);
+        let height = to_px(self.height, h).clamp(1, h - y);
+
+        img.crop_imm(x, y, width, height)
+    }
+}
+
+/// The tile used for a single cell of the grid.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TilePlacement<Id> {
+    pub row: u32,
+    pub col: u32,
+    pub id: Id,
+    /// Metadata of the tile, see [`TileSet::metadata`](crate::TileSet::metadata)
+    #[serde(default, skip_serializing_if = "TileMetadata::is_empty")]
+    pub metadata: TileMetadata,
+    pub transform: Transform,
+    pub crop: CropWindow,
+    /// Mean squared error between the tile and the cell of the target
+    pub error: f32,
+}
+
+/// The result of matching tiles to a target, which can be stored, edited and rendered later
+/// with [`render_plan`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MosaicPlan<Id> {
+    pub grid: Grid,
+    pub fit_mode: FitMode,
+    /// Dimensions of the resized target, which the result has when rendered at scale 1
+    pub target_width: u32,
+    pub target_height: u32,
+    /// One placement for each cell, in row major order
+    pub placements: Vec<TilePlacement<Id>>,
+}
+
+impl<Id> MosaicPlan<Id> {
+    pub(crate) fn frame(&self) -> Frame {
+        Frame {
+            grid: self.grid,
+            fit_mode: self.fit_mode,
+            target_width: self.target_width,
+            target_height: self.target_height,
+        }
+    }
+}
+
+impl<Id: Serialize> MosaicPlan<Id> {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), MosaicError> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| MosaicError::io(path, e))?;
+        serde_json::to_writer_pretty(file, self).map_err(|source| MosaicError::Plan {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
+
+impl<Id: DeserializeOwned> MosaicPlan<Id> {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MosaicError> {
+        let path = path.as_ref();
+        let file = BufReader::new(File::open(path).map_err(|e| MosaicError::io(path, e))?);
+        serde_json::from_reader(file).map_err(|source| MosaicError::Plan {
+            path: path.to_owned(),
+            source,
+        })
+    }
+}
+
+/// Renders a plan with tiles scaled by `scale` compared to the plan's grid. The tiles are loaded
+/// using `load_tile` one row of the grid at a time, every distinct tile of a row once, such that
+/// only the tiles of a single row are kept besides the result.
+pub fn render_plan<Id, F>(
+    plan: &MosaicPlan<Id>,
+    scale: f32,
+    load_tile: F,
+) -> Result<DynamicImage, MosaicError>
+where
+    Id: Eq + Hash + Sync,
+    F: Fn(&Id) -> Result<DynamicImage, MosaicError> + Sync,
+{
+    let frame = plan.frame().scaled(scale);
+    let mut row_tiles = row_tiles(plan, frame.grid, &load_tile)?;
+
+    let result = band::render(
+        frame,
+        &mut row_tiles,
+        &Progress::new(None),
+        &CancellationToken::default(),
+    )?;
+
+    Ok(DynamicImage::ImageRgb8(result))
+}
+
+/// Like [`render_plan`], but writes the result to `output_file`. PNG and TIFF files are written
+/// one band of rows at a time, such that results far larger than the available memory can be
+/// rendered, see [`MosaicBuilder::build_to_file`](crate::MosaicBuilder::build_to_file).
+pub fn render_plan_to_file<Id, F>(
+    plan: &MosaicPlan<Id>,
+    scale: f32,
+    output_file: impl AsRef<Path>,
+    load_tile: F,
+) -> Result<(), MosaicError>
+where
+    Id: Eq + Hash + Sync,
+    F: Fn(&Id) -> Result<DynamicImage, MosaicError> + Sync,
+{
+    write_plan(
+        plan,
+        scale,
+        output_file.as_ref(),
+        &load_tile,
+        &Progress::new(None),
+        &CancellationToken::default(),
+    )
+}
+
+/// [`render_plan_to_file`] with progress and cancellation.
+pub(crate) fn write_plan<Id, F>(
+    plan: &MosaicPlan<Id>,
+    scale: f32,
+    output_file: &Path,
+    load_tile: &F,
+    progress: &Progress,
+    cancellation_token: &CancellationToken,
+) -> Result<(), MosaicError>
+where
+    Id: Eq + Hash + Sync,
+    F: Fn(&Id) -> Result<DynamicImage, MosaicError> + Sync,
+{
+    let frame = plan.frame().scaled(scale);
+    let mut row_tiles = row_tiles(plan, frame.grid, load_tile)?;
+
+    band::render_to_file(
+        output_file,
+        frame,
+        &mut row_tiles,
+        progress,
+        cancellation_token,
+    )
+}
+
+/// The tiles of a row of `grid` as placed by `plan`, cropped and resized to the cells.
+fn row_tiles<'a, Id, F>(
+    plan: &'a MosaicPlan<Id>,
+    grid: Grid,
+    load_tile: &'a F,
+) -> Result<impl FnMut(u32) -> Result<Vec<Option<RgbImage>>, MosaicError> + 'a, MosaicError>
+where
+    Id: Eq + Hash + Sync,
+    F: Fn(&Id) -> Result<DynamicImage, MosaicError> + Sync,
+{
+    let mut rows: Vec<Vec<usize>> = vec![Vec::new(); grid.n_vertical as usize];
+    for (idx, placement) in plan.placements.iter().enumerate() {
+        if placement.row >= grid.n_vertical || placement.col >= grid.n_horizontal {
+            return Err(MosaicError::PlacementOutsideGrid {
+                row: placement.row,
+                col: placement.col,
+                n_horizontal: grid.n_horizontal,
+                n_vertical: grid.n_vertical,
+            });
+        }
+
+        rows[placement.row as usize].push(idx);
+    }
+
+    Ok(move |row: u32| {
+        // later placements of a cell win
+        let mut cells: Vec<Option<usize>> = vec![None; grid.n_horizontal as usize];
+        for &idx in &rows[row as usize] {
+            cells[plan.placements[idx].col as usize] = Some(idx);
+        }
+
+        // placements of every tile, so that each tile is decoded once
+        let mut placements_of: HashMap<&Id, Vec<usize>> = HashMap::new();
+        for &idx in cells.iter().flatten() {
+            placements_of
+                .entry(&plan.placements[idx].id)
+                .or_default()
+                .push(idx);
+        }
+
+        let mut tiles: HashMap<usize, RgbImage> = placements_of
+            .into_par_iter()
+            .map(|(id, placements)| {
+                let img = load_tile(id)?;
+                Ok(placements
+                    .into_iter()
+                    .map(|idx| {
+                        let placement = &plan.placements[idx];
+                        let tile = placement.transform.apply(img.clone());
+                        let tile = placement.crop.crop(&tile).into_rgb8();
+                        let tile = image::imageops::resize(
+                            &tile,
+                            grid.tile_width,
+                            grid.tile_height,
+                            image::imageops::FilterType::Triangle,
+                        );
+                        (idx, tile)
+                    })
+                    .collect::<Vec<_>>())
+            })
+            .collect::<Result<Vec<_>, MosaicError>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        Ok(cells
+            .into_iter()
+            .map(|idx| idx.and_then(|idx| tiles.remove(&idx)))
+            .collect())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::solid;
+
+    fn placement(row: u32, col: u32, id: u8) -> TilePlacement<u8> {
+        TilePlacement {
+            row,
+            col,
+            id,
+            metadata: TileMetadata::new(),
+            transform: Transform::Identity,
+            crop: CropWindow::centered(8, 8, 4, 4),
+            error: 0.0,
+        }
+    }
+
+    fn plan(placements: Vec<TilePlacement<u8>>) -> MosaicPlan<u8> {
+        MosaicPlan {
+            grid: Grid {
+                n_horizontal: 2,
+                n_vertical: 2,
+                tile_width: 4,
+                tile_height: 4,
+            },
+            fit_mode: FitMode::Crop,
+            target_width: 8,
+            target_height: 8,
+            placements,
+        }
+    }
+
+    fn load_tile(id: &u8) -> Result<DynamicImage, MosaicError> {
+        Ok(solid(8, 8, [*id * 10; 3]))
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plan.json");
+
+        let mut plan = plan(vec![
+            placement(0, 0, 1),
+            placement(0, 1, 2),
+            placement(1, 0, 3),
+        ]);
+        plan.fit_mode = FitMode::Pad {
+            background: [1, 2, 3],
+        };
+        plan.placements[1].transform = Transform::Rotate90;
+        plan.placements[1].crop = CropWindow::centered(30, 20, 4, 4);
+        plan.placements[1].error = 0.125;
+        plan.placements[2]
+            .metadata
+            .insert("caption".to_owned(), "a, \"quoted\" caption".to_owned());
+
+        plan.save(&path).unwrap();
+        let loaded: MosaicPlan<u8> = MosaicPlan::load(&path).unwrap();
+
+        assert_eq!(loaded, plan);
+    }
+
+    #[test]
+    fn load_reports_invalid_plans() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plan.json");
+        std::fs::write(&path, "{\"grid\": 3}").unwrap();
+
+        let result = MosaicPlan::<u8>::load(&path);
+        assert!(
+            matches!(&result, Err(MosaicError::Plan { path: p, .. }) if *p == path),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn later_placements_win() {
+        let plan = plan(vec![
+            placement(0, 0, 1),
+            placement(0, 1, 2),
+            placement(1, 0, 3),
+            placement(0, 0, 4),
+        ]);
+
+        let image = render_plan(&plan, 2.0, load_tile).unwrap().to_rgb8();
+
+        assert_eq!(image.dimensions(), (16, 16));
+        assert_eq!(image.get_pixel(0, 0).0, [40; 3]);
+        assert_eq!(image.get_pixel(8, 0).0, [20; 3]);
+        assert_eq!(image.get_pixel(0, 8).0, [30; 3]);
+        // cells without a placement are left black
+        assert_eq!(image.get_pixel(8, 8).0, [0; 3]);
+    }
+
+    #[test]
+    fn render_plan_to_file_matches_render_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan = plan(vec![
+            placement(0, 0, 1),
+            placement(0, 1, 2),
+            placement(1, 0, 3),
+            placement(1, 1, 1),
+        ]);
+
+        for name in ["out.png", "out.tiff", "out.bmp"] {
+            let path = dir.path().join(name);
+            render_plan_to_file(&plan, 1.5, &path, load_tile).unwrap();
+
+            let rendered = render_plan(&plan, 1.5, load_tile).unwrap().to_rgb8();
+            assert_eq!(image::open(&path).unwrap().to_rgb8(), rendered, "{name}");
+        }
+    }
+
+    #[test]
+    fn padded_grids_larger_than_the_target_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut plan = plan(vec![placement(0, 0, 1)]);
+        plan.fit_mode = FitMode::Pad { background: [0; 3] };
+        plan.target_width = 6;
+
+        let result = render_plan(&plan, 1.0, load_tile);
+        assert!(
+            matches!(
+                result,
+                Err(MosaicError::GridLargerThanResult {
+                    grid_width: 8,
+                    width: 6,
+                    ..
+                })
+            ),
+            "{result:?}"
+        );
+
+        let path = dir.path().join("out.png");
+        let result = render_plan_to_file(&plan, 1.0, &path, load_tile);
+        assert!(
+            matches!(result, Err(MosaicError::GridLargerThanResult { .. })),
+            "{result:?}"
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn placements_outside_the_grid_are_rejected() {
+        let plan = plan(vec![placement(2, 0, 1)]);
+
+        let result = render_plan(&plan, 1.0, load_tile);
+        assert!(
+            matches!(
+                result,
+                Err(MosaicError::PlacementOutsideGrid { row: 2, .. })
+            ),
+            "{result:?}"
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::SendError;
+
+pub type ProgressSender = crossbeam::channel::Sender<(usize, usize, &'static str)>;
+pub type ProgressReceiver = crossbeam::channel::Receiver<(usize, usize, &'static str)>;
+pub type ProgressEventSender = crossbeam::channel::Sender<ProgressEvent>;
+pub type ProgressEventReceiver = crossbeam::channel::Receiver<ProgressEvent>;
+
+/// Minimal time between two [`ProgressEvent::Progress`] events of the same stage.
+pub const MIN_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn progress_channel() -> (ProgressSender, ProgressReceiver) {
+    crossbeam::channel::unbounded()
+}
+
+pub fn progress_event_channel() -> (ProgressEventSender, ProgressEventReceiver) {
+    crossbeam::channel::unbounded()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+    LoadingImages,
+    ResizingImages,
+    CalculatingErrors,
+    SelectingImages,
+    InsertingImages,
+    WritingImage,
+}
+
+impl Stage {
+    pub fn description(self) -> &'static str {
+        match self {
+            Stage::LoadingImages => "Loading images from disk",
+            Stage::ResizingImages => "Resizing images",
+            Stage::CalculatingErrors => "Calculating errors",
+            Stage::SelectingImages => "Selecting images for result",
+            Stage::InsertingImages => "Inserting images in target",
+            Stage::WritingImage => "Writing result to disk",
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    StageStarted {
+        stage: Stage,
+        total: usize,
+    },
+    Progress {
+        stage: Stage,
+        done: usize,
+        total: usize,
+        elapsed: Duration,
+        /// Estimated time until the stage is finished
+        eta: Option<Duration>,
+    },
+    StageFinished {
+        stage: Stage,
+        elapsed: Duration,
+    },
+    Warning(String),
+}
+
+/// Receives progress of a running job. Progress events are rate limited to one per
+/// [`MIN_PROGRESS_INTERVAL`] per stage, reporters may be called from multiple threads.
+pub trait ProgressReporter: Send + Sync {
+    fn report(&self, event: ProgressEvent);
+}
+
+impl fmt::Debug for dyn ProgressReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressReporter")
+    }
+}
+
+impl<F: Fn(ProgressEvent) + Send + Sync> ProgressReporter for F {
+    fn report(&self, event: ProgressEvent) {
+        self(event)
+    }
+}
+
+fn handle_progress_send_error<T: fmt::Debug>(e: Result<(), SendError<T>>) {
+    if let Err(e) = e {
+        log::warn!("Failed sending progress: {:?}", e);
+    }
+}
+
+impl ProgressReporter for ProgressEventSender {
+    fn report(&self, event: ProgressEvent) {
+        handle_progress_send_error(self.send(event));
+    }
+}
+
+/// Adapter for the `(done, total, description)` channel.
+impl ProgressReporter for ProgressSender {
+    fn report(&self, event: ProgressEvent) {
+        let e = match event {
+            ProgressEvent::StageStarted { stage, total } => {
+                self.send((0, total, stage.description()))
+            }
+            ProgressEvent::Progress {
+                stage, done, total, ..
+            } => self.send((done, total, stage.description())),
+            ProgressEvent::StageFinished { .. } | ProgressEvent::Warning(_) => return,
+        };
+        handle_progress_send_error(e);
+    }
+}
+
+/// Internal handle to an optional reporter.
+#[derive(Clone, Default)]
+pub(crate) struct Progress(Option<Arc<dyn ProgressReporter>>);
+
+impl Progress {
+    pub(crate) fn new(reporter: Option<Arc<dyn ProgressReporter>>) -> Self {
+        Self(reporter)
+    }
+
+    pub(crate) fn stage(&self, stage: Stage, total: usize) -> StageProgress {
+        if let Some(r) = &self.0 {
+            r.report(ProgressEvent::StageStarted { stage, total });
+        }
+
+        StageProgress {
+            reporter: self.0.clone(),
+            stage,
+            total,
+            start: Instant::now(),
+            done: AtomicUsize::new(0),
+            last_report: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn warn(&self, message: String) {
+        if let Some(r) = &self.0 {
+            r.report(ProgressEvent::Warning(message));
+        }
+    }
+}
+
+pub(crate) struct StageProgress {
+    reporter: Option<Arc<dyn ProgressReporter>>,
+    stage: Stage,
+    total: usize,
+    start: Instant,
+    done: AtomicUsize,
+    /// Nanoseconds since `start` of the last progress event
+    last_report: AtomicU64,
+}
+
+impl StageProgress {
+    pub(crate) fn inc(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.report(done);
+    }
+
+    pub(crate) fn set(&self, done: usize) {
+        self.done.store(done, Ordering::Relaxed);
+        self.report(done);
+    }
+
+    fn report(&self, done: usize) {
+        let reporter = match &self.reporter {
+            Some(r) => r,
+            None => return,
+        };
+
+        let elapsed = self.start.elapsed();
+        let now = elapsed.as_nanos() as u64;
+        // the final event is always reported
+        if done < self.total {
+            let last_report = self.last_report.load(Ordering::Relaxed);
+            if now.saturating_sub(last_report) < MIN_PROGRESS_INTERVAL.as_nanos() as u64 {
+                return;
+            }
+            // of the threads which see the interval has passed, only one reports it
+            if self
+                .last_report
+                .compare_exchange(last_report, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+            {
+                return;
+            }
+        }
+
+        let eta = (done > 0)
+            .then(|| elapsed.mul_f64(self.total.saturating_sub(done) as f64 / done as f64));
+
+        reporter.report(ProgressEvent::Progress {
+            stage: self.stage,
+            done,
+            total: self.total,
+            elapsed,
+            eta,
+        });
+    }
+
+    pub(crate) fn finish(self) {
+        if let Some(r) = &self.reporter {
+            r.report(ProgressEvent::StageFinished {
+                stage: self.stage,
+                elapsed: self.start.elapsed(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    fn collecting_reporter() -> (Arc<Mutex<Vec<ProgressEvent>>>, Progress) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let reporter = {
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event)
+        };
+        (events, Progress::new(Some(Arc::new(reporter))))
+    }
+
+    #[test]
+    fn rate_limits_progress_but_reports_the_end() {
+        let (events, progress) = collecting_reporter();
+
+        let stage = progress.stage(Stage::CalculatingErrors, 10_000);
+        for _ in 0..10_000 {
+            stage.inc();
+        }
+        stage.finish();
+
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events[0],
+            ProgressEvent::StageStarted {
+                stage: Stage::CalculatingErrors,
+                total: 10_000
+            }
+        );
+        assert!(matches!(
+            events[events.len() - 1],
+            ProgressEvent::StageFinished {
+                stage: Stage::CalculatingErrors,
+                ..
+            }
+        ));
+
+        let done: Vec<usize> = events
+            .iter()
+            .filter_map(|e| match e {
+                ProgressEvent::Progress { done, .. } => Some(*done),
+                _ => None,
+            })
+            .collect();
+        assert!(done.len() < 100, "{} progress events", done.len());
+        assert_eq!(done.last(), Some(&10_000));
+    }
+
+    #[test]
+    fn reports_the_end_from_any_thread() {
+        let (events, progress) = collecting_reporter();
+
+        let stage = progress.stage(Stage::LoadingImages, 4_000);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| (0..1_000).for_each(|_| stage.inc()));
+            }
+        });
+
+        let events = events.lock().unwrap();
+        let final_events = events
+            .iter()
+            .filter(|e| matches!(e, ProgressEvent::Progress { done: 4_000, .. }))
+            .count();
+        assert_eq!(final_events, 1);
+    }
+
+    #[test]
+    fn channel_adapter_translates_events() {
+        let (sender, receiver) = progress_channel();
+        let stage = Stage::ResizingImages;
+
+        sender.report(ProgressEvent::StageStarted { stage, total: 8 });
+        sender.report(ProgressEvent::Progress {
+            stage,
+            done: 3,
+            total: 8,
+            elapsed: Duration::from_secs(1),
+            eta: None,
+        });
+        sender.report(ProgressEvent::StageFinished {
+            stage,
+            elapsed: Duration::from_secs(2),
+        });
+        sender.report(ProgressEvent::Warning("skipped".to_owned()));
+        drop(sender);
+
+        let received: Vec<_> = receiver.iter().collect();
+        assert_eq!(
+            received,
+            vec![(0, 8, "Resizing images"), (3, 8, "Resizing images")]
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn legacy_progress_sender_is_used_without_reporter() {
+        let (sender, receiver) = progress_channel();
+        let opts = crate::MakeImgOfImsOpts {
+            progress_sender: Some(sender),
+            ..Default::default()
+        };
+
+        opts.progress().stage(Stage::LoadingImages, 2).set(2);
+        drop(opts);
+
+        let received: Vec<_> = receiver.iter().collect();
+        let description = Stage::LoadingImages.description();
+        assert_eq!(received, vec![(0, 2, description), (2, 2, description)]);
+    }
+}
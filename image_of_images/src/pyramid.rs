@@ -0,0 +1,187 @@
+use image::{imageops::FilterType, GenericImageView, Rgb, RgbImage};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    kernel,
+    storage::{TileStore, TileView},
+};
+
+/// Number of levels built when no other number is requested, of at most 4 and 8 pixels.
+pub(crate) const DEFAULT_LEVELS: usize = 2;
+
+/// Dimensions of `level` for cells of `width` x `height` pixels. The longest side of the coarsest
+/// level is 4 pixels and every next level doubles it, up to the size of the cells. The other side
+/// keeps the aspect ratio of the cells.
+fn level_dimensions(level: usize, width: u32, height: u32) -> (u32, u32) {
+    let longest = width.max(height);
+    let side = (4u32 << level).min(longest);
+    let scale =
+        |len: u32| ((len as u64 * side as u64 + longest as u64 / 2) / longest as u64).max(1);
+    (scale(width) as u32, scale(height) as u32)
+}
+
+/// `img`, which has the size of a cell, at `level`. Cells and tiles are shrunk the same way so
+/// that they can be compared.
+fn shrink<I>(img: &I, level: usize) -> RgbImage
+where
+    I: GenericImageView<Pixel = Rgb<u8>>,
+{
+    let (width, height) = level_dimensions(level, img.width(), img.height());
+    image::imageops::resize(img, width, height, FilterType::Triangle)
+}
+
+/// Copies of every tile at a few tiny sizes, used to shortlist the candidates for a cell before
+/// they are scored at full size. The levels are built from the tiles resized to the cells of a
+/// grid, so they have the aspect ratio of the cells.
+#[derive(Debug, Default)]
+pub(crate) struct Pyramid {
+    /// Coarsest level first
+    levels: Vec<TileStore>,
+}
+
+impl Pyramid {
+    /// Builds `n_levels` levels of `tiles`, which all have the size of the cells they are
+    /// matched to.
+    pub(crate) fn from_tiles(tiles: &TileStore, n_levels: usize) -> Self {
+        let levels = (0..n_levels)
+            .map(|level| {
+                let shrunk: Vec<RgbImage> = (0..tiles.len())
+                    .into_par_iter()
+                    .map(|idx| shrink(&tiles.get(idx), level))
+                    .collect();
+                shrunk.into_iter().collect()
+            })
+            .collect();
+
+        Self { levels }
+    }
+
+    pub(crate) fn n_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.levels.iter().map(TileStore::memory_usage).sum()
+    }
+}
+
+/// Narrows down the images worth scoring at full size for a cell, level by level. The finest
+/// level keeps `size` images and every coarser level keeps twice as many as the next one.
+pub(crate) struct Shortlist<'a> {
+    /// `levels[level][img_idx]`, coarsest level first
+    levels: Vec<Vec<TileView<'a>>>,
+    size: usize,
+}
+
+impl<'a> Shortlist<'a> {
+    /// Shortlists from the images `img_idxs` of `pyramid`, using at most `n_levels` levels. Image
+    /// `i` of the shortlist refers to the tile `img_idxs[i]`.
+    pub(crate) fn new(
+        pyramid: &'a Pyramid,
+        img_idxs: &[usize],
+        n_levels: usize,
+        size: usize,
+    ) -> Self {
+        let levels = pyramid
+            .levels
+            .iter()
+            .take(n_levels)
+            .map(|store| img_idxs.iter().map(|&idx| store.get(idx)).collect())
+            .collect();
+
+        Self {
+            levels,
+            size: size.max(1),
+        }
+    }
+
+    /// The images of `candidates` which should be scored for `cell`, which contains the pixels of
+    /// a `width` x `height` cell as returned by [`cell_pixels`](crate::cell_pixels).
+    pub(crate) fn narrow(
+        &self,
+        mut candidates: Vec<usize>,
+        cell: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Vec<usize> {
+        let cell = image::ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, cell)
+            .expect("cell data matches its dimensions");
+
+        for (level, tiles) in self.levels.iter().enumerate() {
+            let keep = self
+                .size
+                .saturating_mul(1 << (self.levels.len() - 1 - level));
+
+            if candidates.len() <= keep {
+                continue;
+            }
+
+            let small_cell = shrink(&cell, level);
+
+            let mut errors: Vec<(u64, usize)> = candidates
+                .par_iter()
+                .map(|&idx| {
+                    let err = kernel::sum_squared_diff(small_cell.as_raw(), tiles[idx].as_raw());
+                    (err, idx)
+                })
+                .collect();
+
+            errors.select_nth_unstable(keep - 1);
+            candidates = errors[..keep].iter().map(|&(_, idx)| idx).collect();
+            candidates.sort_unstable();
+        }
+
+        candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_have_the_aspect_ratio_of_the_cells() {
+        assert_eq!(level_dimensions(0, 32, 16), (4, 2));
+        assert_eq!(level_dimensions(2, 32, 16), (16, 8));
+        assert_eq!(level_dimensions(1, 6, 10), (5, 8));
+        assert_eq!(level_dimensions(0, 3, 100), (1, 4));
+        // never larger than the cells
+        assert_eq!(level_dimensions(3, 12, 6), (12, 6));
+
+        let tiles: TileStore = (0..3u8)
+            .map(|i| RgbImage::from_pixel(32, 16, Rgb([i * 100; 3])))
+            .collect();
+        let pyramid = Pyramid::from_tiles(&tiles, 3);
+
+        assert_eq!(pyramid.n_levels(), 3);
+        for (level, store) in pyramid.levels.iter().enumerate() {
+            assert_eq!(store.len(), 3);
+            for idx in 0..3 {
+                let tile = store.get(idx);
+                assert_eq!(tile.dimensions(), level_dimensions(level, 32, 16));
+                assert_eq!(*tile.get_pixel(0, 0), Rgb([idx as u8 * 100; 3]));
+            }
+        }
+    }
+
+    #[test]
+    fn shortlist_keeps_size_nearest_tiles() {
+        let tiles: TileStore = (0..20u8)
+            .map(|i| RgbImage::from_pixel(12, 6, Rgb([i * 10; 3])))
+            .collect();
+        let pyramid = Pyramid::from_tiles(&tiles, 2);
+        let cell = RgbImage::from_pixel(12, 6, Rgb([52; 3]));
+
+        // `img_idxs` leaves out tile 5, whose grey is nearest to the cell
+        let img_idxs: Vec<usize> = (0..20).filter(|&idx| idx != 5).collect();
+        let shortlist = Shortlist::new(&pyramid, &img_idxs, 2, 3);
+        let all: Vec<usize> = (0..img_idxs.len()).collect();
+
+        let narrowed = shortlist.narrow(all.clone(), cell.as_raw(), 12, 6);
+        let narrowed: Vec<usize> = narrowed.iter().map(|&i| img_idxs[i]).collect();
+        assert_eq!(narrowed, [4, 6, 7]);
+
+        // fewer candidates than the shortlist are kept as they are
+        assert_eq!(shortlist.narrow(vec![0, 10], cell.as_raw(), 12, 6), [0, 10]);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{progress::Progress, TileInput};
+
+/// Number of free cells which must be filled with tiles of every input to meet its
+/// [`quota`](TileInput::quota). Quotas are rounded up, and lowered to the number of tiles of an
+/// input when it has too few, which is reported to `progress`.
+pub(crate) fn needed_cells(
+    inputs: &[TileInput],
+    img_inputs: &[Option<usize>],
+    n_free_cells: usize,
+    progress: &Progress,
+) -> Vec<usize> {
+    inputs
+        .iter()
+        .enumerate()
+        .map(|(input, tile_input)| {
+            let quota = tile_input.quota.unwrap_or(0.0).clamp(0.0, 1.0);
+            let needed = (quota * n_free_cells as f32).ceil() as usize;
+
+            let available = img_inputs.iter().filter(|&&i| i == Some(input)).count();
+
+            if available < needed {
+                progress.warn(format!(
+                    "Only {available} of the {needed} cells asked for can be filled with images of {:?}",
+                    tile_input.path
+                ));
+            }
+
+            needed.min(available)
+        })
+        .collect()
+}
+
+/// Keeps track of the quotas while images are picked for cells.
+pub(crate) struct Quotas {
+    /// Input of every image which can be picked
+    img_inputs: Vec<Option<usize>>,
+    /// Cells which still have to be filled with images of every input
+    needed: Vec<usize>,
+    total_needed: usize,
+    free_cells: usize,
+}
+
+impl Quotas {
+    pub(crate) fn new(
+        img_inputs: Vec<Option<usize>>,
+        needed: Vec<usize>,
+        free_cells: usize,
+    ) -> Self {
+        Self {
+            img_inputs,
+            total_needed: needed.iter().sum(),
+            needed,
+            free_cells,
+        }
+    }
+
+    fn needed(&self, img_idx: usize) -> usize {
+        self.img_inputs[img_idx].map_or(0, |input| self.needed[input])
+    }
+
+    /// Whether image `img_idx` can fill a cell, which is the case unless the remaining cells are
+    /// all needed for quotas of other inputs.
+    pub(crate) fn allows(&self, img_idx: usize) -> bool {
+        self.needed(img_idx) > 0 || self.free_cells > self.total_needed
+    }
+
+    /// Records that image `img_idx` filled a cell.
+    pub(crate) fn fill(&mut self, img_idx: usize) {
+        self.free_cells -= 1;
+
+        if let Some(input) = self.img_inputs[img_idx].filter(|&input| self.needed[input] > 0) {
+            self.needed[input] -= 1;
+            self.total_needed -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs(quotas: &[Option<f32>]) -> Vec<TileInput> {
+        quotas
+            .iter()
+            .enumerate()
+            .map(|(i, &quota)| TileInput {
+                path: format!("input{i}").into(),
+                quota,
+            })
+            .collect()
+    }
+
+    /// Fills `n_cells` cells with the first image the quotas allow, images may be used once.
+    fn fill_greedily(quotas: &mut Quotas, n_imgs: usize, n_cells: usize) -> Vec<usize> {
+        let mut used = vec![false; n_imgs];
+        (0..n_cells)
+            .map(|_| {
+                let img_idx = (0..n_imgs)
+                    .find(|&idx| !used[idx] && quotas.allows(idx))
+                    .expect("the quotas allow an image for every cell");
+                used[img_idx] = true;
+                quotas.fill(img_idx);
+                img_idx
+            })
+            .collect()
+    }
+
+    #[test]
+    fn needed_cells_rounds_up_and_is_lowered_to_available() {
+        let inputs = inputs(&[Some(0.31), Some(0.5), None]);
+        let img_inputs = [vec![Some(0); 5], vec![Some(1); 2], vec![Some(2); 5]].concat();
+
+        let needed = needed_cells(&inputs, &img_inputs, 10, &Progress::new(None));
+
+        assert_eq!(needed, vec![4, 2, 0]);
+    }
+
+    #[test]
+    fn rounded_quotas_may_exceed_free_cells() {
+        // 50% of 5 cells rounds up to 3 cells for both inputs, 6 cells in total
+        let inputs = inputs(&[Some(0.5), Some(0.5), None]);
+        let img_inputs = vec![
+            Some(2),
+            Some(0),
+            Some(0),
+            Some(0),
+            Some(1),
+            Some(1),
+            Some(1),
+        ];
+
+        let needed = needed_cells(&inputs, &img_inputs, 5, &Progress::new(None));
+        assert_eq!(needed, vec![3, 3, 0]);
+
+        // every cell is still filled, by whichever quota comes first, and images of inputs
+        // without a quota are never picked
+        let mut quotas = Quotas::new(img_inputs, needed, 5);
+        assert!(!quotas.allows(0));
+        assert_eq!(fill_greedily(&mut quotas, 7, 5), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn images_are_reused_through_duplicated_pool_entries_only() {
+        let inputs = inputs(&[Some(0.75), None]);
+
+        // two images of every input, the pool is duplicated to fill 8 cells
+        let pool = vec![Some(0), Some(0), Some(1), Some(1)];
+        let img_inputs = [pool.clone(), pool].concat();
+
+        // the duplicates count as tiles of the input, as the same image can fill another cell
+        let needed = needed_cells(&inputs, &img_inputs, 8, &Progress::new(None));
+        assert_eq!(needed, vec![4, 0]);
+
+        // without the duplicates the quota is lowered to the two images of the input
+        let needed = needed_cells(&inputs, &img_inputs[..4], 8, &Progress::new(None));
+        assert_eq!(needed, vec![2, 0]);
+    }
+}
@@ -0,0 +1,25 @@
+use crate::{cluster::ClusterSearch, pyramid::Shortlist};
+
+/// Picks the images which are scored at full size for a cell, instead of all of them: the members
+/// of the nearest colour clusters, narrowed down further with the pyramid.
+pub(crate) struct CandidateSearch<'a> {
+    pub(crate) n_imgs: usize,
+    pub(crate) clusters: Option<ClusterSearch<'a>>,
+    pub(crate) shortlist: Option<Shortlist<'a>>,
+}
+
+impl CandidateSearch<'_> {
+    /// Indices of the images to score for `cell`, which contains the pixels of a `width` x
+    /// `height` cell as returned by [`cell_pixels`](crate::cell_pixels).
+    pub(crate) fn candidates(&self, cell: &[u8], width: u32, height: u32) -> Vec<usize> {
+        let candidates = match &self.clusters {
+            Some(clusters) => clusters.candidates(cell, width, height),
+            None => (0..self.n_imgs).collect(),
+        };
+
+        match &self.shortlist {
+            Some(shortlist) => shortlist.narrow(candidates, cell, width, height),
+            None => candidates,
+        }
+    }
+}
@@ -0,0 +1,812 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    thread,
+};
+
+use crossbeam::channel::Sender;
+use flate2::read::GzDecoder;
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelBridge,
+    ParallelIterator,
+};
+
+use crate::{CancellationToken, MakeImgOfImsOpts, ManifestSource, MosaicError};
+
+/// Maximum number of archive entries which are read but not yet decoded.
+const ENTRY_QUEUE_SIZE: usize = 64;
+
+/// Receives the contents of a file requested from a [`TileSource`], together with its index in the
+/// requested paths.
+pub type ReadFile<'a> = dyn Fn(usize, io::Result<Vec<u8>>) + Sync + 'a;
+
+/// Metadata of a tile by name, like the extra columns of a [`ManifestSource`].
+pub type TileMetadata = BTreeMap<String, String>;
+
+/// A file in a [`TileSource`].
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    /// The first bytes of the file, used to recognise its format
+    pub header: Vec<u8>,
+    /// Ends up in the [`TilePlacement`](crate::TilePlacement)s of the tile
+    pub metadata: TileMetadata,
+}
+
+/// Where the images of a [`TileSet`](crate::TileSet) are read from. Files in a source are
+/// identified by paths below its [`location`](TileSource::location), for archives these are the
+/// path of the archive joined with the path of the entry.
+pub trait TileSource: Sync {
+    /// The folder or archive
+    fn location(&self) -> &Path;
+
+    /// The files in the source for which `keep` returns true, with their first `header_len` bytes.
+    fn files(
+        &self,
+        header_len: u64,
+        keep: &(dyn Fn(&Path) -> bool + Sync),
+    ) -> Result<Vec<SourceFile>, MosaicError>;
+
+    /// Reads the files at `paths` and passes them to `read_file`, in any order and possibly from
+    /// multiple threads. Paths outside of the source are read from disk. Stops early when
+    /// `cancellation_token` is cancelled.
+    fn read(
+        &self,
+        paths: &[PathBuf],
+        cancellation_token: &CancellationToken,
+        read_file: &ReadFile,
+    ) -> Result<(), MosaicError>;
+}
+
+/// A folder, archive or manifest of tiles, see [`open_source`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileInput {
+    pub path: PathBuf,
+    /// Share of the cells, from 0 to 1, which is at least filled with tiles of this input, as far
+    /// as it has enough tiles. Pinned cells don't count
+    pub quota: Option<f32>,
+}
+
+impl TileInput {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            quota: None,
+        }
+    }
+}
+
+/// The source for `path`, which is either a folder, a zip, tar or tar.gz archive, or a manifest
+/// listing the images, see [`ManifestSource`]. Archives are recognised by their content,
+/// manifests by a `csv`, `txt` or `lst` extension, or otherwise as a text file which lists at
+/// least one existing file. Folders are searched as [`DirSource::from_opts`] does.
+pub fn open_source(
+    path: impl AsRef<Path>,
+    opts: &MakeImgOfImsOpts,
+) -> Result<Box<dyn TileSource>, MosaicError> {
+    let path = path.as_ref();
+
+    if !path.is_file() {
+        return Ok(Box::new(DirSource::from_opts(path, opts)));
+    }
+
+    let mut header = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(512).read_to_end(&mut header))
+        .map_err(|e| MosaicError::io(path, e))?;
+
+    if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+        Ok(Box::new(ZipSource::new(path)))
+    } else if header.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(TarSource::new(path, true)))
+    } else if header.get(257..262) == Some(b"ustar") {
+        Ok(Box::new(TarSource::new(path, false)))
+    } else if ManifestSource::has_extension(path) {
+        Ok(Box::new(ManifestSource::open(path)?))
+    } else if let Some(manifest) = (!header.contains(&0))
+        .then(|| ManifestSource::open(path).ok())
+        .flatten()
+        .filter(ManifestSource::lists_existing_file)
+    {
+        Ok(Box::new(manifest))
+    } else {
+        Err(MosaicError::invalid_option(
+            "input_dir",
+            format!("{path:?} is neither a folder, a zip or tar archive nor a list of images"),
+            "Use a folder of images, an archive of them or a file listing them",
+        ))
+    }
+}
+
+/// The archive `path` is in, when `path` is not a file itself.
+fn archive_of(path: &Path) -> Option<&Path> {
+    if path.is_file() {
+        return None;
+    }
+    path.ancestors().skip(1).find(|p| p.is_file())
+}
+
+/// Whether `path` is a file or may be a file in an archive, which is only known once the archive
+/// is read.
+pub(crate) fn is_file_or_archive_entry(path: &Path) -> bool {
+    path.is_file() || archive_of(path).is_some()
+}
+
+/// Reads the file at `path`, which may also be the path of a file in an archive as listed by
+/// [`TileSource::files`]. The archive is searched on every call.
+pub(crate) fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("{path:?} not found"));
+    let invalid = |e: MosaicError| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+
+    let archive = match archive_of(path) {
+        Some(archive) => archive,
+        None => return fs::read(path),
+    };
+    let source = open_source(archive, &MakeImgOfImsOpts::default()).map_err(invalid)?;
+
+    let data = Mutex::new(None);
+    // stops reading the archive once the file is found
+    let found = CancellationToken::new();
+    source
+        .read(&[path.to_owned()], &found, &|_, read| {
+            *data.lock().unwrap() = Some(read);
+            found.cancel();
+        })
+        .map_err(invalid)?;
+
+    let data = data.into_inner().unwrap();
+    data.unwrap_or_else(|| Err(not_found()))
+}
+
+/// Reads the files at `paths` like [`read_file`] and passes them to `read_file` like
+/// [`TileSource::read`]. Every archive the files are in is searched once for all of them. Files
+/// which are in none of the archives are not passed to `read_file`.
+pub(crate) fn read_files(
+    paths: &[PathBuf],
+    cancellation_token: &CancellationToken,
+    read_file: &ReadFile,
+) -> Result<(), MosaicError> {
+    let mut archives: Vec<&Path> = paths.iter().filter_map(|path| archive_of(path)).collect();
+    archives.sort();
+    archives.dedup();
+
+    let sources = archives
+        .into_iter()
+        .map(|archive| open_source(archive, &MakeImgOfImsOpts::default()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sources: Vec<&dyn TileSource> = sources.iter().map(|source| source.as_ref()).collect();
+
+    read_from_sources(&sources, paths, cancellation_token, read_file)
+}
+
+/// Reads `paths` like [`TileSource::read`], each from the source with the most specific location
+/// it is in, so files in an archive inside of a folder are read from the archive.
+pub(crate) fn read_from_sources(
+    sources: &[&dyn TileSource],
+    paths: &[PathBuf],
+    cancellation_token: &CancellationToken,
+    read_file: &ReadFile,
+) -> Result<(), MosaicError> {
+    let mut sources = sources.to_vec();
+    sources.sort_by_key(|source| std::cmp::Reverse(source.location().components().count()));
+
+    let mut claimed = vec![false; paths.len()];
+    for source in sources {
+        let idxs: Vec<usize> = (0..paths.len())
+            .filter(|&idx| !claimed[idx] && paths[idx].starts_with(source.location()))
+            .collect();
+        if idxs.is_empty() {
+            continue;
+        }
+
+        let source_paths: Vec<PathBuf> = idxs.iter().map(|&idx| paths[idx].clone()).collect();
+        source.read(&source_paths, cancellation_token, &|k, data| {
+            read_file(idxs[k], data)
+        })?;
+
+        for idx in idxs {
+            claimed[idx] = true;
+        }
+    }
+
+    // files in archives outside of the sources, like pinned images, are read from their archive
+    let (in_archives, on_disk): (Vec<usize>, Vec<usize>) = (0..paths.len())
+        .filter(|&idx| !claimed[idx])
+        .partition(|&idx| archive_of(&paths[idx]).is_some());
+
+    if !in_archives.is_empty() {
+        let archive_paths: Vec<PathBuf> =
+            in_archives.iter().map(|&idx| paths[idx].clone()).collect();
+        read_files(&archive_paths, cancellation_token, &|k, data| {
+            read_file(in_archives[k], data)
+        })?;
+    }
+
+    let disk_paths: Vec<PathBuf> = on_disk.iter().map(|&idx| paths[idx].clone()).collect();
+    read_from_disk(&disk_paths, cancellation_token, &|k, data| {
+        read_file(on_disk[k], data)
+    });
+
+    Ok(())
+}
+
+/// Reads the files at `paths` from disk in parallel, like [`TileSource::read`].
+pub(crate) fn read_from_disk(
+    paths: &[PathBuf],
+    cancellation_token: &CancellationToken,
+    read_file: &ReadFile,
+) {
+    paths.par_iter().enumerate().for_each(|(idx, path)| {
+        if !cancellation_token.is_cancelled() {
+            read_file(idx, fs::read(path));
+        }
+    });
+}
+
+/// Whether a file or folder named `name` is hidden, which is the case when its name starts with a
+/// dot.
+pub(crate) fn is_hidden(name: &OsStr) -> bool {
+    name.to_string_lossy().starts_with('.')
+}
+
+pub(crate) fn read_header(reader: impl Read, header_len: u64) -> io::Result<Vec<u8>> {
+    let mut header = Vec::new();
+    reader.take(header_len).read_to_end(&mut header)?;
+    Ok(header)
+}
+
+/// The images in a folder and its subfolders.
+#[derive(Debug, Clone)]
+pub struct DirSource {
+    dir: PathBuf,
+    max_depth: Option<usize>,
+    skip_hidden: bool,
+    follow_symlinks: bool,
+}
+
+impl DirSource {
+    /// All files in `dir` and its subfolders, following symlinks.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            max_depth: None,
+            skip_hidden: false,
+            follow_symlinks: true,
+        }
+    }
+
+    /// The files in `dir` which are found with the `max_depth`, `skip_hidden` and
+    /// `follow_symlinks` of `opts`.
+    pub fn from_opts(dir: impl Into<PathBuf>, opts: &MakeImgOfImsOpts) -> Self {
+        Self {
+            dir: dir.into(),
+            max_depth: opts.max_depth,
+            skip_hidden: opts.skip_hidden,
+            follow_symlinks: opts.follow_symlinks,
+        }
+    }
+}
+
+impl TileSource for DirSource {
+    fn location(&self) -> &Path {
+        &self.dir
+    }
+
+    fn files(
+        &self,
+        header_len: u64,
+        keep: &(dyn Fn(&Path) -> bool + Sync),
+    ) -> Result<Vec<SourceFile>, MosaicError> {
+        let mut walk = walkdir::WalkDir::new(&self.dir)
+            .follow_links(self.follow_symlinks)
+            .sort_by_file_name();
+        if let Some(max_depth) = self.max_depth {
+            // the folder itself is at depth 0
+            walk = walk.max_depth(max_depth + 1);
+        }
+
+        // hidden folders are not searched at all, unreadable entries and loops are skipped
+        let paths: Vec<PathBuf> = walk
+            .into_iter()
+            .filter_entry(|entry| {
+                !(self.skip_hidden && entry.depth() > 0 && is_hidden(entry.file_name()))
+            })
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .map(walkdir::DirEntry::into_path)
+            .filter(|path| keep(path))
+            .collect();
+
+        // unreadable files get an empty header, and are skipped as they are no images
+        Ok(paths
+            .into_par_iter()
+            .map(|path| {
+                let header = File::open(&path)
+                    .and_then(|file| read_header(file, header_len))
+                    .unwrap_or_default();
+                SourceFile {
+                    path,
+                    header,
+                    metadata: TileMetadata::new(),
+                }
+            })
+            .collect())
+    }
+
+    fn read(
+        &self,
+        paths: &[PathBuf],
+        cancellation_token: &CancellationToken,
+        read_file: &ReadFile,
+    ) -> Result<(), MosaicError> {
+        read_from_disk(paths, cancellation_token, read_file);
+        Ok(())
+    }
+}
+
+/// Path of an entry of the archive at `archive`, `None` for entries which would point outside of
+/// the archive.
+fn entry_path(archive: &Path, entry: &Path) -> Option<PathBuf> {
+    let is_enclosed = entry
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+
+    is_enclosed.then(|| archive.join(entry))
+}
+
+/// Reads files one by one with `produce` on a separate thread, while the files it sends are passed
+/// to `read_file` on the threads of the current pool.
+fn read_sequentially(
+    produce: impl FnOnce(&Sender<(usize, io::Result<Vec<u8>>)>) -> Result<(), MosaicError> + Send,
+    read_file: &ReadFile,
+) -> Result<(), MosaicError> {
+    let (sender, receiver) = crossbeam::channel::bounded(ENTRY_QUEUE_SIZE);
+
+    thread::scope(|s| {
+        let producer = s.spawn(move || produce(&sender));
+
+        receiver
+            .into_iter()
+            .par_bridge()
+            .for_each(|(idx, data)| read_file(idx, data));
+
+        producer.join().expect("reading the archive does not panic")
+    })
+}
+
+/// Requested paths which are in the archive at `archive`, by path, and the indices of the others.
+fn split_paths<'a>(archive: &Path, paths: &'a [PathBuf]) -> (HashMap<&'a Path, usize>, Vec<usize>) {
+    let mut inside = HashMap::new();
+    let mut outside = Vec::new();
+
+    for (idx, path) in paths.iter().enumerate() {
+        if path.starts_with(archive) && path != archive {
+            inside.insert(path.as_path(), idx);
+        } else {
+            outside.push(idx);
+        }
+    }
+
+    (inside, outside)
+}
+
+/// The images in a zip archive.
+#[derive(Debug, Clone)]
+pub struct ZipSource {
+    path: PathBuf,
+}
+
+impl ZipSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn open(&self) -> Result<zip::ZipArchive<BufReader<File>>, MosaicError> {
+        let file = File::open(&self.path).map_err(|e| MosaicError::io(&self.path, e))?;
+        zip::ZipArchive::new(BufReader::new(file)).map_err(|e| self.error(e))
+    }
+
+    /// The path of `entry`, see [`entry_path`].
+    fn entry_path(&self, entry: &zip::read::ZipFile) -> Option<PathBuf> {
+        entry
+            .enclosed_name()
+            .and_then(|name| entry_path(&self.path, name))
+    }
+
+    fn error(&self, e: zip::result::ZipError) -> MosaicError {
+        match e {
+            zip::result::ZipError::Io(e) => MosaicError::io(&self.path, e),
+            e => MosaicError::io(&self.path, io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+impl TileSource for ZipSource {
+    fn location(&self) -> &Path {
+        &self.path
+    }
+
+    fn files(
+        &self,
+        header_len: u64,
+        keep: &(dyn Fn(&Path) -> bool + Sync),
+    ) -> Result<Vec<SourceFile>, MosaicError> {
+        let mut archive = self.open()?;
+        let mut files = Vec::new();
+
+        for idx in 0..archive.len() {
+            let entry = archive.by_index(idx).map_err(|e| self.error(e))?;
+            if !entry.is_file() {
+                continue;
+            }
+
+            let path = match self.entry_path(&entry) {
+                Some(path) => path,
+                None => continue,
+            };
+            if !keep(&path) {
+                continue;
+            }
+
+            let header = read_header(entry, header_len).map_err(|e| MosaicError::io(&path, e))?;
+            files.push(SourceFile {
+                path,
+                header,
+                metadata: TileMetadata::new(),
+            });
+        }
+
+        Ok(files)
+    }
+
+    fn read(
+        &self,
+        paths: &[PathBuf],
+        cancellation_token: &CancellationToken,
+        read_file: &ReadFile,
+    ) -> Result<(), MosaicError> {
+        let (inside, outside) = split_paths(&self.path, paths);
+
+        read_sequentially(
+            |sender| {
+                for idx in outside {
+                    let _ = sender.send((idx, fs::read(&paths[idx])));
+                }
+
+                let mut archive = self.open()?;
+                for entry_idx in 0..archive.len() {
+                    if cancellation_token.is_cancelled() {
+                        break;
+                    }
+
+                    let mut entry = archive.by_index(entry_idx).map_err(|e| self.error(e))?;
+                    let idx = match self.entry_path(&entry) {
+                        Some(path) if entry.is_file() => inside.get(path.as_path()).copied(),
+                        _ => None,
+                    };
+
+                    if let Some(idx) = idx {
+                        let mut data = Vec::with_capacity(entry.size() as usize);
+                        let data = entry.read_to_end(&mut data).map(|_| data);
+                        let _ = sender.send((idx, data));
+                    }
+                }
+
+                Ok(())
+            },
+            read_file,
+        )
+    }
+}
+
+/// The images in a tar archive, which may be compressed with gzip. The archive can only be read
+/// from start to end, so it is read once to list its files and once to read them.
+#[derive(Debug, Clone)]
+pub struct TarSource {
+    path: PathBuf,
+    gzip: bool,
+}
+
+impl TarSource {
+    pub fn new(path: impl Into<PathBuf>, gzip: bool) -> Self {
+        Self {
+            path: path.into(),
+            gzip,
+        }
+    }
+
+    /// Calls `f` for every regular file in the archive, with its path, until `f` returns false.
+    fn for_each_file(
+        &self,
+        mut f: impl FnMut(PathBuf, &mut dyn Read) -> io::Result<bool>,
+    ) -> Result<(), MosaicError> {
+        let io_error = |e| MosaicError::io(&self.path, e);
+
+        let file = BufReader::new(File::open(&self.path).map_err(io_error)?);
+        let reader: Box<dyn Read> = if self.gzip {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries().map_err(io_error)? {
+            let mut entry = entry.map_err(io_error)?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let path = match entry_path(&self.path, &entry.path().map_err(io_error)?) {
+                Some(path) => path,
+                None => continue,
+            };
+
+            if !f(path, &mut entry).map_err(io_error)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl TileSource for TarSource {
+    fn location(&self) -> &Path {
+        &self.path
+    }
+
+    fn files(
+        &self,
+        header_len: u64,
+        keep: &(dyn Fn(&Path) -> bool + Sync),
+    ) -> Result<Vec<SourceFile>, MosaicError> {
+        let mut files = Vec::new();
+
+        self.for_each_file(|path, entry| {
+            if keep(&path) {
+                let header = read_header(entry, header_len)?;
+                files.push(SourceFile {
+                    path,
+                    header,
+                    metadata: TileMetadata::new(),
+                });
+            }
+            Ok(true)
+        })?;
+
+        Ok(files)
+    }
+
+    fn read(
+        &self,
+        paths: &[PathBuf],
+        cancellation_token: &CancellationToken,
+        read_file: &ReadFile,
+    ) -> Result<(), MosaicError> {
+        let (inside, outside) = split_paths(&self.path, paths);
+
+        read_sequentially(
+            |sender| {
+                for idx in outside {
+                    let _ = sender.send((idx, fs::read(&paths[idx])));
+                }
+
+                self.for_each_file(|path, entry| {
+                    if let Some(&idx) = inside.get(path.as_path()) {
+                        let mut data = Vec::new();
+                        let data = entry.read_to_end(&mut data).map(|_| data);
+                        let _ = sender.send((idx, data));
+                    }
+                    Ok(!cancellation_token.is_cancelled())
+                })
+            },
+            read_file,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_paths(source: &dyn TileSource) -> Vec<PathBuf> {
+        source
+            .files(0, &|_| true)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path)
+            .collect()
+    }
+
+    #[test]
+    fn open_source_recognises_manifests_and_folders() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::create_dir(dir.join("tiles")).unwrap();
+        for name in ["a.png", "tiles/b.png", "tiles/c.png"] {
+            fs::write(dir.join(name), b"image").unwrap();
+        }
+        fs::write(dir.join("list.txt"), "# tiles\na.png\n").unwrap();
+        fs::write(dir.join("list.csv"), "caption,path\nb,tiles/b.png\n").unwrap();
+
+        let opts = MakeImgOfImsOpts::default();
+        let source = open_source(dir.join("list.txt"), &opts).unwrap();
+        assert_eq!(file_paths(source.as_ref()), vec![dir.join("a.png")]);
+
+        let source = open_source(dir.join("list.csv"), &opts).unwrap();
+        let files = source.files(0, &|_| true).unwrap();
+        assert_eq!(files[0].path, dir.join("tiles/b.png"));
+        assert_eq!(files[0].metadata["caption"], "b");
+
+        let source = open_source(dir.join("tiles"), &opts).unwrap();
+        assert_eq!(
+            file_paths(source.as_ref()),
+            vec![dir.join("tiles/b.png"), dir.join("tiles/c.png")]
+        );
+    }
+
+    #[test]
+    fn open_source_recognises_manifests_by_extension_or_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::write(dir.join("a.png"), b"image").unwrap();
+        fs::write(
+            dir.join("LIST.LST"),
+            "missing.png
+",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("tiles"),
+            "missing.png
+a.png
+",
+        )
+        .unwrap();
+
+        let opts = MakeImgOfImsOpts::default();
+        let source = open_source(dir.join("LIST.LST"), &opts).unwrap();
+        assert_eq!(source.location(), dir.join("LIST.LST"));
+
+        let source = open_source(dir.join("tiles"), &opts).unwrap();
+        assert_eq!(source.location(), dir.join("tiles"));
+    }
+
+    #[test]
+    fn open_source_rejects_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::write(dir.join("tiles.bin"), [0x00, 0x01, 0x02, 0x00]).unwrap();
+        // text which doesn't list any existing file, like an image in a text based format
+        fs::write(
+            dir.join("drawing.svg"),
+            "<svg></svg>
+",
+        )
+        .unwrap();
+        fs::write(dir.join("notes"), "").unwrap();
+
+        for name in ["tiles.bin", "drawing.svg", "notes"] {
+            let result = open_source(dir.join(name), &MakeImgOfImsOpts::default());
+            assert!(
+                matches!(result, Err(MosaicError::InvalidOptions { .. })),
+                "{name}: {:?}",
+                result.err()
+            );
+        }
+    }
+
+    /// Entries of the test archives, of which the last three point outside of the archive.
+    const ENTRIES: [(&str, &[u8]); 5] = [
+        ("a.png", b"a"),
+        ("sub/b.png", b"b"),
+        ("../evil.png", b"evil"),
+        ("/abs.png", b"abs"),
+        ("sub/../../c.png", b"c"),
+    ];
+
+    fn write_zip(path: &Path) {
+        use std::io::Write;
+
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, data) in ENTRIES {
+            zip.start_file(name, Default::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn write_tar(writer: impl io::Write) {
+        let mut tar = tar::Builder::new(writer);
+        for (name, data) in ENTRIES {
+            // `set_path` refuses paths outside of the archive, which is what is tested
+            let mut header = tar::Header::new_ustar();
+            header.as_ustar_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append(&header, data).unwrap();
+        }
+        tar.finish().unwrap();
+    }
+
+    /// The test archives in `dir`: zip, tar and tar.gz.
+    fn archives(dir: &Path) -> Vec<Box<dyn TileSource>> {
+        write_zip(&dir.join("tiles.zip"));
+        write_tar(File::create(dir.join("tiles.tar")).unwrap());
+        write_tar(flate2::write::GzEncoder::new(
+            File::create(dir.join("tiles.tar.gz")).unwrap(),
+            flate2::Compression::default(),
+        ));
+
+        ["tiles.zip", "tiles.tar", "tiles.tar.gz"]
+            .into_iter()
+            .map(|name| open_source(dir.join(name), &MakeImgOfImsOpts::default()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn entry_path_rejects_paths_outside_of_archive() {
+        let archive = Path::new("/tiles/tiles.zip");
+
+        assert_eq!(
+            entry_path(archive, Path::new("sub/./a.png")),
+            Some(archive.join("sub/a.png"))
+        );
+        for entry in ["../a.png", "/a.png", "sub/../a.png", "sub/../../a.png"] {
+            assert_eq!(entry_path(archive, Path::new(entry)), None, "{entry}");
+        }
+    }
+
+    #[test]
+    fn archives_skip_entries_outside_of_archive() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for source in archives(dir.path()) {
+            let archive = source.location();
+            assert_eq!(
+                file_paths(source.as_ref()),
+                vec![archive.join("a.png"), archive.join("sub/b.png")],
+                "{archive:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn archives_read_the_files_they_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside.png");
+        fs::write(&outside, b"outside").unwrap();
+
+        for source in archives(dir.path()) {
+            let archive = source.location();
+            let mut paths = file_paths(source.as_ref());
+            paths.push(outside.clone());
+            // the escaping entries can't be read by the path they would get either
+            paths.push(archive.join("../evil.png"));
+
+            let read = Mutex::new(vec![None; paths.len()]);
+            source
+                .read(&paths, &CancellationToken::new(), &|idx, data| {
+                    read.lock().unwrap()[idx] = data.ok();
+                })
+                .unwrap();
+
+            let read = read.into_inner().unwrap();
+            assert_eq!(
+                read,
+                vec![
+                    Some(b"a".to_vec()),
+                    Some(b"b".to_vec()),
+                    Some(b"outside".to_vec()),
+                    None
+                ],
+                "{archive:?}"
+            );
+        }
+    }
+}
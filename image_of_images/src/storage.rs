@@ -0,0 +1,199 @@
+use std::{
+    hash::{Hash, Hasher},
+    io::{self, Read, Write},
+    mem,
+};
+
+use image::{ImageBuffer, Rgb, RgbImage};
+
+/// A tile borrowed from a [`TileStore`].
+pub(crate) type TileView<'a> = ImageBuffer<Rgb<u8>, &'a [u8]>;
+
+/// Tiles with 8 bit RGB pixels stored back to back in a single buffer. This takes a quarter of
+/// the memory of separate `f32` buffers, pixels are converted when tiles are scored or inserted.
+#[derive(Debug, Default)]
+pub(crate) struct TileStore {
+    data: Vec<u8>,
+    /// Offset into `data`, width and height of every tile
+    tiles: Vec<(usize, u32, u32)>,
+}
+
+impl TileStore {
+    pub(crate) fn push(&mut self, tile: &RgbImage) {
+        let (width, height) = tile.dimensions();
+        self.tiles.push((self.data.len(), width, height));
+        self.data.extend_from_slice(tile.as_raw());
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub(crate) fn dimensions(&self, idx: usize) -> (u32, u32) {
+        let (_, width, height) = self.tiles[idx];
+        (width, height)
+    }
+
+    pub(crate) fn get(&self, idx: usize) -> TileView<'_> {
+        let (offset, width, height) = self.tiles[idx];
+        let len = width as usize * height as usize * 3;
+
+        ImageBuffer::from_raw(width, height, &self.data[offset..offset + len])
+            .expect("tile data matches its dimensions")
+    }
+
+    /// Reorders the tiles such that tile `i` becomes the tile which was at `order[i]`.
+    pub(crate) fn reorder(&mut self, order: &[usize]) {
+        self.tiles = order.iter().map(|&idx| self.tiles[idx]).collect();
+    }
+
+    /// Bytes allocated for the tiles.
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.data.capacity() + self.tiles.capacity() * mem::size_of::<(usize, u32, u32)>()
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.data.shrink_to_fit();
+        self.tiles.shrink_to_fit();
+    }
+
+    /// Writes the tiles in the order they are stored in, to be read back with
+    /// [`TileStore::read_from`].
+    pub(crate) fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(&(self.tiles.len() as u64).to_le_bytes())?;
+        for idx in 0..self.len() {
+            let (width, height) = self.dimensions(idx);
+            w.write_all(&width.to_le_bytes())?;
+            w.write_all(&height.to_le_bytes())?;
+            w.write_all(self.get(idx).as_raw())?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        let mut store = Self::default();
+
+        for _ in 0..read_u64(r)? {
+            let width = read_u32(r)?;
+            let height = read_u32(r)?;
+            let len = width as usize * height as usize * 3;
+
+            store.tiles.push((store.data.len(), width, height));
+            let start = store.data.len();
+            store.data.resize(start + len, 0);
+            r.read_exact(&mut store.data[start..])?;
+        }
+
+        store.shrink_to_fit();
+        Ok(store)
+    }
+}
+
+/// Hashes the tiles in order, regardless of where they are in the buffer.
+impl Hash for TileStore {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for idx in 0..self.len() {
+            self.dimensions(idx).hash(state);
+            state.write(self.get(idx).as_raw());
+        }
+    }
+}
+
+pub(crate) fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub(crate) fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+impl FromIterator<RgbImage> for TileStore {
+    fn from_iter<T: IntoIterator<Item = RgbImage>>(iter: T) -> Self {
+        let mut store = Self::default();
+        for tile in iter {
+            store.push(&tile);
+        }
+        store.shrink_to_fit();
+        store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tiles of different sizes, whose pixels differ from those of their neighbours.
+    fn tiles() -> Vec<RgbImage> {
+        [(4, 4), (1, 1), (3, 5), (8, 2)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (width, height))| {
+                RgbImage::from_fn(width, height, |x, y| {
+                    Rgb([i as u8 * 60, x as u8 * 10, y as u8 * 10])
+                })
+            })
+            .collect()
+    }
+
+    fn assert_tiles(store: &TileStore, tiles: &[RgbImage]) {
+        assert_eq!(store.len(), tiles.len());
+        for (idx, tile) in tiles.iter().enumerate() {
+            assert_eq!(store.dimensions(idx), tile.dimensions(), "{idx}");
+            assert_eq!(store.get(idx).as_raw(), tile.as_raw(), "{idx}");
+        }
+    }
+
+    #[test]
+    fn get_returns_each_tile_up_to_its_neighbours() {
+        let tiles = tiles();
+        let store: TileStore = tiles.iter().cloned().collect();
+        assert_tiles(&store, &tiles);
+
+        // the last tile ends exactly at the end of the buffer
+        let (offset, width, height) = store.tiles[3];
+        assert_eq!(
+            offset + width as usize * height as usize * 3,
+            store.data.len()
+        );
+
+        let mut reordered = store;
+        reordered.reorder(&[3, 1, 0, 2]);
+        let order = [3, 1, 0, 2].map(|idx| tiles[idx].clone());
+        assert_tiles(&reordered, &order);
+    }
+
+    #[test]
+    fn write_and_read_round_trip() {
+        let tiles = tiles();
+        let mut store: TileStore = tiles.iter().cloned().collect();
+        // written in the order of the tiles, not of the buffer
+        store.reorder(&[2, 0, 3, 1]);
+
+        let mut buf = Vec::new();
+        store.write_to(&mut buf).unwrap();
+        let read = TileStore::read_from(&mut buf.as_slice()).unwrap();
+
+        let order = [2, 0, 3, 1].map(|idx| tiles[idx].clone());
+        assert_tiles(&read, &order);
+
+        // a truncated file is an error
+        let result = TileStore::read_from(&mut &buf[..buf.len() - 1]);
+        assert_eq!(
+            result.map(|_| ()).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn memory_usage_is_the_pixels_and_their_positions() {
+        let store: TileStore = (0..10).map(|_| RgbImage::new(16, 8)).collect();
+
+        let entry = mem::size_of::<(usize, u32, u32)>();
+        assert_eq!(store.memory_usage(), 16 * 8 * 3 * 10 + entry * 10);
+    }
+}
@@ -0,0 +1,52 @@
+use std::{
+    fs::{self, File},
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+
+pub(crate) fn solid(width: u32, height: u32, colour: [u8; 3]) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb(colour)))
+}
+
+/// Grey level of image `i` of `n` images, different for every image.
+pub(crate) fn grey(i: usize, n: usize) -> [u8; 3] {
+    [(i * 255 / n.max(1)) as u8; 3]
+}
+
+/// Writes `n` solid `size` x `size` PNG images of different greys, named `0.png`, `1.png` and so
+/// on, to `dir`, which is created when it doesn't exist. Returns their paths.
+pub(crate) fn write_solid_pngs(dir: &Path, n: usize, size: u32) -> Vec<PathBuf> {
+    fs::create_dir_all(dir).unwrap();
+
+    (0..n)
+        .map(|i| {
+            let path = dir.join(format!("{i}.png"));
+            solid(size, size, grey(i, n)).save(&path).unwrap();
+            path
+        })
+        .collect()
+}
+
+/// Like [`write_solid_pngs`], but writes the images to the zip archive `path`. Returns the paths
+/// of the images in the archive.
+pub(crate) fn write_solid_png_zip(path: &Path, n: usize, size: u32) -> Vec<PathBuf> {
+    let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+
+    let paths = (0..n)
+        .map(|i| {
+            let mut png = Vec::new();
+            solid(size, size, grey(i, n))
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .unwrap();
+            zip.start_file(format!("{i}.png"), Default::default())
+                .unwrap();
+            zip.write_all(&png).unwrap();
+            path.join(format!("{i}.png"))
+        })
+        .collect();
+
+    zip.finish().unwrap();
+    paths
+}
@@ -0,0 +1,442 @@
+use image::{DynamicImage, GenericImageView};
+
+use crate::{
+    find_all_imgs, glob_pattern, open_source, pinned_cell_problem, source, target_dimensions,
+    AutoGridSize, FoundImgs, MakeImgOfImsOpts, MosaicError, OptionProblem, TileInput, TileSource,
+};
+
+/// The longest side of the finest level is then 128 pixels, finer levels cost more than they save.
+const MAX_PYRAMID_LEVELS: usize = 6;
+
+/// The images in the inputs, found by [`MakeImgOfImsOpts::validate`]. Loaded with
+/// [`TileSet::from_found`](crate::TileSet::from_found), such that the inputs are only searched
+/// once, which matters for large archives.
+pub struct FoundInputs {
+    pub(crate) inputs: Vec<TileInput>,
+    pub(crate) sources: Vec<Box<dyn TileSource>>,
+    /// The images of every source
+    pub(crate) found: Vec<FoundImgs>,
+}
+
+impl MakeImgOfImsOpts {
+    /// Checks the options against every image of `target_imgs` and the images in `inputs`, before
+    /// any heavy work is done. Reports every problem found, each with a suggested fix. The inputs
+    /// are searched once, the images found in them can be loaded with
+    /// [`TileSet::from_found`](crate::TileSet::from_found).
+    pub fn validate(
+        &self,
+        target_imgs: &[DynamicImage],
+        inputs: &[TileInput],
+    ) -> Result<FoundInputs, MosaicError> {
+        let mut problems: Vec<OptionProblem> = Vec::new();
+        for target_img in target_imgs {
+            // most problems don't depend on the target, these are reported once
+            for problem in self.problems(target_img) {
+                if !problems.contains(&problem) {
+                    problems.push(problem);
+                }
+            }
+        }
+
+        let patterns_are_valid = !problems
+            .iter()
+            .any(|p| p.option == "include" || p.option == "exclude");
+
+        let mut found_inputs = FoundInputs {
+            inputs: inputs.to_vec(),
+            sources: Vec::new(),
+            found: Vec::new(),
+        };
+
+        for input in inputs {
+            if let Some(quota) = input.quota.filter(|q| !(0.0..=1.0).contains(q)) {
+                problems.push(OptionProblem::new(
+                    "input_dir",
+                    format!(
+                        "The quota {quota} of {:?} is not between 0 and 1",
+                        input.path
+                    ),
+                    "Use e.g. 0.3 to fill at least 30% of the cells with its images",
+                ));
+            }
+
+            if !input.path.exists() {
+                problems.push(OptionProblem::new(
+                    "input_dir",
+                    format!("{:?} does not exist", input.path),
+                    "Check the path of the folder, archive or list of images",
+                ));
+            } else if patterns_are_valid {
+                let found = open_source(&input.path, self).and_then(|source| {
+                    let found = find_all_imgs(source.as_ref(), self)?;
+                    Ok((source, found))
+                });
+
+                match found {
+                    Ok((_, found)) if found.imgs.is_empty() => problems.push(OptionProblem::new(
+                        "input_dir",
+                        format!("No images found in {:?}", input.path),
+                        "Add images to the folder, or relax `include`, `exclude` or `max_depth`",
+                    )),
+                    Ok((source, found)) => {
+                        found_inputs.sources.push(source);
+                        found_inputs.found.push(found);
+                    }
+                    Err(MosaicError::InvalidOptions { problems: p }) => problems.extend(p),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        let total_quota: f32 = inputs.iter().filter_map(|input| input.quota).sum();
+        if total_quota > 1.0 {
+            problems.push(OptionProblem::new(
+                "input_dir",
+                format!("The quotas add up to {total_quota}, more than all cells"),
+                "Lower the quotas such that they add up to at most 1",
+            ));
+        }
+
+        if problems.is_empty() {
+            Ok(found_inputs)
+        } else {
+            Err(MosaicError::InvalidOptions { problems })
+        }
+    }
+
+    /// Problems with the options which do not depend on the input images.
+    pub(crate) fn problems(&self, target_img: &DynamicImage) -> Vec<OptionProblem> {
+        let mut problems = Vec::new();
+
+        if self.max_imgs == Some(0) {
+            problems.push(OptionProblem::new(
+                "max_imgs",
+                "Must be at least 1",
+                "Leave it empty to use all images",
+            ));
+        }
+
+        if self.num_threads == Some(0) {
+            problems.push(OptionProblem::new(
+                "num_threads",
+                "Must be at least 1",
+                "Leave it empty to use all cores",
+            ));
+        }
+
+        if self.load_queue_size == 0 {
+            problems.push(OptionProblem::new(
+                "load_queue_size",
+                "Must be at least 1",
+                "Use e.g. a queue of 256 tiles",
+            ));
+        }
+
+        if self.pyramid_levels > MAX_PYRAMID_LEVELS {
+            problems.push(OptionProblem::new(
+                "pyramid_levels",
+                format!("At most {MAX_PYRAMID_LEVELS} levels are supported"),
+                "Use e.g. 2 levels, of at most 4 and 8 pixels",
+            ));
+        }
+
+        if self.shortlist_size == Some(0) {
+            problems.push(OptionProblem::new(
+                "shortlist_size",
+                "Must be at least 1",
+                "Leave it empty to score all images at full size",
+            ));
+        }
+
+        if self.match_tile_size == 0 {
+            problems.push(OptionProblem::new(
+                "match_tile_size",
+                "Must be at least 1",
+                "Use e.g. cells of 64 pixels",
+            ));
+        }
+
+        if self.num_clusters == Some(0) {
+            problems.push(OptionProblem::new(
+                "num_clusters",
+                "Must be at least 1",
+                "Leave it empty to score all images, or use e.g. 64 clusters",
+            ));
+        }
+
+        if self.num_clusters.is_some() && self.clusters_searched == 0 {
+            problems.push(OptionProblem::new(
+                "clusters_searched",
+                "Must be at least 1",
+                "Search e.g. the 3 nearest clusters",
+            ));
+        }
+
+        if self.resume && self.job_dir.is_none() {
+            problems.push(OptionProblem::new(
+                "resume",
+                "Needs the folder of the job to resume",
+                "Set `job_dir` to the folder the job was started with",
+            ));
+        }
+
+        for (option, patterns) in [("include", &self.include), ("exclude", &self.exclude)] {
+            for pattern in patterns {
+                if let Err(problem) = glob_pattern(pattern, option) {
+                    problems.push(problem);
+                }
+            }
+        }
+
+        for path in self.pinned_tiles.values() {
+            if !source::is_file_or_archive_entry(path) {
+                problems.push(OptionProblem::new(
+                    "pinned_tiles",
+                    format!("Pinned image {path:?} does not exist"),
+                    "Check the path of the pinned image",
+                ));
+            }
+        }
+
+        let n_problems = problems.len();
+
+        let (img_width, img_height) = target_img.dimensions();
+        if img_width == 0 || img_height == 0 {
+            problems.push(OptionProblem::new(
+                "target_img",
+                "The target image is empty",
+                "Use a different target image",
+            ));
+        }
+
+        for (option, value) in [
+            ("target_width", self.target_width),
+            ("target_height", self.target_height),
+        ] {
+            if value == Some(0) {
+                problems.push(OptionProblem::new(
+                    option,
+                    "Must be at least 1",
+                    "Leave it empty to keep the aspect ratio of the target",
+                ));
+            }
+        }
+
+        match self.auto_grid {
+            Some(auto_grid) => {
+                let (aspect_w, aspect_h) = auto_grid.tile_aspect_ratio;
+                if aspect_w == 0 || aspect_h == 0 {
+                    problems.push(OptionProblem::new(
+                        "auto_grid",
+                        format!("Invalid tile aspect ratio {aspect_w}:{aspect_h}"),
+                        "Both sides must be at least 1, e.g. 1:1 for square tiles",
+                    ));
+                }
+
+                match auto_grid.size {
+                    AutoGridSize::NumTiles(0) => problems.push(OptionProblem::new(
+                        "auto_grid",
+                        "The amount of tiles must be at least 1",
+                        "Use e.g. 1600 tiles",
+                    )),
+                    AutoGridSize::TileWidth(0) => problems.push(OptionProblem::new(
+                        "auto_grid",
+                        "The tile width must be at least 1",
+                        "Use e.g. a tile width of 25 pixels",
+                    )),
+                    _ => (),
+                }
+            }
+            None => {
+                for (option, value) in [
+                    ("num_horizontal_imgs", self.num_horizontal_imgs),
+                    ("num_vertical_imgs", self.num_vertical_imgs),
+                ] {
+                    if value == 0 {
+                        problems.push(OptionProblem::new(
+                            option,
+                            "Must be at least 1",
+                            "Use e.g. 40 images",
+                        ));
+                    }
+                }
+            }
+        }
+
+        // the grid can only be computed from valid dimensions
+        if problems.len() > n_problems {
+            return problems;
+        }
+
+        let (target_width, target_height) =
+            target_dimensions(img_width, img_height, self.target_width, self.target_height);
+
+        if let Some(AutoGridSize::TileWidth(w)) = self.auto_grid.map(|a| a.size) {
+            if w > target_width {
+                problems.push(OptionProblem::new(
+                    "auto_grid",
+                    format!(
+                        "A tile width of {w} is larger than the target width of {target_width}"
+                    ),
+                    format!(
+                        "Use a tile width of at most {target_width} or increase `target_width`"
+                    ),
+                ));
+            }
+        }
+
+        let grid = self.grid(target_width, target_height);
+
+        // cells would be smaller than a pixel, `Stretch` rounds them up to single pixel tiles
+        if grid.n_horizontal > target_width {
+            problems.push(OptionProblem::new(
+                "num_horizontal_imgs",
+                format!(
+                    "{} horizontal images do not fit in a target width of {target_width}",
+                    grid.n_horizontal
+                ),
+                format!("Use at most {target_width} horizontal images or increase `target_width`"),
+            ));
+        }
+
+        if grid.n_vertical > target_height {
+            problems.push(OptionProblem::new(
+                "num_vertical_imgs",
+                format!(
+                    "{} vertical images do not fit in a target height of {target_height}",
+                    grid.n_vertical
+                ),
+                format!("Use at most {target_height} vertical images or increase `target_height`"),
+            ));
+        }
+
+        for &(row, col) in self.pinned_tiles.keys() {
+            problems.extend(pinned_cell_problem(grid, row, col));
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        test_util::{write_solid_png_zip, write_solid_pngs},
+        FitMode, MosaicBuilder, TileSet,
+    };
+
+    #[test]
+    fn validate_finds_inputs_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let imgs_dir = dir.path().join("imgs");
+        let empty_dir = dir.path().join("empty");
+        write_solid_pngs(&imgs_dir, 3, 8);
+        fs::create_dir(&empty_dir).unwrap();
+
+        let targets = vec![
+            DynamicImage::new_rgb8(40, 30),
+            DynamicImage::new_rgb8(30, 40),
+        ];
+        let opts = MakeImgOfImsOpts {
+            max_imgs: Some(0),
+            ..Default::default()
+        };
+        let inputs = [TileInput::new(&imgs_dir), TileInput::new(&empty_dir)];
+
+        match opts.validate(&targets, &inputs) {
+            Err(MosaicError::InvalidOptions { problems }) => {
+                let options: Vec<_> = problems.iter().map(|p| p.option).collect();
+                assert_eq!(options, ["max_imgs", "input_dir"], "{problems:?}");
+            }
+            r => panic!("{:?}", r.err()),
+        }
+
+        let opts = MakeImgOfImsOpts::default();
+        let found = opts.validate(&targets, &inputs[..1]).unwrap();
+        let tile_set = TileSet::from_found(found, 8, &opts).unwrap();
+        assert_eq!(tile_set.len(), 3);
+    }
+
+    fn problem_options(result: Result<FoundInputs, MosaicError>) -> Vec<&'static str> {
+        match result {
+            Err(MosaicError::InvalidOptions { problems }) => {
+                problems.iter().map(|p| p.option).collect()
+            }
+            r => panic!("{:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn missing_input_is_not_reported_as_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+
+        let result = MakeImgOfImsOpts::default().validate(
+            &[DynamicImage::new_rgb8(40, 30)],
+            &[TileInput::new(&missing)],
+        );
+        match result {
+            Err(MosaicError::InvalidOptions { problems }) => {
+                assert_eq!(problems.len(), 1);
+                assert_eq!(problems[0].message, format!("{missing:?} does not exist"));
+            }
+            r => panic!("{:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn stretched_tiles_must_be_at_least_a_pixel() {
+        let dir = tempfile::tempdir().unwrap();
+        write_solid_pngs(dir.path(), 3, 8);
+
+        let opts = MakeImgOfImsOpts {
+            target_width: Some(40),
+            fit_mode: FitMode::Stretch,
+            num_horizontal_imgs: 100,
+            num_vertical_imgs: 30,
+            ..Default::default()
+        };
+        let result = opts.validate(
+            &[DynamicImage::new_rgb8(40, 30)],
+            &[TileInput::new(dir.path())],
+        );
+        assert_eq!(problem_options(result), ["num_horizontal_imgs"]);
+    }
+
+    #[test]
+    fn images_in_archives_can_be_pinned() {
+        let dir = tempfile::tempdir().unwrap();
+        let imgs_dir = dir.path().join("imgs");
+        write_solid_pngs(&imgs_dir, 3, 8);
+        let pinned = write_solid_png_zip(&dir.path().join("pinned.zip"), 1, 8).remove(0);
+
+        let target = DynamicImage::new_rgb8(40, 40);
+        let inputs = [TileInput::new(&imgs_dir)];
+        let opts = |pinned| MakeImgOfImsOpts {
+            target_width: Some(40),
+            num_horizontal_imgs: 2,
+            num_vertical_imgs: 2,
+            pinned_tiles: [((1, 0), pinned)].into(),
+            ..Default::default()
+        };
+
+        let missing = dir.path().join("missing.zip").join("0.png");
+        let result = opts(missing).validate(std::slice::from_ref(&target), &inputs);
+        assert_eq!(problem_options(result), ["pinned_tiles"]);
+
+        let opts = opts(pinned.clone());
+        let found = opts
+            .validate(std::slice::from_ref(&target), &inputs)
+            .unwrap();
+        let tile_set = TileSet::from_found(found, 8, &opts).unwrap();
+        assert_eq!(tile_set.len(), 4);
+
+        let mosaic = MosaicBuilder::new(&tile_set, opts).build(&target).unwrap();
+        let placement = &mosaic.plan.placements[2];
+        assert_eq!((placement.row, placement.col), (1, 0));
+        assert_eq!(placement.id, pinned);
+    }
+}
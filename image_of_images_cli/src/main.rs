@@ -1,35 +1,217 @@
-use std::{path::PathBuf, thread};
+use std::{collections::HashMap, path::PathBuf, process, sync::Arc, thread};
 
-use image_of_images::{find_free_filepath, MakeImgOfImsOpts, ProgressReceiver, progress_channel};
+use image_of_images::{
+    find_free_filepath, load_target_img, progress_event_channel, render_plan_from_files, AutoGrid,
+    AutoGridSize, CancellationToken, FitMode, MakeImgOfImsOpts, MosaicBuilder, MosaicError,
+    MosaicPlan, ProgressEvent, ProgressEventReceiver, TileInput, TileSet,
+};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 struct Opt {
+    /// Folder of images, a zip, tar or tar.gz archive of them, or a text or CSV file listing them.
+    /// Can be given multiple times, as `path@30%` at least 30% of the cells are filled with its
+    /// images
+    #[structopt(long, required_unless = "render-plan", parse(from_str = parse_input))]
+    input_dir: Vec<TileInput>,
+    /// Image(s) to replicate, the input images are loaded once for all of them
+    #[structopt(long, required_unless = "render-plan")]
+    target_img: Vec<PathBuf>,
     #[structopt(long)]
-    input_dir: PathBuf,
+    output_dir: PathBuf,
+    /// Format of the results, both are written band by band so results can be larger than memory
+    #[structopt(long, default_value = "png", possible_values = &["png", "tiff"])]
+    output_format: String,
+    /// Width of the result, defaults to 1000 when no height is given either
     #[structopt(long)]
-    target_img: PathBuf,
+    target_width: Option<u32>,
     #[structopt(long)]
-    output_dir: PathBuf,
-    #[structopt(long, default_value = "1000")]
-    target_width: u32,
+    target_height: Option<u32>,
+    /// How to handle the margins of the target which are not covered by the grid
+    #[structopt(long, default_value = "crop", possible_values = &["crop", "stretch", "pad"])]
+    fit_mode: String,
+    /// Background colour used with `--fit-mode pad`, as hex rgb
+    #[structopt(long, default_value = "ffffff", parse(try_from_str = parse_hex_color))]
+    background: [u8; 3],
     #[structopt(long, default_value = "40")]
     num_horizontal_imgs: u32,
     #[structopt(long, default_value = "40")]
     num_vertical_imgs: u32,
+    /// Width to height ratio of the tiles, e.g. 4:3. Derives the grid from `--num-tiles` or
+    /// `--tile-width` instead of the number of horizontal and vertical images
+    #[structopt(long, parse(try_from_str = parse_aspect_ratio))]
+    tile_aspect_ratio: Option<(u32, u32)>,
+    /// Approximate total number of tiles, used with `--tile-aspect-ratio`
+    #[structopt(long, conflicts_with = "tile-width")]
+    num_tiles: Option<u32>,
+    /// Width of a tile in pixels, used with `--tile-aspect-ratio`
     #[structopt(long)]
-    max_imgs: Option<usize>,
+    tile_width: Option<u32>,
     #[structopt(long)]
+    max_imgs: Option<usize>,
+    /// Has no effect, images are never used for more than one cell
+    #[structopt(long, hidden = true)]
     no_pop: bool,
+    /// Force an image into a cell, as `row,col=path`. Can be given multiple times
+    #[structopt(long, parse(try_from_str = parse_pin))]
+    pin: Vec<((u32, u32), PathBuf)>,
+    /// Glob pattern of input images which may be used, all when not given. Matched against the path
+    /// relative to the input regardless of case, `*` stays within a folder while `**` doesn't. Can
+    /// be given multiple times
+    #[structopt(long)]
+    include: Vec<String>,
+    /// Glob pattern of input images which should not be used. Can be given multiple times
+    #[structopt(long)]
+    exclude: Vec<String>,
+    /// Levels of subfolders searched for images, 0 to only use the images directly in the inputs
+    #[structopt(long)]
+    max_depth: Option<usize>,
+    /// Skip files and folders whose name starts with a dot
+    #[structopt(long)]
+    skip_hidden: bool,
+    /// Skip symlinks in the input folders instead of following them
+    #[structopt(long)]
+    no_follow_symlinks: bool,
+    /// Threads used for loading images, defaults to the number of cores
+    #[structopt(long)]
+    threads: Option<usize>,
+    /// Maximum number of loaded images waiting to be stored, limits memory use while loading
+    #[structopt(long, default_value = "256")]
+    load_queue_size: usize,
+    /// Number of downscaled copies of every image, with longest sides of 4, 8, ... pixels, used
+    /// with `--shortlist-size`
+    #[structopt(long, default_value = "2")]
+    pyramid_levels: usize,
+    /// Compare cells to the downscaled images first and only score this many images per cell at
+    /// full size. Much faster for large libraries, but the best image may be missed
+    #[structopt(long)]
+    shortlist_size: Option<usize>,
+    /// Score cells downscaled to at most this many pixels wide and high, the result is made from
+    /// the images at full size
+    #[structopt(long, default_value = "64")]
+    match_tile_size: u32,
+    /// Score images like earlier versions did, summing in 32 bit floats. Slower, but picks the
+    /// same images as those versions
+    #[structopt(long)]
+    f32_scoring: bool,
+    /// Group the images into this many clusters of similar colour, and only score the images in
+    /// the clusters nearest to a cell
+    #[structopt(long)]
+    clusters: Option<usize>,
+    /// Number of nearest clusters searched for a cell
+    #[structopt(long, default_value = "3")]
+    clusters_searched: usize,
+    /// File to store the clusters in, they are reused from it while the images stay the same
+    #[structopt(long)]
+    tile_index: Option<PathBuf>,
+    /// Folder in which the progress of the job is stored, so it can be continued with `--resume`
+    /// when it is interrupted
+    #[structopt(long, conflicts_with = "resume")]
+    job_dir: Option<PathBuf>,
+    /// Continue the job stored in this folder, started with `--job-dir` and the same other
+    /// arguments
+    #[structopt(long)]
+    resume: Option<PathBuf>,
+    /// Use the images as they are stored, instead of rotating them as their EXIF orientation asks
+    /// for
+    #[structopt(long)]
+    ignore_exif_orientation: bool,
+    /// Report for how many cells the faster search of `--clusters` and `--shortlist-size` still
+    /// finds the best image
+    #[structopt(long)]
+    measure_recall: bool,
+    /// Also store the placement plan of each result as json
+    #[structopt(long)]
+    save_plan: bool,
+    /// Render a previously saved plan instead of matching images
+    #[structopt(long)]
+    render_plan: Option<PathBuf>,
+    /// Scale of the tiles when rendering a plan, compared to the tiles in the plan
+    #[structopt(long, default_value = "1.0")]
+    render_scale: f32,
+}
+
+fn parse_hex_color(s: &str) -> anyhow::Result<[u8; 3]> {
+    let s = s.trim_start_matches('#');
+    if s.len() != 6 {
+        return Err(anyhow::anyhow!("Expected a colour of the form rrggbb"));
+    }
+
+    let mut result = [0; 3];
+    for (i, c) in result.iter_mut().enumerate() {
+        *c = u8::from_str_radix(&s[2 * i..2 * i + 2], 16)?;
+    }
+
+    Ok(result)
+}
+
+fn parse_aspect_ratio(s: &str) -> anyhow::Result<(u32, u32)> {
+    let (w, h) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Expected an aspect ratio of the form w:h"))?;
+
+    Ok((w.trim().parse()?, h.trim().parse()?))
+}
+
+fn parse_pin(s: &str) -> anyhow::Result<((u32, u32), PathBuf)> {
+    let err = || anyhow::anyhow!("Expected a pin of the form row,col=path");
+
+    let (cell, path) = s.split_once('=').ok_or_else(err)?;
+    let (row, col) = cell.split_once(',').ok_or_else(err)?;
+
+    Ok(((row.trim().parse()?, col.trim().parse()?), path.into()))
+}
+
+fn parse_input(s: &str) -> TileInput {
+    // `@` can also be part of a path, so only a suffix which is a percentage is a quota
+    let quota = s.rsplit_once('@').and_then(|(path, quota)| {
+        let percent: f32 = quota.strip_suffix('%')?.trim().parse().ok()?;
+        Some((path, percent / 100.0))
+    });
+
+    match quota {
+        Some((path, quota)) => TileInput {
+            path: path.into(),
+            quota: Some(quota),
+        },
+        None => TileInput::new(s),
+    }
 }
 
-fn start_print_progress_thread(progress_receiver: ProgressReceiver) {
+fn start_print_progress_thread(progress_receiver: ProgressEventReceiver) {
     thread::spawn(move || {
         let term = console::Term::stdout();
         let _ = term.write_line("");
-        while let Ok((part, total, desc)) = progress_receiver.recv() {
-            let _ = term.clear_last_lines(1);
-            let _ = term.write_line(&format!("{desc} ({part}/{total})"));
+        while let Ok(event) = progress_receiver.recv() {
+            match event {
+                ProgressEvent::StageStarted { .. } => (),
+                ProgressEvent::Progress {
+                    stage,
+                    done,
+                    total,
+                    eta,
+                    ..
+                } => {
+                    let eta = eta
+                        .map(|eta| format!(", {}s remaining", eta.as_secs()))
+                        .unwrap_or_default();
+                    let _ = term.clear_last_lines(1);
+                    let _ = term.write_line(&format!("{stage} ({done}/{total}{eta})"));
+                }
+                ProgressEvent::StageFinished { stage, elapsed } => {
+                    let _ = term.clear_last_lines(1);
+                    let _ = term.write_line(&format!(
+                        "{stage} finished in {:.1}s",
+                        elapsed.as_secs_f32()
+                    ));
+                    let _ = term.write_line("");
+                }
+                ProgressEvent::Warning(warning) => {
+                    let _ = term.clear_last_lines(1);
+                    let _ = term.write_line(&format!("Warning: {warning}"));
+                    let _ = term.write_line("");
+                }
+            }
         }
     });
 }
@@ -38,30 +220,204 @@ fn main() -> anyhow::Result<()> {
     let _ = dotenv::dotenv();
     env_logger::init();
 
-    let opt = Opt::from_args();
+    match run(Opt::from_args()) {
+        Err(e) if matches!(e.downcast_ref(), Some(MosaicError::Cancelled)) => {
+            println!("Cancelled");
+            process::exit(130);
+        }
+        r => r,
+    }
+}
 
+fn run(opt: Opt) -> anyhow::Result<()> {
     std::fs::create_dir_all(&opt.output_dir)?;
 
-    let output_file = find_free_filepath(opt.output_dir, "result", ".png");
+    if let Some(plan_file) = &opt.render_plan {
+        let plan: MosaicPlan<PathBuf> = MosaicPlan::load(plan_file)?;
+        let extension = format!(".{}", opt.output_format);
+        let output_file = find_free_filepath(&opt.output_dir, "result", &extension);
 
-    let (progress_sender, progress_receiver) = progress_channel();
+        let (progress_sender, progress_receiver) = progress_event_channel();
+        let opts = MakeImgOfImsOpts {
+            progress_reporter: Some(Arc::new(progress_sender)),
+            ignore_exif_orientation: opt.ignore_exif_orientation,
+            ..Default::default()
+        };
 
-    start_print_progress_thread(progress_receiver);
+        let cancellation_token = opts.cancellation_token.clone();
+        ctrlc::set_handler(move || cancellation_token.cancel())?;
+        start_print_progress_thread(progress_receiver);
 
-    image_of_images::make_img_of_images(
-        opt.target_img,
-        opt.input_dir,
-        output_file,
-        MakeImgOfImsOpts {
-            target_width: opt.target_width,
-            num_horizontal_imgs: opt.num_horizontal_imgs,
-            num_vertical_imgs: opt.num_vertical_imgs,
-            max_imgs: opt.max_imgs,
-            no_pop: opt.no_pop,
-            progress_sender: Some(progress_sender),
-            ..Default::default()
+        render_plan_from_files(&plan, opt.render_scale, &output_file, &opts)?;
+        println!("Saved {output_file:?}");
+
+        return Ok(());
+    }
+
+    if opt.input_dir.is_empty() {
+        return Err(anyhow::anyhow!("--input-dir is required"));
+    }
+
+    if opt.no_pop {
+        println!("Warning: --no-pop has no effect, images are never used for more than one cell");
+    }
+
+    let (progress_sender, progress_receiver) = progress_event_channel();
+
+    let target_width = match (opt.target_width, opt.target_height) {
+        (None, None) => Some(1000),
+        (w, _) => w,
+    };
+
+    let auto_grid_size = match (opt.num_tiles, opt.tile_width) {
+        (Some(n), _) => Some(AutoGridSize::NumTiles(n)),
+        (None, Some(w)) => Some(AutoGridSize::TileWidth(w)),
+        (None, None) => None,
+    };
+
+    let auto_grid = match (opt.tile_aspect_ratio, auto_grid_size) {
+        (Some(_), None) => {
+            return Err(anyhow::anyhow!(
+                "--tile-aspect-ratio requires either --num-tiles or --tile-width"
+            ))
+        }
+        (aspect_ratio, Some(size)) => Some(AutoGrid {
+            tile_aspect_ratio: aspect_ratio.unwrap_or((1, 1)),
+            size,
+        }),
+        (None, None) => None,
+    };
+
+    let fit_mode = match opt.fit_mode.as_str() {
+        "stretch" => FitMode::Stretch,
+        "pad" => FitMode::Pad {
+            background: opt.background,
         },
-    )?;
+        _ => FitMode::Crop,
+    };
+
+    let opts = MakeImgOfImsOpts {
+        target_width,
+        target_height: opt.target_height,
+        fit_mode,
+        num_horizontal_imgs: opt.num_horizontal_imgs,
+        num_vertical_imgs: opt.num_vertical_imgs,
+        auto_grid,
+        max_imgs: opt.max_imgs,
+        pinned_tiles: opt.pin.into_iter().collect::<HashMap<_, _>>(),
+        include: opt.include,
+        exclude: opt.exclude,
+        max_depth: opt.max_depth,
+        skip_hidden: opt.skip_hidden,
+        follow_symlinks: !opt.no_follow_symlinks,
+        progress_reporter: Some(Arc::new(progress_sender)),
+        cancellation_token: CancellationToken::new(),
+        num_threads: opt.threads,
+        load_queue_size: opt.load_queue_size,
+        pyramid_levels: opt.pyramid_levels,
+        shortlist_size: opt.shortlist_size,
+        match_tile_size: opt.match_tile_size,
+        f32_scoring: opt.f32_scoring,
+        num_clusters: opt.clusters,
+        clusters_searched: opt.clusters_searched,
+        tile_index: opt.tile_index,
+        job_dir: opt.job_dir.or_else(|| opt.resume.clone()),
+        resume: opt.resume.is_some(),
+        ignore_exif_orientation: opt.ignore_exif_orientation,
+        ..Default::default()
+    };
+
+    let target_imgs = opt
+        .target_img
+        .iter()
+        .map(|path| load_target_img(path, !opts.ignore_exif_orientation))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let found = opts.validate(&target_imgs, &opt.input_dir)?;
+
+    // a cancelled job stops at the next check, so nothing is left half written
+    let cancellation_token = opts.cancellation_token.clone();
+    ctrlc::set_handler(move || cancellation_token.cancel())?;
+
+    let max_tile_size = target_imgs
+        .iter()
+        .map(|img| {
+            let grid = opts.grid_for_target(img);
+            grid.tile_width.max(grid.tile_height)
+        })
+        .max()
+        .unwrap_or(1);
+
+    start_print_progress_thread(progress_receiver);
+
+    let tile_set = TileSet::from_found(found, max_tile_size, &opts)?;
+    println!(
+        "Loaded {} images using {:.1} MB",
+        tile_set.len(),
+        tile_set.memory_usage() as f64 / 1e6
+    );
+    let builder = MosaicBuilder::new(&tile_set, opts);
+
+    for target_img in &target_imgs {
+        let extension = format!(".{}", opt.output_format);
+        let output_file = find_free_filepath(&opt.output_dir, "result", &extension);
+
+        let plan = builder.build_to_file(target_img, &output_file)?;
+
+        if opt.save_plan {
+            plan.save(output_file.with_extension("json"))?;
+        }
+
+        let grid = plan.grid;
+        println!(
+            "Saved {output_file:?}, using a grid of {}x{} tiles of {}x{} pixels",
+            grid.n_horizontal, grid.n_vertical, grid.tile_width, grid.tile_height
+        );
+
+        if opt.measure_recall {
+            let recall = builder.search_recall(target_img)?;
+            println!(
+                "The best image of {:.1}% of the cells is among the searched images",
+                recall * 100.0
+            );
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_input_with_quota() {
+        assert_eq!(
+            parse_input("tiles@30%"),
+            TileInput {
+                path: "tiles".into(),
+                quota: Some(0.3),
+            }
+        );
+        assert_eq!(
+            parse_input("me@home/tiles.zip@ 12.5 %"),
+            TileInput {
+                path: "me@home/tiles.zip".into(),
+                quota: Some(0.125),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_input_keeps_at_in_paths() {
+        for input in [
+            "me@home/tiles",
+            "tiles@30",
+            "tiles@%",
+            "tiles@x%",
+            "tiles@30%/more",
+        ] {
+            assert_eq!(parse_input(input), TileInput::new(input), "{input}");
+        }
+    }
+}
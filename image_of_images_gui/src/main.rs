@@ -1,23 +1,26 @@
 use std::{
     path::{Path, PathBuf},
+    sync::Arc,
     thread,
     time::Duration,
 };
 
 use crossbeam::channel::{Receiver, Sender};
-use eframe::{
-    epi::{App, Storage},
-    NativeOptions,
-};
+use eframe::{epi::App, NativeOptions};
 use egui::{Response, TextBuffer};
 use image_of_images::{
-    make_img_of_images, progress_channel, ProgressReceiver, ProgressSender, IMAGE_EXTENSIONS, find_free_filepath,
+    find_free_filepath, image_extensions, load_target_img, progress_event_channel, AutoGrid,
+    AutoGridSize, CancellationToken, FitMode, Grid, MakeImgOfImsOpts, MosaicBuilder, MosaicError,
+    ProgressEvent, ProgressEventReceiver, ProgressEventSender, TileInput, TileSet,
 };
+use parking_lot::Mutex;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, Copy)]
 enum FileDialogType {
     TargetImgPath,
-    InputFolderPath,
+    /// Index of the input folder
+    InputFolderPath(usize),
     OutputFolderPath,
 }
 
@@ -25,13 +28,16 @@ enum NumInputType {
     NumHorizontalImgs,
     NumVerticalImgs,
     TargetImgWidth,
+    TargetImgHeight,
+    NumTiles,
+    MaxDepth,
 }
 
 impl FileDialogType {
     fn make_result_event(self, result: String) -> Event {
         match self {
             FileDialogType::TargetImgPath => Event::SetTargetImgPath(result),
-            FileDialogType::InputFolderPath => Event::SetInputFolderPath(result),
+            FileDialogType::InputFolderPath(idx) => Event::SetInputFolderPath(idx, result),
             FileDialogType::OutputFolderPath => Event::SetOutputFolderPath(result),
         }
     }
@@ -40,27 +46,94 @@ impl FileDialogType {
 #[derive(Debug, Clone)]
 enum Event {
     SetTargetImgPath(String),
-    SetInputFolderPath(String),
+    SetInputFolderPath(usize, String),
     SetOutputFolderPath(String),
     SetProgressText(Option<String>),
     ProcessFinished { process_result: Option<PathBuf> },
 }
 
+/// Tiles of the last run, reused as long as the inputs and the way images are found in them do
+/// not change.
+#[derive(Debug)]
+struct CachedTileSet {
+    inputs: Vec<TileInput>,
+    discovery: Discovery,
+    tile_set: Arc<TileSet<PathBuf>>,
+}
+
+/// The options which decide which images are found in the inputs.
+#[derive(Debug, Clone, PartialEq)]
+struct Discovery {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    max_depth: Option<usize>,
+    skip_hidden: bool,
+    follow_symlinks: bool,
+}
+
+impl Discovery {
+    fn from_opts(opts: &MakeImgOfImsOpts) -> Self {
+        Self {
+            include: opts.include.clone(),
+            exclude: opts.exclude.clone(),
+            max_depth: opts.max_depth,
+            skip_hidden: opts.skip_hidden,
+            follow_symlinks: opts.follow_symlinks,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct InputFolder {
+    path: String,
+    /// Percentage of the cells which is at least filled with images of this folder
+    quota: String,
+}
+
+impl InputFolder {
+    /// Parses an input folder stored as `quota\tpath`.
+    fn from_stored(s: &str) -> Self {
+        let (quota, path) = s.split_once('\t').unwrap_or(("", s));
+        Self {
+            path: path.to_string(),
+            quota: quota.to_string(),
+        }
+    }
+
+    fn to_stored(&self) -> String {
+        format!("{}\t{}", self.quota, self.path)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct ImgOfImgsGui {
     target_img_path: String,
-    input_folder_path: String,
+    input_folders: Vec<InputFolder>,
     output_folder_path: String,
     num_horizontal_imgs: String,
     num_vertical_imgs: String,
     target_img_width: String,
+    target_img_height: String,
+    fit_mode: FitMode,
+    tile_aspect_ratio: String,
+    num_tiles: String,
+    /// Comma separated glob patterns
+    include_patterns: String,
+    /// Comma separated glob patterns
+    exclude_patterns: String,
+    max_depth: String,
+    skip_hidden: bool,
+    follow_symlinks: bool,
     processing: bool,
     process_result: Option<PathBuf>,
     event_receiver: Receiver<Event>,
     event_sender: Sender<Event>,
     progress_text: Option<String>,
-    progress_receiver: ProgressReceiver,
-    progress_sender: ProgressSender,
+    progress_receiver: ProgressEventReceiver,
+    progress_sender: ProgressEventSender,
+    tile_set_cache: Arc<Mutex<Option<CachedTileSet>>>,
+    /// Token of the running job
+    cancellation_token: CancellationToken,
 }
 
 impl ImgOfImgsGui {
@@ -70,11 +143,11 @@ impl ImgOfImgsGui {
         thread::spawn(move || {
             let opt_path = match dialog_type {
                 FileDialogType::TargetImgPath => nfd::open_dialog(
-                    Some(&IMAGE_EXTENSIONS.join(",")),
+                    Some(&image_extensions().join(",")),
                     None,
                     nfd::DialogType::SingleFile,
                 ),
-                FileDialogType::InputFolderPath | FileDialogType::OutputFolderPath => {
+                FileDialogType::InputFolderPath(_) | FileDialogType::OutputFolderPath => {
                     nfd::open_pick_folder(None)
                 }
             };
@@ -99,7 +172,8 @@ impl ImgOfImgsGui {
     fn add_path_input(&mut self, ui: &mut egui::Ui, dialog_type: FileDialogType) {
         ui.label(match dialog_type {
             FileDialogType::TargetImgPath => "Target image",
-            FileDialogType::InputFolderPath => "Input folder",
+            FileDialogType::InputFolderPath(0) => "Input folders",
+            FileDialogType::InputFolderPath(_) => "",
             FileDialogType::OutputFolderPath => "Output folder",
         });
 
@@ -108,7 +182,7 @@ impl ImgOfImgsGui {
                 ui,
                 match dialog_type {
                     FileDialogType::TargetImgPath => &mut self.target_img_path,
-                    FileDialogType::InputFolderPath => &mut self.input_folder_path,
+                    FileDialogType::InputFolderPath(idx) => &mut self.input_folders[idx].path,
                     FileDialogType::OutputFolderPath => &mut self.output_folder_path,
                 },
             );
@@ -121,15 +195,71 @@ impl ImgOfImgsGui {
         ui.end_row();
     }
 
+    fn add_input_folders(&mut self, ui: &mut egui::Ui) {
+        let mut removed = None;
+
+        for idx in 0..self.input_folders.len() {
+            self.add_path_input(ui, FileDialogType::InputFolderPath(idx));
+
+            ui.label("");
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.input_folders[idx].quota)
+                        .desired_width(80f32)
+                        .hint_text("Quota in %"),
+                );
+
+                if self.input_folders.len() > 1 && ui.button("Remove").clicked() {
+                    removed = Some(idx);
+                }
+            });
+            ui.end_row();
+        }
+
+        if let Some(idx) = removed {
+            self.input_folders.remove(idx);
+        }
+
+        ui.label("");
+        if ui.button("Add input folder").clicked() {
+            self.input_folders.push(InputFolder::default());
+        }
+        ui.end_row();
+    }
+
     fn handle_events(&mut self) {
-        while let Ok((part, total, desc)) = self.progress_receiver.try_recv() {
-            self.progress_text = Some(format!("Processing: {} ({}/{})", desc, part, total));
+        while let Ok(event) = self.progress_receiver.try_recv() {
+            match event {
+                ProgressEvent::StageStarted { stage, total } => {
+                    self.progress_text = Some(format!("Processing: {} (0/{})", stage, total));
+                }
+                ProgressEvent::Progress {
+                    stage,
+                    done,
+                    total,
+                    eta,
+                    ..
+                } => {
+                    let eta = eta
+                        .map(|eta| format!(", {}s remaining", eta.as_secs()))
+                        .unwrap_or_default();
+                    self.progress_text =
+                        Some(format!("Processing: {} ({}/{}{})", stage, done, total, eta));
+                }
+                ProgressEvent::StageFinished { .. } => (),
+                ProgressEvent::Warning(warning) => log::warn!("{}", warning),
+            }
         }
 
         while let Ok(event) = self.event_receiver.try_recv() {
             match event {
                 Event::SetTargetImgPath(s) => self.target_img_path = s,
-                Event::SetInputFolderPath(s) => self.input_folder_path = s,
+                Event::SetInputFolderPath(idx, s) => {
+                    // the folder may have been removed while the dialog was open
+                    if let Some(input_folder) = self.input_folders.get_mut(idx) {
+                        input_folder.path = s;
+                    }
+                }
                 Event::SetOutputFolderPath(s) => self.output_folder_path = s,
                 Event::SetProgressText(s) => self.progress_text = s,
                 Event::ProcessFinished { process_result: output_file } => {
@@ -141,65 +271,113 @@ impl ImgOfImgsGui {
     }
 
 
-    fn start_make_img_of_imgs(&self) -> anyhow::Result<()> {
-        
+    fn start_make_img_of_imgs(&mut self) -> anyhow::Result<()> {
+        self.cancellation_token = CancellationToken::new();
 
         let progress_sender = self.progress_sender.clone();
         let event_sender = self.event_sender.clone();
+        let tile_set_cache = self.tile_set_cache.clone();
+        let cancellation_token = self.cancellation_token.clone();
 
         let target_img_path = self.target_img_path.clone();
-        let input_folder_path = self.input_folder_path.clone();
+        let inputs = self
+            .input_folders
+            .iter()
+            .filter(|input_folder| !input_folder.path.trim().is_empty())
+            .map(|input_folder| {
+                Ok(TileInput {
+                    path: input_folder.path.clone().into(),
+                    quota: parse_optional_num(&input_folder.quota)?.map(|q| q as f32 / 100.0),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
         let output_folder_path = self.output_folder_path.clone();
 
         let num_horizontal_imgs = self.num_horizontal_imgs.parse()?;
         let num_vertical_imgs = self.num_vertical_imgs.parse()?;
-        let target_width = self.target_img_width.parse()?;
+        let target_width = parse_optional_num(&self.target_img_width)?;
+        let target_height = parse_optional_num(&self.target_img_height)?;
+        let fit_mode = self.fit_mode;
+        let include = parse_patterns(&self.include_patterns);
+        let exclude = parse_patterns(&self.exclude_patterns);
+        let max_depth = parse_optional_num(&self.max_depth)?.map(|d| d as usize);
+        let skip_hidden = self.skip_hidden;
+        let follow_symlinks = self.follow_symlinks;
+
+        let auto_grid = if self.tile_aspect_ratio.trim().is_empty() {
+            None
+        } else {
+            Some(AutoGrid {
+                tile_aspect_ratio: parse_aspect_ratio(&self.tile_aspect_ratio)?,
+                size: AutoGridSize::NumTiles(self.num_tiles.parse()?),
+            })
+        };
 
         thread::spawn(move || {
             let r = std::fs::create_dir_all(&output_folder_path);
             
-            let output_file = find_free_filepath(output_folder_path, "result", ".png");
+            let output_file = find_free_filepath(&output_folder_path, "result", ".png");
 
             let result = match r {
                 Ok(()) => {
-                    make_img_of_images(
+                    make_img_of_imgs_cached(
+                        &tile_set_cache,
                         target_img_path,
-                        input_folder_path,
+                        inputs,
                         &output_file,
-                        image_of_images::MakeImgOfImsOpts {
-                            progress_sender: Some(progress_sender),
+                        MakeImgOfImsOpts {
+                            progress_reporter: Some(Arc::new(progress_sender)),
                             num_horizontal_imgs,
                             num_vertical_imgs,
+                            auto_grid,
                             target_width,
+                            target_height,
+                            fit_mode,
+                            include,
+                            exclude,
+                            max_depth,
+                            skip_hidden,
+                            follow_symlinks,
+                            cancellation_token,
                             ..Default::default()
                         },
                     )
                 },
-                Err(e) => Err(anyhow::anyhow!("{e:?}")),
+                Err(e) => Err(MosaicError::Io {
+                    path: output_folder_path.into(),
+                    source: e,
+                }),
             };
 
-            let success = match result {
-                Ok(_) => {
-                    event_sender
-                        .send(Event::SetProgressText(Some(format!(
-                            "Finished creating image, which can be found in the results folder",
-                        ))))
-                        .unwrap();
-                    true
-                }
-                Err(e) => {
-                    event_sender
-                        .send(Event::SetProgressText(Some(format!(
-                            "Failed creating image of images: {}",
-                            e
-                        ))))
-                        .unwrap();
-                    false
+            let success = result.is_ok();
+
+            let message = match result {
+                Ok(grid) => format!(
+                    "Finished creating image of {}x{} tiles, which can be found in the results folder",
+                    grid.n_horizontal, grid.n_vertical
+                ),
+                Err(MosaicError::Cancelled) => "Cancelled".to_string(),
+                Err(MosaicError::TooFewImages {
+                    available,
+                    required,
+                }) => format!(
+                    "Only {} images for {} tiles, reduce the amount of horizontal and/or vertical images or the amount of tiles",
+                    available, required
+                ),
+                Err(MosaicError::EmptyLibrary { .. }) => {
+                    "No images found in the input folders".to_string()
                 }
+                Err(e) => format!("Failed creating image of images: {:#}", anyhow::Error::new(e)),
             };
 
             event_sender
-                .send(Event::ProcessFinished { process_result: success.then(|| output_file) })
+                .send(Event::SetProgressText(Some(message)))
+                .unwrap();
+
+            event_sender
+                .send(Event::ProcessFinished {
+                    process_result: success.then_some(output_file),
+                })
                 .unwrap();
         });
 
@@ -211,34 +389,173 @@ impl ImgOfImgsGui {
             NumInputType::NumHorizontalImgs => "Amount of horizontal images",
             NumInputType::NumVerticalImgs => "Amount of vertical images",
             NumInputType::TargetImgWidth => "Target image width",
+            NumInputType::TargetImgHeight => "Target image height",
+            NumInputType::NumTiles => "Amount of tiles",
+            NumInputType::MaxDepth => "Max folder depth",
         });
 
         let field = match num_type {
             NumInputType::NumHorizontalImgs => &mut self.num_horizontal_imgs,
             NumInputType::NumVerticalImgs => &mut self.num_vertical_imgs,
             NumInputType::TargetImgWidth => &mut self.target_img_width,
+            NumInputType::TargetImgHeight => &mut self.target_img_height,
+            NumInputType::NumTiles => &mut self.num_tiles,
+            NumInputType::MaxDepth => &mut self.max_depth,
         };
 
         if ui.text_edit_singleline(field).changed() {
             (*field) = field.chars().filter(|c| c.is_numeric()).collect();
 
-            if let Err(_) = field.parse::<u32>() {
+            if field.parse::<u32>().is_err() {
                 field.drain(..);
             }
         }
 
         ui.end_row();
     }
+
+    fn add_tile_aspect_ratio_input(&mut self, ui: &mut egui::Ui) {
+        ui.label("Tile aspect ratio (e.g. 4:3)");
+        ui.add(
+            egui::TextEdit::singleline(&mut self.tile_aspect_ratio)
+                .hint_text("Use amount of horizontal and vertical images"),
+        );
+        ui.end_row();
+    }
+
+    /// Inputs for the options which decide which images are found in the input folders.
+    fn add_discovery_inputs(&mut self, ui: &mut egui::Ui) {
+        ui.label("Include patterns");
+        ui.add(
+            egui::TextEdit::singleline(&mut self.include_patterns)
+                .hint_text("All images, or e.g. **/*.jpg, holidays/**"),
+        );
+        ui.end_row();
+
+        ui.label("Exclude patterns");
+        ui.add(
+            egui::TextEdit::singleline(&mut self.exclude_patterns)
+                .hint_text("e.g. **/thumbnails/**"),
+        );
+        ui.end_row();
+
+        self.add_number_input(ui, NumInputType::MaxDepth);
+
+        ui.label("Hidden files and symlinks");
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.skip_hidden, "Skip hidden files");
+            ui.checkbox(&mut self.follow_symlinks, "Follow symlinks");
+        });
+        ui.end_row();
+    }
+
+    fn add_fit_mode_input(&mut self, ui: &mut egui::Ui) {
+        ui.label("Fit mode");
+
+        let pad = match self.fit_mode {
+            FitMode::Pad { .. } => self.fit_mode,
+            _ => FitMode::Pad {
+                background: [255, 255, 255],
+            },
+        };
+
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("fit_mode")
+                .selected_text(match self.fit_mode {
+                    FitMode::Crop => "Crop",
+                    FitMode::Stretch => "Stretch",
+                    FitMode::Pad { .. } => "Pad",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.fit_mode, FitMode::Crop, "Crop");
+                    ui.selectable_value(&mut self.fit_mode, FitMode::Stretch, "Stretch");
+                    ui.selectable_value(&mut self.fit_mode, pad, "Pad");
+                });
+
+            if let FitMode::Pad { background } = &mut self.fit_mode {
+                ui.color_edit_button_srgb(background);
+            }
+        });
+
+        ui.end_row();
+    }
+}
+
+fn make_img_of_imgs_cached(
+    tile_set_cache: &Mutex<Option<CachedTileSet>>,
+    target_img_path: String,
+    inputs: Vec<TileInput>,
+    output_file: &Path,
+    opts: MakeImgOfImsOpts,
+) -> Result<Grid, MosaicError> {
+    let target_img = load_target_img(target_img_path, !opts.ignore_exif_orientation)?;
+    let found = opts.validate(std::slice::from_ref(&target_img), &inputs)?;
+    let grid = opts.grid_for_target(&target_img);
+    let max_tile_size = grid.tile_width.max(grid.tile_height);
+
+    let discovery = Discovery::from_opts(&opts);
+
+    let tile_set = {
+        let mut cache = tile_set_cache.lock();
+
+        match &*cache {
+            Some(cached)
+                if cached.inputs == inputs
+                    && cached.discovery == discovery
+                    && cached.tile_set.max_tile_size() >= max_tile_size =>
+            {
+                cached.tile_set.clone()
+            }
+            _ => {
+                let tile_set = Arc::new(TileSet::from_found(found, max_tile_size, &opts)?);
+                *cache = Some(CachedTileSet {
+                    inputs,
+                    discovery,
+                    tile_set: tile_set.clone(),
+                });
+                tile_set
+            }
+        }
+    };
+
+    let plan = MosaicBuilder::new(&tile_set, opts).build_to_file(&target_img, output_file)?;
+
+    Ok(plan.grid)
+}
+
+fn parse_aspect_ratio(s: &str) -> anyhow::Result<(u32, u32)> {
+    let (w, h) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Expected a tile aspect ratio of the form w:h"))?;
+
+    Ok((w.trim().parse()?, h.trim().parse()?))
+}
+
+/// Splits comma separated glob patterns.
+fn parse_patterns(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn parse_optional_num(s: &str) -> anyhow::Result<Option<u32>> {
+    if s.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(s.parse()?))
+    }
 }
 
 impl Default for ImgOfImgsGui {
     fn default() -> Self {
         let (event_sender, event_receiver) = crossbeam::channel::unbounded();
-        let (progress_sender, progress_receiver) = progress_channel();
+        let (progress_sender, progress_receiver) = progress_event_channel();
 
         Self {
             target_img_path: Default::default(),
-            input_folder_path: Default::default(),
+            input_folders: vec![InputFolder::default()],
             output_folder_path: "results".into(),
             processing: false,
             process_result: None,
@@ -247,9 +564,20 @@ impl Default for ImgOfImgsGui {
             progress_text: Default::default(),
             progress_receiver,
             progress_sender,
+            tile_set_cache: Default::default(),
+            cancellation_token: Default::default(),
             num_horizontal_imgs: 40.to_string(),
             num_vertical_imgs: 40.to_string(),
             target_img_width: 1000.to_string(),
+            target_img_height: Default::default(),
+            fit_mode: FitMode::default(),
+            tile_aspect_ratio: Default::default(),
+            num_tiles: 1600.to_string(),
+            include_patterns: Default::default(),
+            exclude_patterns: Default::default(),
+            max_depth: Default::default(),
+            skip_hidden: false,
+            follow_symlinks: true,
         }
     }
 }
@@ -266,35 +594,45 @@ fn request_update_every(ctx: eframe::epi::Frame, interval: Duration) {
 }
 
 impl App for ImgOfImgsGui {
-    fn update(&mut self, ctx: &egui::Context, frame: &eframe::epi::Frame) {
+    fn update(&mut self, ctx: &egui::Context, _frame: &eframe::epi::Frame) {
         // handle events
         self.handle_events();
 
         
 
-        egui::CentralPanel::default().show(&ctx, |ui| {
+        egui::CentralPanel::default().show(ctx, |ui| {
             // ui.set_style(style);
             egui::Grid::new("Config")
                 // .min_col_width(200f32)
                 .max_col_width(500f32)
                 .show(ui, |ui| {
                     self.add_path_input(ui, FileDialogType::TargetImgPath);
-                    self.add_path_input(ui, FileDialogType::InputFolderPath);
+                    self.add_input_folders(ui);
+                    self.add_discovery_inputs(ui);
                     self.add_path_input(ui, FileDialogType::OutputFolderPath);
                     self.add_number_input(ui, NumInputType::NumHorizontalImgs);
                     self.add_number_input(ui, NumInputType::NumVerticalImgs);
+                    self.add_tile_aspect_ratio_input(ui);
+                    if !self.tile_aspect_ratio.trim().is_empty() {
+                        self.add_number_input(ui, NumInputType::NumTiles);
+                    }
                     self.add_number_input(ui, NumInputType::TargetImgWidth);
+                    self.add_number_input(ui, NumInputType::TargetImgHeight);
+                    self.add_fit_mode_input(ui);
                 });
 
-            if !self.processing {
-                if ui.button("Create image of images").clicked() && !self.processing {
-                    self.processing = true;
-                    if let Err(e) = self.start_make_img_of_imgs() {
-                        self.progress_text = Some(format!("Error in input fields: {}", e))
-                    }
+            if !self.processing && ui.button("Create image of images").clicked() {
+                self.processing = true;
+                if let Err(e) = self.start_make_img_of_imgs() {
+                    self.processing = false;
+                    self.progress_text = Some(format!("Error in input fields: {}", e))
                 }
             }
 
+            if self.processing && ui.button("Cancel").clicked() {
+                self.cancellation_token.cancel();
+            }
+
             if let Some(txt) = &self.progress_text {
                 ui.label(txt);
             }
@@ -330,9 +668,20 @@ impl App for ImgOfImgsGui {
             self.target_img_path = storage
                 .get_string("target_img")
                 .unwrap_or(defaults.target_img_path);
-            self.input_folder_path = storage
-                .get_string("input_folder")
-                .unwrap_or(defaults.input_folder_path);
+            self.input_folders = match storage.get_string("input_folders") {
+                Some(s) => s.lines().map(InputFolder::from_stored).collect(),
+                // stored by versions with a single input folder
+                None => match storage.get_string("input_folder") {
+                    Some(path) => vec![InputFolder {
+                        path,
+                        ..Default::default()
+                    }],
+                    None => defaults.input_folders,
+                },
+            };
+            if self.input_folders.is_empty() {
+                self.input_folders.push(InputFolder::default());
+            }
             self.output_folder_path = storage
                 .get_string("output_folder")
                 .unwrap_or(defaults.output_folder_path);
@@ -345,7 +694,12 @@ impl App for ImgOfImgsGui {
 
     fn save(&mut self, storage: &mut dyn eframe::epi::Storage) {
         storage.set_string("target_img", self.target_img_path.clone());
-        storage.set_string("input_folder", self.input_folder_path.clone());
+        let input_folders: Vec<String> = self
+            .input_folders
+            .iter()
+            .map(InputFolder::to_stored)
+            .collect();
+        storage.set_string("input_folders", input_folders.join("\n"));
         storage.set_string("output_folder", self.output_folder_path.clone());
     }
 